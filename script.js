@@ -9,6 +9,7 @@ class MediaSyncApp {
         this.availableFiles = [];
         this.connectedClients = [];
         this.clientRefreshTimer = null;
+        this.libraryRevision = 0;
         
         this.initializeElements();
         this.setupEventListeners();
@@ -20,6 +21,12 @@ class MediaSyncApp {
         // Server elements
         this.serverPortInput = document.getElementById('server-port');
         this.mediaDirectoryInput = document.getElementById('media-directory');
+        this.scanIncludeInput = document.getElementById('scan-include');
+        this.scanExcludeInput = document.getElementById('scan-exclude');
+        this.scanMaxDepthInput = document.getElementById('scan-max-depth');
+        this.hostPlaybackSelect = document.getElementById('host-playback');
+        this.hostPlayerSelect = document.getElementById('host-player');
+        this.slowClientPolicySelect = document.getElementById('slow-client-policy');
         this.startServerBtn = document.getElementById('start-server');
         this.stopServerBtn = document.getElementById('stop-server');
         this.serverStatusDot = document.getElementById('server-status-dot');
@@ -27,6 +34,11 @@ class MediaSyncApp {
         this.loadedFilesContainer = document.getElementById('loaded-files');
         this.connectedClientsContainer = document.getElementById('connected-clients');
         this.browseDirectoryBtn = document.getElementById('browse-directory');
+        this.groupPauseBtn = document.getElementById('group-pause-btn');
+        this.groupResumeBtn = document.getElementById('group-resume-btn');
+        this.groupStopBtn = document.getElementById('group-stop-btn');
+        this.groupSeekBtn = document.getElementById('group-seek-btn');
+        this.groupSeekInput = document.getElementById('group-seek-seconds');
 
         // Client elements
         this.serverAddressInput = document.getElementById('server-address');
@@ -57,6 +69,10 @@ class MediaSyncApp {
         this.stopServerBtn.addEventListener('click', () => this.stopServer());
         this.browseDirectoryBtn.addEventListener('click', () => this.browseDirectory());
         document.getElementById('browse-files').addEventListener('click', () => this.browseFiles());
+        this.groupPauseBtn.addEventListener('click', () => this.groupPlaybackCommand('pause-media', {}, 'Paused playback for all clients'));
+        this.groupResumeBtn.addEventListener('click', () => this.groupPlaybackCommand('resume-media', {}, 'Resumed playback for all clients'));
+        this.groupStopBtn.addEventListener('click', () => this.groupPlaybackCommand('stop-media', {}, 'Stopped playback for all clients'));
+        this.groupSeekBtn.addEventListener('click', () => this.groupSeek());
         
         // Test buttons
         document.getElementById('use-test-media').addEventListener('click', () => this.useTestMedia());
@@ -87,6 +103,7 @@ class MediaSyncApp {
         this.clientRefreshTimer = setInterval(() => {
             this.refreshServerLogs();
             this.refreshConnectedClients();
+            this.refreshLibrary();
         }, 2000);
         
         // Also refresh immediately
@@ -128,6 +145,42 @@ class MediaSyncApp {
         }
     }
 
+    async refreshLibrary() {
+        try {
+            const response = await this.callRustCommand('get-library-changes', { since: this.libraryRevision });
+            if (!response.success) {
+                return;
+            }
+
+            if (response.files) {
+                // Missed too many changes; the server sent the whole list
+                this.loadedFiles = response.files;
+            } else if (response.changes && response.changes.length > 0) {
+                response.changes.forEach(change => this.applyLibraryChange(change));
+            } else {
+                this.libraryRevision = response.revision;
+                return;
+            }
+
+            this.libraryRevision = response.revision;
+            this.updateLoadedFiles();
+        } catch (error) {
+            console.debug('Failed to refresh library:', error.message);
+        }
+    }
+
+    applyLibraryChange(change) {
+        const replaced = new Set([...change.removed, ...change.updated.map(file => file.name)]);
+        this.loadedFiles = this.loadedFiles
+            .filter(file => !replaced.has(file.name))
+            .concat(change.added, change.updated)
+            .sort((a, b) => a.name.localeCompare(b.name));
+
+        change.added.forEach(file => this.logMessage(`New media file: ${file.name}`, 'info'));
+        change.removed.forEach(name => this.logMessage(`Media file removed: ${name}`, 'info'));
+        change.updated.forEach(file => this.logMessage(`Media file changed: ${file.name}`, 'info'));
+    }
+
     displayServerLogs(serverLogs) {
         // Get existing log entries to avoid duplicates
         const existingEntries = Array.from(this.statusLog.querySelectorAll('.log-entry'));
@@ -144,7 +197,7 @@ class MediaSyncApp {
             logEntry.className = `log-entry server-log ${log.level.toLowerCase()}`;
             logEntry.innerHTML = `
                 <span class="timestamp">[${log.timestamp}]</span> 
-                <span class="log-source">[SERVER]</span> ${log.message}
+                <span class="log-source">[SERVER]</span> ${this.escapeHtml(log.message)}
             `;
             
             this.statusLog.appendChild(logEntry);
@@ -209,7 +262,13 @@ class MediaSyncApp {
             // Debug the exact payload being sent
             const payload = {
                 port: parseInt(port),
-                directory: directory
+                directory: directory,
+                include: this.scanIncludeInput.value,
+                exclude: this.scanExcludeInput.value,
+                maxDepth: this.scanMaxDepthInput.value === '' ? null : parseInt(this.scanMaxDepthInput.value),
+                hostPlayback: this.hostPlaybackSelect.value,
+                player: this.hostPlayerSelect.value,
+                slowClientPolicy: this.slowClientPolicySelect.value
             };
             this.logMessage(`Sending payload: ${JSON.stringify(payload)}`, 'info');
             
@@ -219,6 +278,7 @@ class MediaSyncApp {
                 this.serverRunning = true;
                 this.updateServerStatus(true);
                 this.loadedFiles = response.files || [];
+                this.libraryRevision = response.libraryRevision || 0;
                 this.updateLoadedFiles();
                 this.showNotification('Server started successfully', 'success');
                 this.logMessage('Server started successfully', 'success');
@@ -256,6 +316,30 @@ class MediaSyncApp {
         }
     }
 
+    async groupPlaybackCommand(command, params, successMessage) {
+        try {
+            const response = await this.callRustCommand(command, params);
+
+            if (response.success) {
+                this.logMessage(successMessage, 'info');
+            } else {
+                throw new Error(response.error || `Failed to run ${command}`);
+            }
+        } catch (error) {
+            this.logMessage(`Playback control failed: ${error.message}`, 'error');
+            this.showNotification(`Playback control failed: ${error.message}`, 'error');
+        }
+    }
+
+    groupSeek() {
+        const seconds = parseFloat(this.groupSeekInput.value);
+        if (isNaN(seconds) || seconds < 0) {
+            this.showNotification('Please enter a position in seconds', 'error');
+            return;
+        }
+        this.groupPlaybackCommand('seek-media', { positionMs: Math.round(seconds * 1000) }, `Seeked all clients to ${seconds}s`);
+    }
+
     useTestMedia() {
         this.mediaDirectoryInput.value = 'f:\\software\\test_media';
         this.logMessage('Set directory to test_media folder', 'info');
@@ -453,26 +537,37 @@ class MediaSyncApp {
             this.serverStatusText.textContent = 'Server Running';
             this.startServerBtn.disabled = true;
             this.stopServerBtn.disabled = false;
+            this.setGroupControlsEnabled(true);
         } else {
             this.serverStatusDot.classList.remove('running');
             this.serverStatusText.textContent = 'Server Stopped';
             this.startServerBtn.disabled = false;
             this.stopServerBtn.disabled = true;
+            this.setGroupControlsEnabled(false);
         }
     }
 
+    setGroupControlsEnabled(enabled) {
+        this.groupPauseBtn.disabled = !enabled;
+        this.groupResumeBtn.disabled = !enabled;
+        this.groupStopBtn.disabled = !enabled;
+        this.groupSeekBtn.disabled = !enabled;
+    }
+
     updateLoadedFiles() {
         if (this.loadedFiles.length === 0) {
             this.loadedFilesContainer.innerHTML = '<p class="empty-state">No media files loaded</p>';
         } else {
             this.loadedFilesContainer.innerHTML = this.loadedFiles.map(file => `
                 <div class="file-item">
+                    <img class="file-thumb" src="/thumbnail?file=${encodeURIComponent(file.name)}" alt="" loading="lazy" onerror="this.remove()">
                     <div class="file-info">
-                        <div class="file-name">${file.name}</div>
+                        <div class="file-name">${this.escapeHtml(file.name)}</div>
                         <div class="file-size">${this.formatFileSize(file.size)}</div>
+                        ${file.metadata && this.formatMetadata(file.metadata) ? `<div class="file-meta">${this.formatMetadata(file.metadata)}</div>` : ''}
                     </div>
                     <div class="file-actions">
-                        <button class="btn btn-sm btn-primary" onclick="app.streamToClients('${file.name}')">
+                        <button class="btn btn-sm btn-primary" data-file="${this.escapeHtml(file.name)}" onclick="app.streamToClients(this.dataset.file)">
                             Stream to Clients
                         </button>
                     </div>
@@ -488,10 +583,12 @@ class MediaSyncApp {
             this.connectedClientsContainer.innerHTML = this.connectedClients.map(client => `
                 <div class="client-item">
                     <div class="file-info">
-                        <div class="file-name">${client.id}</div>
+                        <div class="file-name">${this.escapeHtml(client.id)}</div>
                         <div class="file-size">Connected: ${client.connectedTime}</div>
+                        <div class="file-size">${this.formatClockSync(client)}</div>
+                        ${client.playingFile ? `<div class="file-size">${this.formatPlayback(client)}</div>` : ''}
                     </div>
-                    <div class="file-actions">                        <button class="btn btn-sm btn-danger" onclick="app.disconnectSpecificClient('${client.id}')">
+                    <div class="file-actions">                        <button class="btn btn-sm btn-danger" data-client="${this.escapeHtml(client.id)}" onclick="app.disconnectSpecificClient(this.dataset.client)">
                             Disconnect
                         </button>
                     </div>
@@ -598,11 +695,14 @@ class MediaSyncApp {
         } else {
             this.availableFilesContainer.innerHTML = this.availableFiles.map(file => `
                 <div class="file-item">
+                    <img class="file-thumb" src="/client-thumbnail?id=${encodeURIComponent(file.id)}" alt="" loading="lazy" onerror="this.remove()">
                     <div class="file-info">
-                        <div class="file-name">${file}</div>
+                        <div class="file-name">${this.escapeHtml(file.path)}</div>
+                        <div class="file-size">${this.formatFileSize(file.size)} · ${this.escapeHtml(file.mime)}</div>
+                        ${file.metadata && this.formatMetadata(file.metadata) ? `<div class="file-meta">${this.formatMetadata(file.metadata)}</div>` : ''}
                     </div>
                     <div class="file-actions">
-                        <button class="btn btn-sm btn-primary" onclick="app.requestMedia('${file}')">
+                        <button class="btn btn-sm btn-primary" data-path="${this.escapeHtml(file.path)}" onclick="app.requestMedia(this.dataset.path)">
                             Request
                         </button>
                     </div>
@@ -661,28 +761,28 @@ class MediaSyncApp {
             bytes[i] = binaryString.charCodeAt(i);
         }
         
-        const blob = new Blob([bytes], { type: this.getMimeType(mediaType, filename) });
+        const blob = new Blob([bytes], { type: mediaType.mime });
         const url = URL.createObjectURL(blob);
 
         let playerHTML = '';
         
-        if (mediaType === 'video') {
+        if (mediaType.kind === 'video') {
             playerHTML = `
                 <video controls autoplay style="max-width: 100%; max-height: 100%;">
-                    <source src="${url}" type="${this.getMimeType(mediaType, filename)}">
+                    <source src="${url}" type="${this.escapeHtml(mediaType.mime)}">
                     Your browser does not support the video tag.
                 </video>
             `;
-        } else if (mediaType === 'audio') {
+        } else if (mediaType.kind === 'audio') {
             playerHTML = `
                 <audio controls autoplay style="width: 100%;">
-                    <source src="${url}" type="${this.getMimeType(mediaType, filename)}">
+                    <source src="${url}" type="${this.escapeHtml(mediaType.mime)}">
                     Your browser does not support the audio tag.
                 </audio>
             `;
-        } else if (mediaType === 'image') {
+        } else if (mediaType.kind === 'image') {
             playerHTML = `
-                <img src="${url}" alt="${filename}" style="max-width: 100%; max-height: 100%; object-fit: contain;">
+                <img src="${url}" alt="${this.escapeHtml(filename)}" style="max-width: 100%; max-height: 100%; object-fit: contain;">
             `;
         }
 
@@ -731,40 +831,21 @@ class MediaSyncApp {
     }
 
     // Utility Methods
-    getMimeType(mediaType, filename) {
-        const extension = filename.split('.').pop().toLowerCase();
-        
-        if (mediaType === 'video') {
-            switch (extension) {
-                case 'mp4': return 'video/mp4';
-                case 'avi': return 'video/x-msvideo';
-                case 'mkv': return 'video/x-matroska';
-                case 'mov': return 'video/quicktime';
-                case 'webm': return 'video/webm';
-                default: return 'video/mp4';
-            }
-        } else if (mediaType === 'audio') {
-            switch (extension) {
-                case 'mp3': return 'audio/mpeg';
-                case 'wav': return 'audio/wav';
-                case 'flac': return 'audio/flac';
-                case 'ogg': return 'audio/ogg';
-                case 'aac': return 'audio/aac';
-                default: return 'audio/mpeg';
-            }
-        } else if (mediaType === 'image') {
-            switch (extension) {
-                case 'jpg':
-                case 'jpeg': return 'image/jpeg';
-                case 'png': return 'image/png';
-                case 'gif': return 'image/gif';
-                case 'bmp': return 'image/bmp';
-                case 'webp': return 'image/webp';
-                default: return 'image/jpeg';
-            }
+    formatClockSync(client) {
+        if (client.clockOffsetMs === null || client.clockOffsetMs === undefined) {
+            return 'Clock: syncing...';
         }
-        
-        return 'application/octet-stream';
+        const sign = client.clockOffsetMs >= 0 ? '+' : '';
+        const backlog = client.queuedMessages > 0 ? `, ${client.queuedMessages} message(s) queued` : '';
+        return `Clock offset: ${sign}${client.clockOffsetMs} ms, latency: ${client.roundTripMs} ms${backlog}`;
+    }
+
+    formatPlayback(client) {
+        const position = (client.positionMs / 1000).toFixed(1);
+        const drift = client.driftMs === null || client.driftMs === undefined
+            ? ''
+            : `, drift: ${client.driftMs >= 0 ? '+' : ''}${client.driftMs} ms`;
+        return `Playing ${this.escapeHtml(client.playingFile)} at ${position}s${drift}`;
     }
 
     formatFileSize(bytes) {
@@ -773,6 +854,37 @@ class MediaSyncApp {
         const sizes = ['Bytes', 'KB', 'MB', 'GB'];
         const i = Math.floor(Math.log(bytes) / Math.log(k));
         return parseFloat((bytes / Math.pow(k, i)).toFixed(2)) + ' ' + sizes[i];
+    }
+
+    formatMetadata(metadata) {
+        const parts = [];
+        if (metadata.duration_ms !== undefined) {
+            const seconds = Math.floor(metadata.duration_ms / 1000);
+            const minutes = Math.floor(seconds / 60);
+            const pad = value => String(value).padStart(2, '0');
+            parts.push(minutes >= 60
+                ? `${Math.floor(minutes / 60)}:${pad(minutes % 60)}:${pad(seconds % 60)}`
+                : `${minutes}:${pad(seconds % 60)}`);
+        }
+        if (metadata.width && metadata.height) {
+            parts.push(`${metadata.width}×${metadata.height}`);
+        }
+        // Tags are read from inside the media files, so they are escaped like any other untrusted text
+        const name = [metadata.artist, metadata.title].filter(Boolean).join(' - ');
+        if (name) parts.push(this.escapeHtml(name));
+        if (metadata.album) parts.push(this.escapeHtml(metadata.album));
+        if (metadata.date) parts.push(this.escapeHtml(metadata.date));
+        return parts.join(' · ');
+    }
+
+    // Makes text safe to put into innerHTML, including inside a quoted attribute
+    escapeHtml(text) {
+        return String(text)
+            .replace(/&/g, '&amp;')
+            .replace(/</g, '&lt;')
+            .replace(/>/g, '&gt;')
+            .replace(/"/g, '&quot;')
+            .replace(/'/g, '&#39;');
     }    // Communication with Rust Backend
     async callRustCommand(command, params) {
         try {
@@ -810,18 +922,7 @@ class MediaSyncApp {
         }
     }
 
-    getMediaTypeFromFilename(filename) {
-        const extension = filename.split('.').pop().toLowerCase();
-        
-        if (['mp4', 'avi', 'mkv', 'mov', 'webm'].includes(extension)) {
-            return 'video';
-        } else if (['mp3', 'wav', 'flac', 'ogg', 'aac'].includes(extension)) {
-            return 'audio';
-        } else if (['jpg', 'jpeg', 'png', 'gif', 'bmp', 'webp'].includes(extension)) {
-            return 'image';
-        }
-          return 'unknown';
-    }    isValidDirectoryPath(path) {
+    isValidDirectoryPath(path) {
         // Check if the path looks like a valid directory path
         // This is a basic validation to prevent passing display text instead of paths
         if (!path || typeof path !== 'string') {
@@ -875,7 +976,7 @@ class MediaSyncApp {
         logEntry.className = `log-entry client-log ${type}`;
         logEntry.innerHTML = `
             <span class="timestamp">[${timestamp}]</span> 
-            <span class="log-source">[CLIENT]</span> ${message}
+            <span class="log-source">[CLIENT]</span> ${this.escapeHtml(message)}
         `;
         
         this.statusLog.appendChild(logEntry);
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// A chunk is identified by the file it came from, that file's modification
+// time (so an edited file never serves stale bytes) and its offset
+type ChunkKey = (PathBuf, Option<SystemTime>, u64);
+
+/// Least-recently-used cache of file chunks read for streaming, bounded by
+/// the total number of bytes held. A capacity of 0 disables it.
+pub struct ChunkCache {
+    capacity_bytes: usize,
+    used_bytes: usize,
+    // Each chunk with the generation it was last used in
+    chunks: HashMap<ChunkKey, (Arc<Vec<u8>>, u64)>,
+    // Chunks by generation, least recently used first, so a touch or an
+    // eviction never has to search
+    order: BTreeMap<u64, ChunkKey>,
+    next_generation: u64,
+}
+
+impl ChunkCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            capacity_bytes,
+            used_bytes: 0,
+            chunks: HashMap::new(),
+            order: BTreeMap::new(),
+            next_generation: 0,
+        }
+    }
+
+    /// Changes the byte limit, evicting as needed to fit under it.
+    pub fn set_capacity(&mut self, capacity_bytes: usize) {
+        self.capacity_bytes = capacity_bytes;
+        self.evict_to(capacity_bytes);
+    }
+
+    pub fn get(&mut self, path: &Path, modified: Option<SystemTime>, offset: u64) -> Option<Arc<Vec<u8>>> {
+        let key = (path.to_path_buf(), modified, offset);
+        let generation = self.next_generation;
+        let (chunk, used) = self.chunks.get_mut(&key)?;
+        let chunk = Arc::clone(chunk);
+        let previous = std::mem::replace(used, generation);
+        self.next_generation += 1;
+        self.order.remove(&previous);
+        self.order.insert(generation, key);
+        Some(chunk)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, modified: Option<SystemTime>, offset: u64, chunk: Arc<Vec<u8>>) {
+        if self.capacity_bytes == 0 || chunk.len() > self.capacity_bytes {
+            return;
+        }
+        let key = (path, modified, offset);
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        if let Some((previous, used)) = self.chunks.insert(key.clone(), (Arc::clone(&chunk), generation)) {
+            self.used_bytes -= previous.len();
+            self.order.remove(&used);
+        }
+        self.order.insert(generation, key);
+        self.used_bytes += chunk.len();
+        self.evict_to(self.capacity_bytes);
+    }
+
+    fn evict_to(&mut self, limit_bytes: usize) {
+        while self.used_bytes > limit_bytes {
+            let Some((_, key)) = self.order.pop_first() else { break };
+            if let Some((chunk, _)) = self.chunks.remove(&key) {
+                self.used_bytes -= chunk.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; len])
+    }
+
+    fn cached(cache: &mut ChunkCache, offset: u64) -> bool {
+        cache.get(Path::new("a.mp4"), None, offset).is_some()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_chunk() {
+        let mut cache = ChunkCache::new(30);
+        for offset in 0..3 {
+            cache.insert(PathBuf::from("a.mp4"), None, offset, chunk(10));
+        }
+        // Touch 0, so 1 is now the oldest
+        assert!(cached(&mut cache, 0));
+        cache.insert(PathBuf::from("a.mp4"), None, 3, chunk(10));
+
+        assert!(!cached(&mut cache, 1));
+        assert!(cached(&mut cache, 0));
+        assert!(cached(&mut cache, 2));
+        assert!(cached(&mut cache, 3));
+        assert_eq!(cache.used_bytes, 30);
+    }
+
+    #[test]
+    fn reinserting_a_key_replaces_its_bytes() {
+        let mut cache = ChunkCache::new(30);
+        cache.insert(PathBuf::from("a.mp4"), None, 0, chunk(10));
+        cache.insert(PathBuf::from("a.mp4"), None, 1, chunk(10));
+        cache.insert(PathBuf::from("a.mp4"), None, 0, chunk(15));
+        assert_eq!(cache.used_bytes, 25);
+        assert_eq!(cache.chunks.len(), 2);
+        assert_eq!(cache.order.len(), 2);
+        assert_eq!(cache.get(Path::new("a.mp4"), None, 0).unwrap().len(), 15);
+    }
+
+    #[test]
+    fn chunks_from_another_version_of_the_file_miss() {
+        let mut cache = ChunkCache::new(30);
+        let modified = Some(SystemTime::UNIX_EPOCH);
+        cache.insert(PathBuf::from("a.mp4"), modified, 0, chunk(10));
+        assert!(cache.get(Path::new("a.mp4"), None, 0).is_none());
+        assert!(cache.get(Path::new("a.mp4"), modified, 0).is_some());
+    }
+
+    #[test]
+    fn capacity_zero_keeps_nothing() {
+        let mut cache = ChunkCache::new(0);
+        cache.insert(PathBuf::from("a.mp4"), None, 0, chunk(10));
+        cache.insert(PathBuf::from("a.mp4"), None, 1, chunk(0));
+        assert!(!cached(&mut cache, 0));
+        assert!(!cached(&mut cache, 1));
+        assert_eq!(cache.used_bytes, 0);
+    }
+
+    #[test]
+    fn shrinking_the_capacity_evicts_oldest_first() {
+        let mut cache = ChunkCache::new(30);
+        for offset in 0..3 {
+            cache.insert(PathBuf::from("a.mp4"), None, offset, chunk(10));
+        }
+        cache.set_capacity(15);
+        assert_eq!(cache.used_bytes, 10);
+        assert!(cached(&mut cache, 2));
+        assert!(!cached(&mut cache, 0));
+
+        cache.set_capacity(0);
+        assert_eq!(cache.used_bytes, 0);
+        assert!(cache.order.is_empty());
+    }
+
+    #[test]
+    fn chunks_larger_than_the_capacity_are_not_kept() {
+        let mut cache = ChunkCache::new(30);
+        cache.insert(PathBuf::from("a.mp4"), None, 0, chunk(10));
+        cache.insert(PathBuf::from("a.mp4"), None, 1, chunk(31));
+        assert!(cached(&mut cache, 0));
+        assert!(!cached(&mut cache, 1));
+    }
+}
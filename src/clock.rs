@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+/// How many recent ping/pong samples are kept per client.
+const MAX_SAMPLES: usize = 8;
+
+/// Milliseconds since the UNIX epoch on this machine's wall clock.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Sleeps until the local wall clock reaches `at_ms`. Returns immediately if it already has.
+pub fn sleep_until(at_ms: u64) {
+    let now = now_millis();
+    if at_ms > now {
+        std::thread::sleep(std::time::Duration::from_millis(at_ms - now));
+    }
+}
+
+/// `sleep_until` for async code.
+pub async fn wait_until(at_ms: u64) {
+    let now = now_millis();
+    if at_ms > now {
+        tokio::time::sleep(std::time::Duration::from_millis(at_ms - now)).await;
+    }
+}
+
+/// Best current guess at how a client's clock relates to the server's.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// Client clock minus server clock, in milliseconds
+    pub offset_ms: i64,
+    /// Network round trip excluding the client's processing time
+    pub round_trip_ms: u64,
+    /// Number of samples the estimate was chosen from
+    pub samples: usize,
+}
+
+impl ClockEstimate {
+    /// Converts an instant on the server's clock to the same instant on the client's clock.
+    pub fn to_client_time(self, server_ms: u64) -> u64 {
+        (server_ms as i64 + self.offset_ms).max(0) as u64
+    }
+
+    /// Converts an instant on the client's clock to the same instant on the server's clock.
+    pub fn to_server_time(self, client_ms: u64) -> u64 {
+        (client_ms as i64 - self.offset_ms).max(0) as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    offset_ms: i64,
+    round_trip_ms: u64,
+}
+
+/// Collects NTP-style samples from `ClockPing`/`ClockPong` exchanges.
+#[derive(Debug, Default, Clone)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockSync {
+    /// Records one exchange. `origin` and `arrival` are server timestamps for when the
+    /// ping left and the pong came back; `receive` and `transmit` are the client's
+    /// timestamps for when it got the ping and sent the pong.
+    pub fn add_sample(&mut self, origin: u64, receive: u64, transmit: u64, arrival: u64) {
+        let (t0, t1, t2, t3) = (origin as i64, receive as i64, transmit as i64, arrival as i64);
+        let offset_ms = ((t1 - t0) + (t2 - t3)) / 2;
+        let round_trip_ms = ((t3 - t0) - (t2 - t1)).max(0) as u64;
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockSample { offset_ms, round_trip_ms });
+    }
+
+    /// Picks the sample with the shortest round trip, whose offset is least
+    /// distorted by asymmetric network delay.
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.round_trip_ms)
+            .map(|best| ClockEstimate {
+                offset_ms: best.offset_ms,
+                round_trip_ms: best.round_trip_ms,
+                samples: self.samples.len(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symmetric_delay_gives_the_exact_offset() {
+        // Client 100 ms ahead, 10 ms each way, 5 ms spent answering
+        let mut sync = ClockSync::default();
+        sync.add_sample(1000, 1110, 1115, 1025);
+        assert_eq!(sync.estimate(), Some(ClockEstimate { offset_ms: 100, round_trip_ms: 20, samples: 1 }));
+    }
+
+    #[test]
+    fn asymmetric_delay_is_off_by_half_the_difference() {
+        // Client 100 ms ahead, 30 ms out and 10 ms back
+        let mut sync = ClockSync::default();
+        sync.add_sample(1000, 1130, 1130, 1040);
+        let estimate = sync.estimate().unwrap();
+        assert_eq!(estimate.offset_ms, 110);
+        assert_eq!(estimate.round_trip_ms, 40);
+    }
+
+    #[test]
+    fn negative_offset_converts_both_ways() {
+        // Client 250 ms behind, 5 ms each way, 2 ms spent answering
+        let mut sync = ClockSync::default();
+        sync.add_sample(5000, 4755, 4757, 5012);
+        let estimate = sync.estimate().unwrap();
+        assert_eq!(estimate.offset_ms, -250);
+        assert_eq!(estimate.round_trip_ms, 10);
+        assert_eq!(estimate.to_client_time(5000), 4750);
+        assert_eq!(estimate.to_server_time(4750), 5000);
+        // Clamped rather than wrapping below the epoch
+        assert_eq!(estimate.to_client_time(100), 0);
+    }
+
+    #[test]
+    fn estimate_uses_the_shortest_round_trip() {
+        let mut sync = ClockSync::default();
+        assert_eq!(sync.estimate(), None);
+        sync.add_sample(1000, 1130, 1130, 1040);
+        sync.add_sample(2000, 2110, 2115, 2025);
+        sync.add_sample(3000, 3150, 3150, 3060);
+        assert_eq!(sync.estimate(), Some(ClockEstimate { offset_ms: 100, round_trip_ms: 20, samples: 3 }));
+    }
+
+    #[test]
+    fn old_samples_fall_out_of_the_window() {
+        let mut sync = ClockSync::default();
+        sync.add_sample(1000, 1110, 1115, 1025);
+        for i in 0..MAX_SAMPLES as u64 {
+            let t0 = 2000 + i * 100;
+            sync.add_sample(t0, t0 + 130, t0 + 130, t0 + 40);
+        }
+        assert_eq!(sync.estimate(), Some(ClockEstimate { offset_ms: 110, round_trip_ms: 40, samples: MAX_SAMPLES }));
+    }
+}
@@ -0,0 +1,324 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use crate::clock;
+use crate::protocol::{self, Message};
+
+/// How many `MediaChunk`s of one transfer may be queued for a client before
+/// the sender waits for its writer to catch up.
+const TRANSFER_WINDOW: usize = 16;
+
+/// Default for how many messages may wait to be written to one client on top
+/// of its transfer window before `SlowClientPolicy` applies.
+pub const DEFAULT_SEND_QUEUE_LEN: usize = 256;
+
+/// How long a write already under way, and the `ServerShutdown` after it, may
+/// take once the server is stopping. A client that stopped reading is cut off
+/// after this rather than holding up the shutdown.
+const SHUTDOWN_WRITE_GRACE: Duration = Duration::from_secs(2);
+
+/// What happens to a message for a client whose send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Drop the message if it is one the client can do without (a clock
+    /// ping, drift correction or thumbnail) and keep the client; anything
+    /// else still disconnects it, since missing it would leave the client
+    /// out of step with the group
+    Drop,
+    /// Disconnect the client; it can reconnect and catch up
+    Disconnect,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(SlowClientPolicy::Drop),
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            other => Err(format!("Unknown slow client policy '{}' (expected drop or disconnect)", other)),
+        }
+    }
+}
+
+// A message waiting for a connection's writer task. Transfer chunks hold a
+// permit from the connection's transfer window until they are written
+struct Outbound {
+    message: Message,
+    _permit: Option<OwnedSemaphorePermit>,
+    // Written as a line of JSON instead of a frame, for a pre-framing peer
+    legacy: bool,
+}
+
+/// The sending side of a client connection on the server. Messages go into a
+/// bounded queue written out by a task of their own, so sending never waits
+/// on the network and one slow client can't hold up anybody else. A client
+/// that lets its queue fill up is dealt with by its `SlowClientPolicy`.
+#[derive(Clone)]
+pub struct Connection {
+    address: SocketAddr,
+    queue: mpsc::Sender<Outbound>,
+    transfer_window: Arc<Semaphore>,
+    policy: SlowClientPolicy,
+    // Set while messages are being dropped, so a stalled client is logged once
+    dropping: Arc<AtomicBool>,
+    closed: Arc<watch::Sender<bool>>,
+    // Set by disconnect: the writer stops at once, even mid-write
+    disconnecting: Arc<watch::Sender<bool>>,
+}
+
+impl Connection {
+    /// Starts the writer task for `writer`. It runs until the connection is
+    /// closed or disconnected, a write fails or `stopping` is set, then shuts
+    /// the socket down. When the server is stopping the client is sent
+    /// `ServerShutdown` first.
+    /// `queue_len` messages can wait besides the chunks of a transfer.
+    pub fn open<W: AsyncWrite + Unpin + Send + 'static>(address: SocketAddr, mut writer: W, queue_len: usize, policy: SlowClientPolicy, mut stopping: watch::Receiver<bool>) -> Self {
+        // Room for a full transfer window, so chunks never crowd out other messages
+        let (queue, mut outbound) = mpsc::channel::<Outbound>(queue_len.max(1) + TRANSFER_WINDOW);
+        let (closed, mut closing) = watch::channel(false);
+        let closed = Arc::new(closed);
+        let (disconnecting, mut disconnected) = watch::channel(false);
+        let disconnecting = Arc::new(disconnecting);
+
+        let writer_closed = Arc::clone(&closed);
+        // Held by the writer so the last handle going away, e.g. right after
+        // a Rejected is queued, doesn't read as a disconnect
+        let writer_disconnecting = Arc::clone(&disconnecting);
+        tokio::spawn(async move {
+            let _disconnecting = writer_disconnecting;
+            loop {
+                // Whatever was queued before the close is still written
+                let next = tokio::select! {
+                    biased;
+                    // Whatever is still queued is dropped; the client will ask again
+                    _ = wait_until_set(&mut stopping) => {
+                        let shutdown = protocol::write_message_async(&mut writer, &Message::ServerShutdown);
+                        let _ = tokio::time::timeout(SHUTDOWN_WRITE_GRACE, shutdown).await;
+                        break;
+                    }
+                    // Nothing queued is worth waiting for; the client may have stopped reading
+                    _ = wait_until_set(&mut disconnected) => break,
+                    next = outbound.recv() => next,
+                    _ = closing.changed() => None,
+                };
+                let Some(mut next) = next else { break };
+                // Stamped here rather than when queued, so time spent behind
+                // other messages doesn't count as network latency
+                if let Message::ClockPing { origin_ms } = &mut next.message {
+                    *origin_ms = clock::now_millis();
+                }
+                let write = async {
+                    if next.legacy {
+                        protocol::write_legacy_message_async(&mut writer, &next.message).await
+                    } else {
+                        protocol::write_message_async(&mut writer, &next.message).await
+                    }
+                };
+                let written = tokio::select! {
+                    written = write => written,
+                    _ = wait_until_set(&mut disconnected) => break,
+                    _ = async {
+                        wait_until_set(&mut stopping).await;
+                        tokio::time::sleep(SHUTDOWN_WRITE_GRACE).await;
+                    } => break,
+                };
+                if let Err(e) = written {
+                    eprintln!("Error sending message to {}: {}", address, e);
+                    break;
+                }
+            }
+            writer_closed.send_replace(true);
+            let _ = writer.shutdown().await;
+        });
+
+        Self {
+            address,
+            queue,
+            transfer_window: Arc::new(Semaphore::new(TRANSFER_WINDOW)),
+            policy,
+            dropping: Arc::new(AtomicBool::new(false)),
+            closed,
+            disconnecting,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Queues a message, applying the slow client policy if the queue is
+    /// full. Returns false once the connection is closed, or if the message
+    /// was dropped.
+    pub fn send(&self, message: Message) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        let droppable = is_droppable(&message);
+        match self.queue.try_send(Outbound { message, _permit: None, legacy: false }) {
+            Ok(()) => {
+                // Caught up once the queue had emptied, not as soon as one slot frees
+                if self.queued() <= 1 && self.dropping.swap(false, Ordering::Relaxed) {
+                    println!("{} caught up, no longer dropping messages", self.address);
+                }
+                true
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => match self.policy {
+                SlowClientPolicy::Drop if droppable => {
+                    if !self.dropping.swap(true, Ordering::Relaxed) {
+                        eprintln!("Send queue for {} is full, dropping messages until it catches up", self.address);
+                    }
+                    false
+                }
+                SlowClientPolicy::Drop | SlowClientPolicy::Disconnect => {
+                    eprintln!("{} fell too far behind ({} messages queued), disconnecting", self.address, self.queued());
+                    self.disconnect();
+                    false
+                }
+            },
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Queues a message that must arrive in order with the ones around it,
+    /// such as the start or end of a file transfer, waiting for room in the
+    /// queue rather than applying the slow client policy. Returns false once
+    /// the connection is closed.
+    pub async fn deliver(&self, message: Message) -> bool {
+        !self.is_closed() && self.queue.send(Outbound { message, _permit: None, legacy: false }).await.is_ok()
+    }
+
+    /// Queues a message for a peer that predates framing, written as a line
+    /// of newline-delimited JSON, which is all it can read.
+    pub fn send_legacy(&self, message: Message) -> bool {
+        !self.is_closed() && self.queue.try_send(Outbound { message, _permit: None, legacy: true }).is_ok()
+    }
+
+    /// Queues part of a file transfer, first waiting until fewer than
+    /// `TRANSFER_WINDOW` chunks are queued, so a whole file is never held in
+    /// memory. Returns false once the connection is closed.
+    pub async fn send_chunk(&self, message: Message) -> bool {
+        let Ok(permit) = Arc::clone(&self.transfer_window).acquire_owned().await else {
+            return false;
+        };
+        !self.is_closed() && self.queue.send(Outbound { message, _permit: Some(permit), legacy: false }).await.is_ok()
+    }
+
+    /// Messages waiting to be written to the client.
+    pub fn queued(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    /// Stops the writer once the messages already queued are written.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// Closes the connection straight away: a write under way is abandoned,
+    /// whatever is still queued is thrown away and the socket is shut down.
+    pub fn disconnect(&self) {
+        self.disconnecting.send_replace(true);
+        self.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Resolves once the connection is closed, from either end.
+    pub async fn closed(&self) {
+        wait_until_set(&mut self.closed.subscribe()).await;
+    }
+
+    /// Whether `other` is a handle to this same connection.
+    pub fn is_same(&self, other: &Connection) -> bool {
+        self.queue.same_channel(&other.queue)
+    }
+}
+
+// Messages a client can miss without getting out of step: pings and
+// corrections are sent again, and a thumbnail can be asked for again
+fn is_droppable(message: &Message) -> bool {
+    matches!(message, Message::ClockPing { .. } | Message::SyncCorrection { .. } | Message::Thumbnail { .. })
+}
+
+/// Resolves once `flag` is true, or its sender is gone.
+pub async fn wait_until_set(flag: &mut watch::Receiver<bool>) {
+    let _ = flag.wait_for(|set| *set).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    // A connection whose client never reads, so its queue fills up
+    fn stalled(policy: SlowClientPolicy) -> (Connection, DuplexStream, watch::Sender<bool>) {
+        let (writer, client) = tokio::io::duplex(1);
+        let (stopping, stop) = watch::channel(false);
+        let connection = Connection::open("127.0.0.1:9000".parse().unwrap(), writer, 1, policy, stop);
+        (connection, client, stopping)
+    }
+
+    fn ping() -> Message {
+        Message::ClockPing { origin_ms: 0 }
+    }
+
+    // Resolves once the writer has shut the socket down
+    async fn assert_shut_down(mut client: DuplexStream) {
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+            .await
+            .expect("the socket was not shut down")
+            .unwrap();
+    }
+
+    // Sends pings until one doesn't fit, returning how many did
+    fn fill(connection: &Connection) -> usize {
+        (0..).take_while(|_| connection.send(ping())).count()
+    }
+
+    #[tokio::test]
+    async fn drop_policy_discards_droppable_messages_only() {
+        let (connection, client, _stopping) = stalled(SlowClientPolicy::Drop);
+        assert_eq!(fill(&connection), 1 + TRANSFER_WINDOW);
+        assert!(!connection.is_closed());
+        assert!(!connection.send(Message::SyncCorrection { filename: "a.mp4".to_string(), correction: protocol::Correction::Rate { rate: 1.0 } }));
+        assert!(!connection.is_closed());
+
+        // Missing this would leave the client out of step, so it is cut off instead
+        assert!(!connection.send(Message::Stop));
+        assert!(connection.is_closed());
+        connection.closed().await;
+        assert_shut_down(client).await;
+    }
+
+    #[tokio::test]
+    async fn disconnect_policy_closes_a_full_queue() {
+        let (connection, client, _stopping) = stalled(SlowClientPolicy::Disconnect);
+        assert_eq!(fill(&connection), 1 + TRANSFER_WINDOW);
+        assert!(connection.is_closed());
+        assert!(!connection.send(ping()));
+        connection.closed().await;
+        assert_shut_down(client).await;
+    }
+
+    #[tokio::test]
+    async fn messages_are_written_while_the_client_keeps_up() {
+        let (writer, mut client) = tokio::io::duplex(64 * 1024);
+        let (_stopping, stop) = watch::channel(false);
+        let connection = Connection::open("127.0.0.1:9000".parse().unwrap(), writer, 1, SlowClientPolicy::Disconnect, stop);
+        assert!(connection.send(Message::Stop));
+        connection.close();
+        connection.closed().await;
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let message = protocol::read_message(&mut std::io::Cursor::new(received)).unwrap();
+        assert!(matches!(message, Some(Message::Stop)));
+    }
+}
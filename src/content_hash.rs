@@ -0,0 +1,34 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+// How much of a file is read between checks for cancellation
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// SHA-256 of a file's contents as lowercase hex. Identifies a file's bytes
+/// independently of its name, so a client can check what it received and
+/// recognise a file it already has.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    hash_file_unless(path, || false)
+}
+
+/// `hash_file` that gives up once `cancelled` returns true, checked between
+/// blocks, so hashing a large file doesn't hold up a shutdown.
+pub fn hash_file_unless(path: &Path, cancelled: impl Fn() -> bool) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut block = vec![0; BLOCK_SIZE];
+    loop {
+        if cancelled() {
+            return Err(io::Error::other("hashing cancelled"));
+        }
+        match file.read(&mut block) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&block[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
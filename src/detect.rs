@@ -0,0 +1,208 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use crate::media_type::MediaType;
+
+/// How much of a file is read to identify it. Enough for every signature
+/// below, including a Matroska DocType a little way into the EBML header.
+const SNIFF_LEN: usize = 4096;
+
+/// Reads the start of `path` and identifies it. `Ok(Err(reason))` means the
+/// file was readable but isn't a format we can play.
+pub fn detect_file(path: &Path) -> io::Result<Result<MediaType, String>> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    fs::File::open(path)?.take(SNIFF_LEN as u64).read_to_end(&mut header)?;
+
+    if header.is_empty() {
+        return Ok(Err("file is empty".to_string()));
+    }
+    let Some(mime) = sniff(&header) else {
+        return Ok(Err(format!("unrecognised content (starts with {})", hex_prefix(&header))));
+    };
+    Ok(MediaType::from_mime(mime).ok_or_else(|| format!("{} is not a supported format", mime)))
+}
+
+/// Identifies a media container or image from its first bytes, as a MIME type.
+pub fn sniff(header: &[u8]) -> Option<&'static str> {
+    // ISO base media (MP4, MOV, M4A): a box size then "ftyp" and the major brand
+    if header.len() >= 12 && &header[4..8] == b"ftyp" {
+        return Some(match &header[8..12] {
+            b"qt  " => "video/quicktime",
+            b"M4A " | b"M4B " | b"M4P " => "audio/mp4",
+            b"heic" | b"heix" | b"mif1" | b"msf1" => "image/heic",
+            brand if brand.starts_with(b"3g") => "video/3gpp",
+            _ => "video/mp4",
+        });
+    }
+
+    // Matroska and WebM share the EBML header; the DocType tells them apart
+    if header.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some(if contains(header, b"webm") {
+            "video/webm"
+        } else {
+            "video/x-matroska"
+        });
+    }
+
+    if header.len() >= 12 && header.starts_with(b"RIFF") {
+        return match &header[8..12] {
+            b"WAVE" => Some("audio/wav"),
+            b"AVI " => Some("video/x-msvideo"),
+            b"WEBP" => Some("image/webp"),
+            _ => None,
+        };
+    }
+
+    if header.starts_with(b"OggS") {
+        return Some(if contains(header, b"theora") {
+            "video/ogg"
+        } else {
+            "audio/ogg"
+        });
+    }
+
+    if header.starts_with(b"fLaC") {
+        return Some("audio/flac");
+    }
+
+    if header.starts_with(b"ID3") {
+        return Some("audio/mpeg");
+    }
+
+    if header.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        return Some("image/png");
+    }
+
+    if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+
+    if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+
+    // "BM" alone is too common in text; also require a known DIB header size
+    if header.len() >= 18 && header.starts_with(b"BM") {
+        let dib_header_size = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        if matches!(dib_header_size, 12 | 40 | 52 | 56 | 64 | 108 | 124) {
+            return Some("image/bmp");
+        }
+    }
+
+    // Raw MPEG audio frame or AAC ADTS frame without any tag in front
+    if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 {
+        if header[1] & 0xF6 == 0xF0 {
+            return Some("audio/aac");
+        }
+        // Layer bits 00 are reserved
+        if header[1] & 0x06 != 0 {
+            return Some("audio/mpeg");
+        }
+    }
+
+    None
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+fn hex_prefix(header: &[u8]) -> String {
+    header
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media_type::MediaKind;
+    use crate::test_support::with_temp_file;
+
+    fn detect_bytes(data: &[u8]) -> Result<MediaType, String> {
+        with_temp_file(data, detect_file).unwrap()
+    }
+
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\0\0\0\x18ftypisom", "video/mp4"),
+        (b"\0\0\0\x14ftypqt  ", "video/quicktime"),
+        (b"\0\0\0\x20ftypM4A ", "audio/mp4"),
+        (b"\0\0\0\x20ftypM4B ", "audio/mp4"),
+        (b"\0\0\0\x18ftypheic", "image/heic"),
+        (b"\0\0\0\x18ftypmif1", "image/heic"),
+        (b"\0\0\0\x14ftyp3gp5", "video/3gpp"),
+        (b"\x1A\x45\xDF\xA3\x93\x42\x82\x88matroska", "video/x-matroska"),
+        (b"\x1A\x45\xDF\xA3\x9F\x42\x82\x84webm", "video/webm"),
+        (b"RIFF\x24\0\0\0WAVEfmt ", "audio/wav"),
+        (b"RIFF\x24\0\0\0AVI LIST", "video/x-msvideo"),
+        (b"RIFF\x24\0\0\0WEBPVP8 ", "image/webp"),
+        (b"OggS\0\x02\0\0\0\0\0\0\0\0\x01vorbis", "audio/ogg"),
+        (b"OggS\0\x02\0\0\0\0\0\0\0\0\x80theora", "video/ogg"),
+        (b"fLaC\0\0\0\x22", "audio/flac"),
+        (b"ID3\x04\0\0\0\0\0\0", "audio/mpeg"),
+        (b"\xFF\xFB\x90\x00", "audio/mpeg"),
+        (b"\xFF\xF3\x80\xC0", "audio/mpeg"),
+        (b"\xFF\xF1\x50\x80", "audio/aac"),
+        (b"\xFF\xF9\x50\x80", "audio/aac"),
+        (b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR", "image/png"),
+        (b"\xFF\xD8\xFF\xE0\0\x10JFIF", "image/jpeg"),
+        (b"GIF87a\x01\0\x01\0", "image/gif"),
+        (b"GIF89a\x01\0\x01\0", "image/gif"),
+        (b"BM\0\0\0\0\0\0\0\0\0\0\0\0\x28\0\0\0", "image/bmp"),
+        (b"BM\0\0\0\0\0\0\0\0\0\0\0\0\x0C\0\0\0", "image/bmp"),
+    ];
+
+    #[test]
+    fn signatures_identify_their_format() {
+        for (header, mime) in SIGNATURES {
+            assert_eq!(sniff(header), Some(*mime), "{}", hex_prefix(header));
+        }
+    }
+
+    #[test]
+    fn every_sniffed_format_is_supported() {
+        for (header, mime) in SIGNATURES {
+            assert_eq!(detect_bytes(header).map(|media_type| media_type.mime), Ok(mime.to_string()));
+        }
+        assert_eq!(detect_bytes(b"\xFF\xD8\xFF\xE0").map(|media_type| media_type.kind), Ok(MediaKind::Image));
+    }
+
+    #[test]
+    fn short_headers_are_not_misread() {
+        assert_eq!(sniff(&[]), None);
+        // Only checks that no signature cut short is read past its end
+        for (header, _) in SIGNATURES {
+            for len in 0..header.len() {
+                sniff(&header[..len]);
+            }
+        }
+        for header in [&b"\0\0\0\x18ftyp"[..], b"RIFF\x24\0\0\0WAV", b"BM\0\0\0\0\0\0\0\0\0\0\0\0\x28\0\0", b"\xFF", b"GIF8", b"\x89PNG"] {
+            assert_eq!(sniff(header), None, "{}", hex_prefix(header));
+        }
+    }
+
+    #[test]
+    fn unknown_content_is_not_identified() {
+        for header in [
+            &b"plain text that isn't media"[..],
+            b"BMP files start with BM but this is text",
+            b"RIFF\x24\0\0\0CDXA",
+            b"%PDF-1.7",
+            b"PK\x03\x04",
+            // An MPEG sync word with the reserved layer
+            b"\xFF\xE0\x90\x00",
+        ] {
+            assert_eq!(sniff(header), None, "{}", hex_prefix(header));
+        }
+    }
+
+    #[test]
+    fn unsupported_files_report_why() {
+        assert_eq!(detect_bytes(b""), Err("file is empty".to_string()));
+        assert_eq!(detect_bytes(b"%PDF-1.7\n%\xE2\xE3"), Err("unrecognised content (starts with 25 50 44 46 2D 31 2E 37)".to_string()));
+        assert_eq!(detect_bytes(b"PK"), Err("unrecognised content (starts with 50 4B)".to_string()));
+    }
+}
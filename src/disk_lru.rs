@@ -0,0 +1,53 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Records a use of a file in a size-bounded directory cache, whose entries'
+/// modification times say when each was last used.
+pub fn touch(path: &Path) -> io::Result<()> {
+    fs::File::options().write(true).open(path)?.set_modified(SystemTime::now())
+}
+
+/// Deletes the least recently used of the files in `dir` that `counts`
+/// accepts until their total size is within `max_bytes`. Files `keep`
+/// accepts count towards the total but are never deleted, even if they alone
+/// are over the limit. Returns the files deleted; `cache` names the
+/// directory in error messages.
+pub fn evict(
+    dir: &Path,
+    max_bytes: u64,
+    cache: &str,
+    counts: impl Fn(&Path) -> bool,
+    keep: impl Fn(&Path) -> bool,
+) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| counts(&entry.path()))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(fs::Metadata::is_file)?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    files.sort();
+
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    let mut evicted = Vec::new();
+    for (_, len, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if keep(&path) {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                total -= len;
+                evicted.push(path);
+            }
+            Err(e) => eprintln!("Could not evict {} from the {}: {}", path.display(), cache, e),
+        }
+    }
+    evicted
+}
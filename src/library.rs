@@ -0,0 +1,279 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+/// Which files under a media root end up in the library.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Only files matching one of these globs are kept; empty keeps everything
+    pub include: Vec<String>,
+    /// Files and directories matching any of these globs are skipped
+    pub exclude: Vec<String>,
+    /// How many directory levels below the root to descend; `None` is unlimited
+    /// and 0 only scans the root itself
+    pub max_depth: Option<usize>,
+}
+
+/// `ScanOptions` with the globs compiled.
+///
+/// A pattern is matched against both the path relative to the root and the
+/// bare file name, so `*.mkv` and `Extras` match at any depth while
+/// `Season 1/*` only matches inside that folder.
+pub struct ScanRules {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    max_depth: Option<usize>,
+}
+
+impl ScanRules {
+    pub fn new(options: &ScanOptions) -> Result<Self, globset::Error> {
+        let include = if options.include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(&options.include)?)
+        };
+
+        Ok(Self {
+            include,
+            exclude: build_glob_set(&options.exclude)?,
+            max_depth: options.max_depth,
+        })
+    }
+
+    fn is_excluded(&self, key: &str, name: &str) -> bool {
+        self.exclude.is_match(key) || self.exclude.is_match(name)
+    }
+
+    fn is_included(&self, key: &str, name: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(key) || include.is_match(name))
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        // `*` stops at a `/`, so only `**` reaches into subfolders
+        builder.add(GlobBuilder::new(pattern).literal_separator(true).build()?);
+    }
+    builder.build()
+}
+
+/// Library key for a file: its path relative to the media root, always with
+/// `/` separators so keys are the same whichever OS the server runs on.
+pub fn relative_key(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<String> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Short identifier for a library key, the same on every run and every
+/// platform so clients can hold on to it. Clients ask for files by id, and a
+/// server only answers for ids in its own index, so nothing a client sends is
+/// ever turned into a path. On the wire it is 16 lowercase hex digits, and
+/// anything else is refused when the message is decoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct MediaId(String);
+
+impl MediaId {
+    /// The id of `key`: a 64-bit FNV-1a hash of it in hex.
+    pub fn for_key(key: &str) -> Self {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+        let hash = key
+            .bytes()
+            .fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME));
+        MediaId(format!("{:016x}", hash))
+    }
+}
+
+impl TryFrom<String> for MediaId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if id.len() == 16 && id.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')) {
+            Ok(MediaId(id))
+        } else {
+            Err(format!("'{}' is not a media id", id.escape_debug()))
+        }
+    }
+}
+
+impl From<MediaId> for String {
+    fn from(id: MediaId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for MediaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Checks that a library key is a plain relative path: components joined by
+/// `/`, none of them empty, `.` or `..`, and no backslashes or control
+/// characters anywhere. Servers leave other files out of the index and
+/// clients drop entries that fail it, so a key is safe to show or to turn
+/// into a local name.
+pub fn validate_key(key: &str) -> Result<(), String> {
+    if let Some(c) = key.chars().find(|c| c.is_control() || *c == '\\') {
+        return Err(format!("'{}' contains {:?}", key.escape_debug(), c));
+    }
+    if key.split('/').any(|component| matches!(component, "" | "." | "..")) {
+        return Err(format!("'{}' is not a plain relative path", key.escape_debug()));
+    }
+    Ok(())
+}
+
+/// Recursively lists the files under `root` that pass `rules`, as
+/// `(key, path)` pairs. Symlinked directories are not followed, so a link
+/// back up the tree can't loop. Subdirectories that can't be read are
+/// reported and skipped rather than failing the whole scan.
+pub fn scan(root: &Path, rules: &ScanRules) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut found = Vec::new();
+    scan_dir(root, root, 0, rules, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+fn scan_dir(root: &Path, dir: &Path, depth: usize, rules: &ScanRules, found: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let Some(key) = relative_key(root, &path) else { continue };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if rules.is_excluded(&key, &name) {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if rules.max_depth.is_none_or(|max_depth| depth < max_depth) {
+                if let Err(e) = scan_dir(root, &path, depth + 1, rules, found) {
+                    eprintln!("Skipping unreadable directory {}: {}", path.display(), e);
+                }
+            }
+        } else if path.is_file() && rules.is_included(&key, &name) {
+            found.push((key, path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_relative_keys_are_valid() {
+        for key in ["song.mp3", "Albums/Live/01 Intro.flac", "..hidden", "a..b/c", "Ärtist/曲.ogg"] {
+            assert_eq!(validate_key(key), Ok(()), "{}", key);
+        }
+    }
+
+    #[test]
+    fn dot_components_are_rejected() {
+        for key in ["..", "../etc/passwd", "a/../../b", "a/..", ".", "./a", "a/./b"] {
+            assert!(validate_key(key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn empty_components_are_rejected() {
+        for key in ["", "a//b", "a/", "/"] {
+            assert!(validate_key(key).is_err(), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn absolute_paths_are_rejected() {
+        for key in ["/etc/passwd", "/a"] {
+            assert!(validate_key(key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn backslashes_are_rejected() {
+        for key in ["a\\b", "..\\..\\windows", "C:\\Users", "\\\\server\\share"] {
+            assert!(validate_key(key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        for key in ["a\nb", "a\0b", "tab\there", "esc\u{1b}[31m", "del\u{7f}", "c1\u{85}"] {
+            assert!(validate_key(key).is_err(), "{:?}", key);
+        }
+    }
+
+    fn scan_rules(include: &[&str], exclude: &[&str]) -> ScanRules {
+        ScanRules::new(&ScanOptions {
+            include: include.iter().map(|pattern| pattern.to_string()).collect(),
+            exclude: exclude.iter().map(|pattern| pattern.to_string()).collect(),
+            max_depth: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn folder_patterns_only_match_directly_inside_the_folder() {
+        let rules = scan_rules(&["Season 1/*"], &[]);
+        assert!(rules.is_included("Season 1/01.mkv", "01.mkv"));
+        assert!(!rules.is_included("Season 1/Extras/making-of.mkv", "making-of.mkv"));
+        assert!(!rules.is_included("Season 2/01.mkv", "01.mkv"));
+
+        let rules = scan_rules(&["Season 1/**"], &[]);
+        assert!(rules.is_included("Season 1/Extras/making-of.mkv", "making-of.mkv"));
+    }
+
+    #[test]
+    fn name_patterns_match_at_any_depth() {
+        let rules = scan_rules(&["*.mkv"], &["Extras"]);
+        assert!(rules.is_included("a/b/c.mkv", "c.mkv"));
+        assert!(!rules.is_included("a/b/c.mp4", "c.mp4"));
+        assert!(rules.is_excluded("Season 1/Extras", "Extras"));
+        assert!(!rules.is_excluded("Season 1/01.mkv", "01.mkv"));
+    }
+
+    #[test]
+    fn media_id_round_trips() {
+        let id = MediaId::for_key("Albums/song.mp3");
+        let text = String::from(id.clone());
+        assert_eq!(text.len(), 16);
+        assert_eq!(MediaId::try_from(text), Ok(id.clone()));
+        assert_eq!(serde_json::from_str::<MediaId>(&serde_json::to_string(&id).unwrap()).unwrap(), id);
+    }
+
+    #[test]
+    fn media_ids_differ_by_key() {
+        assert_ne!(MediaId::for_key("a/b.mp3"), MediaId::for_key("a_b.mp3"));
+    }
+
+    #[test]
+    fn malformed_media_ids_are_rejected() {
+        for id in [
+            "",
+            "0123456789abcde",
+            "0123456789abcdef0",
+            "0123456789ABCDEF",
+            "0123456789abcdeg",
+            "../../etc/passwd",
+            "0123456789abcde/",
+            "0123456789abcd\n\0",
+            "０123456789abcde",
+        ] {
+            assert!(MediaId::try_from(id.to_string()).is_err(), "{:?}", id);
+            assert!(serde_json::from_str::<MediaId>(&serde_json::to_string(id).unwrap()).is_err(), "{:?}", id);
+        }
+    }
+}
@@ -1,66 +1,407 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{Write, BufReader, BufRead};
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::path::Path;
+use std::io::{Read, Write, BufReader, Seek, SeekFrom};
+use std::net::{SocketAddr, TcpStream, Shutdown};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
-use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod chunk_cache;
+mod clock;
+mod connection;
+mod content_hash;
+mod detect;
+mod disk_lru;
+mod library;
+mod media_cache;
+mod media_type;
+mod metadata;
+mod partial;
+mod player;
+mod protocol;
+mod scratch;
+#[cfg(test)]
+mod test_support;
+mod thumbnail;
 mod web_server;
 
-// Protocol messages
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Message {
-    // Client to Server
-    Join { client_id: String },
-    RequestMediaList,
-    RequestMedia { filename: String },
-    
-    // Server to Client
-    Welcome { client_id: String },
-    MediaList { files: Vec<String> },
-    MediaData { 
-        filename: String, 
-        data: Vec<u8>, 
-        media_type: String,
-        timestamp: u64 
-    },
-    PlayCommand { 
-        filename: String, 
-        timestamp: u64 
-    },
-    PauseCommand,
-    Error { message: String },
+use chunk_cache::ChunkCache;
+use clock::{ClockEstimate, ClockSync};
+use connection::{Connection, SlowClientPolicy, DEFAULT_SEND_QUEUE_LEN};
+use library::{MediaId, ScanOptions, ScanRules};
+use media_cache::{MediaCache, DEFAULT_MEDIA_CACHE_SIZE};
+use media_type::MediaType;
+use metadata::MediaMetadata;
+use partial::PartialDownload;
+use player::{Player, PlayerBackend, PlayerResult, SharedPlayer};
+use protocol::{Capability, Correction, FrameError, MediaEntry, Message, RejectReason, MEDIA_CHUNK_SIZE};
+use scratch::ScratchDir;
+use thumbnail::ThumbnailCache;
+use tokio::sync::watch;
+
+/// Number of quick pings sent right after a client joins.
+const CLOCK_SYNC_BURST: u32 = 5;
+const CLOCK_SYNC_BURST_INTERVAL: Duration = Duration::from_millis(200);
+/// Interval between pings once the initial burst is done.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the media roots are rescanned for added, removed or changed files.
+const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// How often files hashed in the background are announced to clients.
+const HASH_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_PLAY_LEAD_TIME: Duration = Duration::from_millis(1500);
+const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+const THUMBNAIL_POLL_INTERVAL: Duration = Duration::from_millis(25);
+/// A thumbnail request with no answer after this long is sent again.
+const THUMBNAIL_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often a playing client sends a `PositionReport`.
+const POSITION_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_DRIFT_THRESHOLD: Duration = Duration::from_millis(100);
+/// Drift beyond this many thresholds is fixed with a seek rather than a rate nudge.
+const SEEK_DRIFT_FACTOR: i64 = 5;
+/// A rate nudge is sized to cancel the drift over this long.
+const RATE_CORRECTION_WINDOW_MS: f64 = 5000.0;
+const MAX_RATE_ADJUSTMENT: f64 = 0.05;
+
+/// A client records how far an incoming file has got each time this much more
+/// of it is on disk, so an interrupted download resumes close to where it stopped.
+const PARTIAL_CHECKPOINT_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Whether and how the server machine itself plays the group media.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostPlayback {
+    /// Only distribute media; nothing plays on the host
+    Off,
+    /// Play on the host at the scheduled start, without drift correction
+    On,
+    /// Play on the host and correct its drift from the group like a client's
+    Participant,
 }
 
+impl FromStr for HostPlayback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(HostPlayback::Off),
+            "on" => Ok(HostPlayback::On),
+            "participant" => Ok(HostPlayback::Participant),
+            other => Err(format!("Unknown host playback mode '{}' (expected off, on or participant)", other)),
+        }
+    }
+}
+
+type StatusCallback = Arc<Mutex<Option<Box<dyn Fn(String) + Send + Sync>>>>;
+// Size and modification time a file had when it was last looked at
+type FileStamp = (u64, Option<SystemTime>);
+type LibraryCallback = Arc<Mutex<Option<Box<dyn Fn(&LibraryChange) + Send + Sync>>>>;
+type ClientMap = Arc<Mutex<HashMap<String, ClientConnection>>>;
+type MediaListCallback = Arc<Mutex<Option<Box<dyn Fn(&[MediaEntry]) + Send + Sync>>>>;
+// A client's socket, locked for each whole frame it writes
+type SharedStream = Arc<Mutex<TcpStream>>;
+
+// Server-side state for one joined client
+struct ClientConnection {
+    connection: Connection,
+    clock: ClockSync,
+    playback: Option<ClientPlayback>,
+}
+
+// Requests a client is being served in the background, so its handler keeps
+// reading while a file or thumbnail is on the way. Dropped with the handler,
+// which aborts whatever is still running
+#[derive(Default)]
+struct ClientWork {
+    tasks: tokio::task::JoinSet<()>,
+    // One transfer at a time per client, so files arrive one after another
+    transfer_turn: Arc<tokio::sync::Mutex<()>>,
+    // One thumbnail at a time per client, so a long list can't tie up every blocking thread
+    thumbnail_turn: Arc<tokio::sync::Mutex<()>>,
+}
+
+// The group play currently running, on the server's clock
+#[derive(Clone)]
+struct PlaybackSession {
+    filename: String,
+    // When position 0 was (or would have been) played
+    start_at_ms: u64,
+    // The original start instant; unlike start_at_ms it survives seeks and
+    // resumes, so it tells apart two plays of the same file
+    scheduled_at_ms: u64,
+    paused_at_position_ms: Option<u64>,
+}
+
+impl PlaybackSession {
+    fn expected_position_at(&self, server_ms: u64) -> Option<u64> {
+        match self.paused_at_position_ms {
+            Some(position_ms) => Some(position_ms),
+            None => server_ms.checked_sub(self.start_at_ms),
+        }
+    }
+}
+
+// Picks the correction for a player that is `drift_ms` off the group while playing
+// at `rate`. `seek_target` gives the position a seek should land on.
+fn drift_correction(drift_ms: i64, threshold_ms: i64, rate: f64, seek_target: impl FnOnce() -> Option<u64>) -> Option<Correction> {
+    if drift_ms.abs() > threshold_ms * SEEK_DRIFT_FACTOR {
+        seek_target().map(|position_ms| Correction::Seek { position_ms })
+    } else if drift_ms.abs() > threshold_ms {
+        let nudged = (1.0 - drift_ms as f64 / RATE_CORRECTION_WINDOW_MS)
+            .clamp(1.0 - MAX_RATE_ADJUSTMENT, 1.0 + MAX_RATE_ADJUSTMENT);
+        // Already nudged this way; give it time to work
+        Some(Correction::Rate { rate: nudged }).filter(|_| (nudged - rate).abs() > 0.001)
+    } else if rate != 1.0 && drift_ms.abs() <= threshold_ms / 2 {
+        // Back in sync: stop nudging
+        Some(Correction::Rate { rate: 1.0 })
+    } else {
+        None
+    }
+}
+
+/// Where a client last said it was, and how that compares to the group.
+#[derive(Clone, Debug)]
+pub struct ClientPlayback {
+    pub filename: String,
+    pub position_ms: u64,
+    /// Server clock time the position was sampled at
+    pub reported_at_ms: u64,
+    pub playing: bool,
+    /// Reported minus expected position; positive means the client is ahead
+    pub drift_ms: Option<i64>,
+    /// Playback rate the client was last told to use
+    pub rate: f64,
+}
+
+// A group play waiting for clients to report MediaReady
+struct PendingPlay {
+    filename: String,
+    waiting_for: HashSet<String>,
+    ready: Vec<String>,
+}
+
+/// Snapshot of a joined client, as reported by `MediaServer::get_connected_clients`.
+#[derive(Clone, Debug)]
+pub struct ConnectedClient {
+    pub id: String,
+    pub address: String,
+    /// Clock offset and latency, once at least one ping has come back
+    pub clock: Option<ClockEstimate>,
+    pub playback: Option<ClientPlayback>,
+    /// Messages waiting to be written to the client; a growing number means it can't keep up
+    pub queued_messages: usize,
+}
+
+/// A library entry. Only the location and metadata are kept; the contents
+/// are read from disk when the file is streamed.
 #[derive(Clone)]
 pub struct MediaFile {
     pub filename: String,
-    pub data: Vec<u8>,
-    pub media_type: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Identified from the file's content
+    pub media_type: MediaType,
+    /// Duration, dimensions and tags read from the file's headers
+    pub metadata: MediaMetadata,
+    /// SHA-256 of the contents in hex, sent so clients can verify what they
+    /// receive. Computed in the background once the server is running, so
+    /// `None` until then
+    pub hash: Option<String>,
+}
+
+impl MediaFile {
+    /// How the file is described to clients in a `MediaList`.
+    pub fn entry(&self) -> MediaEntry {
+        MediaEntry {
+            id: MediaId::for_key(&self.filename),
+            path: self.filename.clone(),
+            size: self.size,
+            media_type: self.media_type.clone(),
+            modified_ms: self
+                .modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_millis() as u64),
+            hash: self.hash.clone(),
+            metadata: (!self.metadata.is_empty()).then(|| self.metadata.clone()),
+        }
+    }
+}
+
+/// Differences found by one rescan of the media roots.
+#[derive(Clone, Default)]
+pub struct LibraryChange {
+    pub added: Vec<MediaFile>,
+    pub removed: Vec<String>,
+    pub updated: Vec<MediaFile>,
+}
+
+impl LibraryChange {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+    }
 }
 
 #[derive(Clone)]
 pub struct MediaServer {
     pub media_files: Arc<Mutex<HashMap<String, MediaFile>>>,
-    clients: Arc<Mutex<HashMap<String, Arc<Mutex<TcpStream>>>>>,
+    // The library key each media id stands for, kept with media_files and
+    // always locked after it
+    media_ids: Arc<Mutex<HashMap<MediaId, String>>>,
+    clients: ClientMap,
     current_media: Arc<Mutex<Option<String>>>,
     is_playing: Arc<Mutex<bool>>,
-    status_callback: Arc<Mutex<Option<Box<dyn Fn(String) + Send + Sync>>>>,
+    pending_play: Arc<Mutex<Option<PendingPlay>>>,
+    playback_session: Arc<Mutex<Option<PlaybackSession>>>,
+    play_lead_time: Arc<Mutex<Duration>>,
+    ready_timeout: Arc<Mutex<Duration>>,
+    drift_threshold: Arc<Mutex<Duration>>,
+    host_playback: Arc<Mutex<HostPlayback>>,
+    host_player: SharedPlayer,
+    chunk_cache: Arc<Mutex<ChunkCache>>,
+    thumbnails: Arc<ThumbnailCache>,
+    scan_options: Arc<Mutex<ScanOptions>>,
+    // Files and directories passed to load_media_path, rescanned by the watcher
+    media_roots: Arc<Mutex<Vec<PathBuf>>>,
+    // Files found not to be playable media, with the size and modification
+    // time they had, so the watcher doesn't sniff and report them every pass
+    skipped_files: Arc<Mutex<HashMap<PathBuf, FileStamp>>>,
+    // Files that were new or changed on the last rescan, with the stamp they
+    // had then. They are only identified once a pass finds them unchanged, so
+    // a file still being written isn't sniffed and announced over and over
+    settling_files: Arc<Mutex<HashMap<PathBuf, FileStamp>>>,
+    // Content hashes already computed, with the stamp the file had at the time
+    hashes: Arc<Mutex<HashMap<PathBuf, (FileStamp, String)>>>,
+    // Woken when the library has files without a hash yet
+    hash_wanted: Arc<tokio::sync::Notify>,
+    watch_interval: Arc<Mutex<Option<Duration>>>,
+    send_queue_len: Arc<Mutex<usize>>,
+    slow_client_policy: Arc<Mutex<SlowClientPolicy>>,
+    library_callback: LibraryCallback,
+    status_callback: StatusCallback,
+    // Set by shutdown to make start_server wind down and return
+    stopping: Arc<watch::Sender<bool>>,
+    // The runtime serve is running on, for group play tasks started from
+    // other threads such as the console
+    runtime: Arc<Mutex<Option<tokio::runtime::Handle>>>,
+    // Woken when a client gets ready for, or leaves, a pending group play
+    ready_changed: Arc<tokio::sync::Notify>,
+    // The task waiting to start the latest group play; a newer play or a stop aborts it
+    play_task: Arc<Mutex<Option<tokio::task::AbortHandle>>>,
+    // The task waiting to resume the host player; a pause or stop aborts it
+    resume_task: Arc<Mutex<Option<tokio::task::AbortHandle>>>,
+}
+
+impl Default for MediaServer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl MediaServer {    pub fn new() -> Self {
+impl MediaServer {
+    pub fn new() -> Self {
         Self {
             media_files: Arc::new(Mutex::new(HashMap::new())),
+            media_ids: Arc::new(Mutex::new(HashMap::new())),
             clients: Arc::new(Mutex::new(HashMap::new())),
             current_media: Arc::new(Mutex::new(None)),
             is_playing: Arc::new(Mutex::new(false)),
+            pending_play: Arc::new(Mutex::new(None)),
+            playback_session: Arc::new(Mutex::new(None)),
+            play_lead_time: Arc::new(Mutex::new(DEFAULT_PLAY_LEAD_TIME)),
+            ready_timeout: Arc::new(Mutex::new(DEFAULT_READY_TIMEOUT)),
+            drift_threshold: Arc::new(Mutex::new(DEFAULT_DRIFT_THRESHOLD)),
+            host_playback: Arc::new(Mutex::new(HostPlayback::On)),
+            host_player: player::shared(PlayerBackend::Auto.create().unwrap()),
+            chunk_cache: Arc::new(Mutex::new(ChunkCache::new(0))),
+            thumbnails: Arc::new(ThumbnailCache::new(std::env::temp_dir().join("media-sync-thumbnails"))),
+            scan_options: Arc::new(Mutex::new(ScanOptions::default())),
+            media_roots: Arc::new(Mutex::new(Vec::new())),
+            skipped_files: Arc::new(Mutex::new(HashMap::new())),
+            settling_files: Arc::new(Mutex::new(HashMap::new())),
+            hashes: Arc::new(Mutex::new(HashMap::new())),
+            hash_wanted: Arc::new(tokio::sync::Notify::new()),
+            watch_interval: Arc::new(Mutex::new(Some(DEFAULT_WATCH_INTERVAL))),
+            send_queue_len: Arc::new(Mutex::new(DEFAULT_SEND_QUEUE_LEN)),
+            slow_client_policy: Arc::new(Mutex::new(SlowClientPolicy::Disconnect)),
+            library_callback: Arc::new(Mutex::new(None)),
             status_callback: Arc::new(Mutex::new(None)),
+            stopping: Arc::new(watch::channel(false).0),
+            runtime: Arc::new(Mutex::new(None)),
+            ready_changed: Arc::new(tokio::sync::Notify::new()),
+            play_task: Arc::new(Mutex::new(None)),
+            resume_task: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// How far in the future a group play is scheduled once all clients are ready.
+    /// Must cover the time for the `PlayCommand` to reach every client.
+    pub fn set_play_lead_time(&self, lead_time: Duration) {
+        *self.play_lead_time.lock().unwrap() = lead_time;
+    }
+
+    /// How far a client may drift from the group before it is sent a `SyncCorrection`.
+    pub fn set_drift_threshold(&self, threshold: Duration) {
+        *self.drift_threshold.lock().unwrap() = threshold;
+    }
+
+    /// How long a group play waits for clients to fetch the media before starting without them.
+    pub fn set_ready_timeout(&self, timeout: Duration) {
+        *self.ready_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Whether the host plays the group media itself or only distributes it.
+    pub fn set_host_playback(&self, mode: HostPlayback) {
+        *self.host_playback.lock().unwrap() = mode;
+    }
+
+    /// Bytes of recently streamed media kept in memory for the next client
+    /// that asks for the same file. 0, the default, always reads from disk.
+    pub fn set_cache_size(&self, bytes: usize) {
+        self.chunk_cache.lock().unwrap().set_capacity(bytes);
+    }
+
+    /// Include/exclude globs and depth limit for the next `load_media_path`.
+    pub fn set_scan_options(&self, options: ScanOptions) {
+        *self.scan_options.lock().unwrap() = options;
+    }
+
+    /// How often `start_server` rescans the media roots for changes; `None` stops watching.
+    pub fn set_watch_interval(&self, interval: Option<Duration>) {
+        *self.watch_interval.lock().unwrap() = interval;
+    }
+
+    /// How many messages may wait to be sent to one client, besides the chunks
+    /// of a file transfer, before the slow client policy applies. Takes effect
+    /// for clients that connect afterwards.
+    pub fn set_send_queue_len(&self, len: usize) {
+        *self.send_queue_len.lock().unwrap() = len;
+    }
+
+    /// What to do with a client whose send queue is full: drop what doesn't
+    /// fit, or disconnect it (the default). Takes effect for clients that
+    /// connect afterwards.
+    pub fn set_slow_client_policy(&self, policy: SlowClientPolicy) {
+        *self.slow_client_policy.lock().unwrap() = policy;
+    }
+
+    /// Called with each batch of library changes the watcher finds.
+    pub fn set_library_callback<F>(&self, callback: F)
+    where
+        F: Fn(&LibraryChange) + Send + Sync + 'static,
+    {
+        *self.library_callback.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Player the host plays the group media on.
+    pub fn set_host_player(&self, player: Box<dyn Player>) {
+        *self.host_player.lock().unwrap() = player;
+    }
+
     pub fn set_status_callback<F>(&self, callback: F)
     where
         F: Fn(String) + Send + Sync + 'static,
@@ -69,6 +410,12 @@ impl MediaServer {    pub fn new() -> Self {
         *status_callback = Some(Box::new(callback));
     }
 
+    // The runtime to run group play tasks on: the one serving clients, or the
+    // caller's own if the server hasn't started serving yet
+    fn runtime(&self) -> Option<tokio::runtime::Handle> {
+        self.runtime.lock().unwrap().clone().or_else(|| tokio::runtime::Handle::try_current().ok())
+    }
+
     fn log_status(&self, message: &str) {
         println!("{}", message);
         if let Some(ref callback) = *self.status_callback.lock().unwrap() {
@@ -76,8 +423,12 @@ impl MediaServer {    pub fn new() -> Self {
         }
     }
 
+    /// Adds a file, or every file under a directory, to the library. Files
+    /// are identified by sniffing their contents but not hashed yet; the
+    /// server does that in the background once it is running.
     pub fn load_media_path(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let mut media_files = self.media_files.lock().unwrap();
+        // Identified into a map of its own so the library isn't locked while the disk is read
+        let mut media_files = HashMap::new();
         
         // Debug logging
         println!("Loading media from path: '{}'", path);
@@ -93,112 +444,430 @@ impl MediaServer {    pub fn new() -> Self {
         if path_obj.is_file() {
             // Handle single file
             println!("Loading single file: {}", path);
-            self.load_single_file(&mut media_files, path_obj)?;
+            let filename = path_obj.file_name().unwrap().to_string_lossy().to_string();
+            self.load_single_file(&mut media_files, filename, path_obj)?;
+            self.media_roots.lock().unwrap().push(path_obj.to_path_buf());
         } else if path_obj.is_dir() {
-            // Handle directory
+            // Handle directory and everything below it, keyed by path relative to it
             println!("Loading directory: {}", path);
-            for entry in fs::read_dir(path_obj)? {
-                let entry = entry?;
-                let file_path = entry.path();
-                
-                if file_path.is_file() {
-                    self.load_single_file(&mut media_files, &file_path)?;
-                }
+            let rules = ScanRules::new(&self.scan_options.lock().unwrap())?;
+            for (key, file_path) in library::scan(path_obj, &rules)? {
+                self.load_single_file(&mut media_files, key, &file_path)?;
             }
+            self.media_roots.lock().unwrap().push(path_obj.to_path_buf());
         } else {
             let error_msg = format!("Path '{}' is not a valid file or directory", path);
             println!("Error: {}", error_msg);
             return Err(error_msg.into());
         }
         
-        if media_files.is_empty() {
+        let mut library = self.media_files.lock().unwrap();
+        let mut ids = self.media_ids.lock().unwrap();
+        let mut media_files: Vec<(String, MediaFile)> = media_files.into_iter().collect();
+        media_files.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, media_file) in media_files {
+            if self.claim_id(&mut ids, &media_file) {
+                library.insert(key, media_file);
+            }
+        }
+        drop(ids);
+        if library.is_empty() {
             println!("No supported media files found in the specified path.");
         } else {
-            println!("Loaded {} media file(s)", media_files.len());
+            println!("Loaded {} media file(s)", library.len());
         }
+        drop(library);
+        self.hash_wanted.notify_one();
         
         Ok(())
     }
 
-    fn load_single_file(&self, media_files: &mut HashMap<String, MediaFile>, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(extension) = path.extension() {
-            let ext = extension.to_string_lossy().to_lowercase();
-            let media_type = match ext.as_str() {
-                "mp4" | "avi" | "mkv" | "mov" | "webm" => "video",
-                "mp3" | "wav" | "flac" | "ogg" | "aac" => "audio",
-                "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" => "image",
-                _ => return Ok(()), // Skip unsupported files
-            };
+    // Indexes a file under its media id, unless another file already has that
+    // id. Such a file is kept out of the library and remembered as skipped,
+    // since a client asking for one of the two could be sent the other
+    fn claim_id(&self, ids: &mut HashMap<MediaId, String>, media_file: &MediaFile) -> bool {
+        let id = MediaId::for_key(&media_file.filename);
+        match ids.get(&id) {
+            Some(owner) if *owner != media_file.filename => {
+                eprintln!("Skipping {}: its media id {} is already used by {}", media_file.filename, id, owner);
+                self.skipped_files
+                    .lock()
+                    .unwrap()
+                    .insert(media_file.path.clone(), (media_file.size, media_file.modified));
+                false
+            }
+            _ => {
+                ids.insert(id, media_file.filename.clone());
+                true
+            }
+        }
+    }
 
-            let filename = path.file_name().unwrap().to_string_lossy().to_string();
-            let data = fs::read(path)?;
-            
-            media_files.insert(filename.clone(), MediaFile {
-                filename: filename.clone(),
-                data,
-                media_type: media_type.to_string(),
-            });
-            
-            println!("Loaded media file: {} ({} bytes)", filename, media_files.get(&filename).unwrap().data.len());
+    fn load_single_file(&self, media_files: &mut HashMap<String, MediaFile>, filename: String, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let metadata = fs::metadata(path)?;
+        if let Some(media_file) = self.identify_file(filename, path, &metadata)? {
+            if media_file.metadata.is_empty() {
+                println!("Loaded media file: {} ({} bytes, {})", media_file.filename, media_file.size, media_file.media_type);
+            } else {
+                println!("Loaded media file: {} ({} bytes, {}, {})", media_file.filename, media_file.size, media_file.media_type, media_file.metadata);
+            }
+            media_files.insert(media_file.filename.clone(), media_file);
         }
         
         Ok(())
     }
 
-    // This function now only plays on the HOST/SERVER machine
-    fn play_media_on_host(filename: &str, data: &[u8], media_type: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Create a temporary file for the media on the host
-        let temp_file = format!("host_temp_{}", filename);
-        fs::write(&temp_file, data)?;
+    // Library entry for a file, identified by its content rather than its name.
+    // Files that aren't playable media, or whose key clients would refuse, are
+    // reported once and remembered as skipped.
+    fn identify_file(&self, filename: String, path: &Path, stat: &fs::Metadata) -> std::io::Result<Option<MediaFile>> {
+        let detected = match library::validate_key(&filename) {
+            Ok(()) => detect::detect_file(path)?,
+            Err(e) => Err(e),
+        };
+        let media_type = match detected {
+            Ok(media_type) => media_type,
+            Err(reason) => {
+                println!("Skipping {}: {}", filename, reason);
+                self.skipped_files
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_path_buf(), (stat.len(), stat.modified().ok()));
+                return Ok(None);
+            }
+        };
         
-        println!("Playing media on HOST: {} ({} bytes, type: {})", filename, data.len(), media_type);
+        if let Some(labelled) = MediaType::from_path(path).filter(|labelled| *labelled != media_type) {
+            println!("Warning: {} is named like {} but contains {}", filename, labelled, media_type);
+        }
         
-        match media_type {
-            "video" | "audio" | "image" => {
-                #[cfg(target_os = "windows")]
-                {
-                    std::process::Command::new("cmd")
-                        .args(&["/C", "start", "", &temp_file])
-                        .spawn()?;
+        // Unreadable tags shouldn't keep an otherwise playable file out of the library
+        let metadata = metadata::extract(path, &media_type).unwrap_or_else(|e| {
+            eprintln!("Could not read metadata from {}: {}", filename, e);
+            MediaMetadata::default()
+        });
+        
+        let stamp = (stat.len(), stat.modified().ok());
+        let hash = self.known_hash(path, stamp);
+        
+        self.skipped_files.lock().unwrap().remove(path);
+        Ok(Some(MediaFile {
+            filename,
+            path: path.to_path_buf(),
+            size: stat.len(),
+            modified: stat.modified().ok(),
+            media_type,
+            metadata,
+            hash,
+        }))
+    }
+
+    // The hash of a file as it was when `stamp` was taken, if already computed
+    fn known_hash(&self, path: &Path, stamp: FileStamp) -> Option<String> {
+        self.hashes
+            .lock()
+            .unwrap()
+            .get(path)
+            .filter(|(hashed, _)| *hashed == stamp)
+            .map(|(_, hash)| hash.clone())
+    }
+
+    // SHA-256 of a file, reading it only if it changed since it was last hashed.
+    // Fails once the server is stopping, so a shutdown doesn't wait for a large file
+    fn content_hash(&self, path: &Path, stamp: FileStamp) -> std::io::Result<String> {
+        if let Some(hash) = self.known_hash(path, stamp) {
+            return Ok(hash);
+        }
+        let hash = content_hash::hash_file_unless(path, || *self.stopping.borrow())?;
+        self.hashes.lock().unwrap().insert(path.to_path_buf(), (stamp, hash.clone()));
+        Ok(hash)
+    }
+
+    // Hashes library files that don't have a hash yet, one at a time, and
+    // announces them as updated so clients can check their caches. Sleeps
+    // until `hash_wanted` when there is nothing to do, until the server stops
+    async fn hash_library(&self) {
+        let mut stopping = self.stopping.subscribe();
+        // Files that couldn't be read, left alone until they change
+        let mut unreadable: HashMap<PathBuf, FileStamp> = HashMap::new();
+        loop {
+            let mut unhashed: Vec<MediaFile> = self
+                .media_files
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|media_file| media_file.hash.is_none())
+                .filter(|media_file| unreadable.get(&media_file.path) != Some(&(media_file.size, media_file.modified)))
+                .cloned()
+                .collect();
+            if unhashed.is_empty() {
+                tokio::select! {
+                    _ = self.hash_wanted.notified() => continue,
+                    _ = connection::wait_until_set(&mut stopping) => break,
                 }
+            }
+            unhashed.sort_by(|a, b| a.filename.cmp(&b.filename));
+            
+            let mut change = LibraryChange::default();
+            let mut published = Instant::now();
+            for media_file in unhashed {
+                let server = self.clone();
+                let path = media_file.path.clone();
+                let stamp = (media_file.size, media_file.modified);
+                let hashing = tokio::task::spawn_blocking(move || server.content_hash(&path, stamp));
+                let hashed = tokio::select! {
+                    hashed = hashing => hashed,
+                    _ = connection::wait_until_set(&mut stopping) => return,
+                };
+                let hash = match hashed {
+                    Ok(Ok(hash)) => hash,
+                    Ok(Err(e)) => {
+                        eprintln!("Could not hash {}: {}", media_file.filename, e);
+                        unreadable.insert(media_file.path, stamp);
+                        continue;
+                    }
+                    Err(_) => return,
+                };
                 
-                #[cfg(target_os = "macos")]
+                // Only if the entry is still the file that was hashed
+                let mut media_files = self.media_files.lock().unwrap();
+                if let Some(entry) = media_files
+                    .get_mut(&media_file.filename)
+                    .filter(|entry| entry.path == media_file.path && (entry.size, entry.modified) == stamp)
                 {
-                    std::process::Command::new("open")
-                        .arg(&temp_file)
-                        .spawn()?;
+                    entry.hash = Some(hash);
+                    change.updated.push(entry.clone());
                 }
+                drop(media_files);
                 
-                #[cfg(target_os = "linux")]
-                {
-                    std::process::Command::new("xdg-open")
-                        .arg(&temp_file)
-                        .spawn()?;
+                // Announced in batches so a large library isn't one message per file
+                if published.elapsed() >= HASH_ANNOUNCE_INTERVAL && !change.is_empty() {
+                    self.publish_library_change(&std::mem::take(&mut change));
+                    published = Instant::now();
                 }
             }
+            if !change.is_empty() {
+                self.publish_library_change(&change);
+            }
+        }
+    }
+
+    // Rescans every media root every `interval` and applies what changed,
+    // until the server stops
+    async fn watch_library(&self, interval: Duration) {
+        let mut stopping = self.stopping.subscribe();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = connection::wait_until_set(&mut stopping) => break,
+            }
             
-            _ => {
-                println!("Unknown media type: {}", media_type);
+            // Scanning and sniffing files is blocking disk work
+            let server = self.clone();
+            let Ok(change) = tokio::task::spawn_blocking(move || server.rescan_library()).await else { break };
+            if !change.is_empty() {
+                self.publish_library_change(&change);
+            }
+        }
+    }
+
+    /// Brings `media_files` in line with what is on disk under the media roots.
+    fn rescan_library(&self) -> LibraryChange {
+        let roots = self.media_roots.lock().unwrap().clone();
+        let rules = match ScanRules::new(&self.scan_options.lock().unwrap()) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!("Invalid scan patterns: {}", e);
+                return LibraryChange::default();
+            }
+        };
+        
+        // Scan without holding the library lock; the disk can be slow
+        let known = self.media_files.lock().unwrap().clone();
+        let mut on_disk = HashMap::new();
+        let mut settling = HashMap::new();
+        for root in &roots {
+            let found = if root.is_dir() {
+                library::scan(root, &rules)
+            } else if root.is_file() {
+                let filename = root.file_name().unwrap().to_string_lossy().to_string();
+                Ok(vec![(filename, root.clone())])
+            } else {
+                Ok(Vec::new())
+            };
+            
+            match found {
+                Ok(found) => {
+                    for (key, path) in found {
+                        if *self.stopping.borrow() {
+                            return LibraryChange::default();
+                        }
+                        // Vanished between the scan and the stat; the next pass will see it gone
+                        let Ok(metadata) = fs::metadata(&path) else { continue };
+                        let stamp = (metadata.len(), metadata.modified().ok());
+                        
+                        // Only sniff files that are new or changed since the last pass
+                        let unchanged = known
+                            .get(&key)
+                            .filter(|entry| entry.path == path && (entry.size, entry.modified) == stamp);
+                        if let Some(entry) = unchanged {
+                            on_disk.insert(key, entry.clone());
+                        } else if self.skipped_files.lock().unwrap().get(&path) != Some(&stamp) {
+                            // Wait for a pass that finds the file as it was on the last one;
+                            // until then the old entry, if any, stays as it is
+                            let settled = self.settling_files.lock().unwrap().get(&path) == Some(&stamp);
+                            if !settled {
+                                settling.insert(path, stamp);
+                                if let Some(entry) = known.get(&key) {
+                                    on_disk.insert(key, entry.clone());
+                                }
+                            } else {
+                                match self.identify_file(key.clone(), &path, &metadata) {
+                                    Ok(Some(media_file)) => {
+                                        on_disk.insert(media_file.filename.clone(), media_file);
+                                    }
+                                    Ok(None) => {}
+                                    // Possibly only for now; keep the old entry and try again next pass
+                                    Err(e) => {
+                                        eprintln!("Could not read {}: {}", path.display(), e);
+                                        settling.insert(path, stamp);
+                                        if let Some(entry) = known.get(&key) {
+                                            on_disk.insert(key, entry.clone());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    // Keep the entries from a root we can't read right now
+                    eprintln!("Could not rescan {}: {}", root.display(), e);
+                    return LibraryChange::default();
+                }
             }
         }
+        // Files that vanished while settling are forgotten with the rest
+        *self.settling_files.lock().unwrap() = settling;
+        
+        let mut media_files = self.media_files.lock().unwrap();
+        let mut ids = self.media_ids.lock().unwrap();
+        let mut change = LibraryChange::default();
+        media_files.retain(|key, media_file| {
+            let watched = roots.iter().any(|root| media_file.path.starts_with(root));
+            if watched && !on_disk.contains_key(key) {
+                ids.remove(&MediaId::for_key(key));
+                change.removed.push(key.clone());
+                false
+            } else {
+                true
+            }
+        });
+        let mut on_disk: Vec<(String, MediaFile)> = on_disk.into_iter().collect();
+        on_disk.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, media_file) in on_disk {
+            match media_files.get(&key) {
+                None if !self.claim_id(&mut ids, &media_file) => continue,
+                None => change.added.push(media_file.clone()),
+                Some(existing) if existing.size != media_file.size || existing.modified != media_file.modified => {
+                    change.updated.push(media_file.clone());
+                }
+                Some(_) => continue,
+            }
+            media_files.insert(key, media_file);
+        }
+        drop(ids);
+        drop(media_files);
+        if change.added.iter().chain(&change.updated).any(|media_file| media_file.hash.is_none()) {
+            self.hash_wanted.notify_one();
+        }
+        
+        change.added.sort_by(|a, b| a.filename.cmp(&b.filename));
+        change.updated.sort_by(|a, b| a.filename.cmp(&b.filename));
+        change.removed.sort();
+        change
+    }
+
+    fn publish_library_change(&self, change: &LibraryChange) {
+        let entries = |files: &[MediaFile]| files.iter().map(MediaFile::entry).collect::<Vec<_>>();
+        self.broadcast(&Message::MediaListChanged {
+            added: entries(&change.added),
+            removed: change.removed.clone(),
+            updated: entries(&change.updated),
+        });
         
+        self.log_status(&format!(
+            "Library changed: {} added, {} removed, {} updated",
+            change.added.len(),
+            change.removed.len(),
+            change.updated.len()
+        ));
+        if let Some(ref callback) = *self.library_callback.lock().unwrap() {
+            callback(change);
+        }
+    }
+
+    // Loads the media into the host player straight from the library, paused at the start
+    fn load_media_on_host(&self, media_file: &MediaFile) -> PlayerResult<()> {
+        println!("Loading media on HOST: {} ({} bytes, type: {})", media_file.filename, media_file.size, media_file.media_type);
+        self.host_player.lock().unwrap().load(&media_file.path, &media_file.media_type)
+    }
+
+    // Runs a transport command on the host player, logging rather than failing the group command
+    fn control_host_player(&self, action: &str, command: impl FnOnce(&mut dyn Player) -> PlayerResult<()>) {
+        if *self.host_playback.lock().unwrap() == HostPlayback::Off {
+            return;
+        }
+        if let Err(e) = command(self.host_player.lock().unwrap().as_mut()) {
+            eprintln!("Host player failed to {}: {}", action, e);
+        }
+    }
+
+    /// Accepts clients on `port` until `shutdown` is called. Each connection is
+    /// served by tasks on the current tokio runtime. Returns once every
+    /// connection is closed and the port is free again.
+    pub async fn start_server(&self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = self.listen(port).await?;
+        self.serve(listener).await;
         Ok(())
-    }    pub fn start_server(&self, port: u16) -> Result<(), Box<dyn std::error::Error>> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    }
+
+    /// Binds the port clients connect to, so a caller can report a port
+    /// that is taken before handing the listener to `serve`.
+    pub async fn listen(&self, port: u16) -> std::io::Result<tokio::net::TcpListener> {
+        tokio::net::TcpListener::bind(("0.0.0.0", port)).await
+    }
+
+    /// `start_server` on a listener from `listen`. A server that was shut
+    /// down stays stopped, even if `shutdown` came before this was called;
+    /// serving again takes a new `MediaServer`.
+    pub async fn serve(&self, listener: tokio::net::TcpListener) {
+        let port = listener.local_addr().map_or(0, |address| address.port());
+        *self.runtime.lock().unwrap() = Some(tokio::runtime::Handle::current());
         self.log_status(&format!("Media server started on port {}", port));
         self.log_status("Waiting for clients to connect...");
+        
+        let mut tasks = tokio::task::JoinSet::new();
+        let server = self.clone();
+        tasks.spawn(async move { server.hash_library().await });
+        if let Some(interval) = *self.watch_interval.lock().unwrap() {
+            let server = self.clone();
+            tasks.spawn(async move { server.watch_library(interval).await });
+        }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let media_files = Arc::clone(&self.media_files);
-                    let clients = Arc::clone(&self.clients);
-                    let current_media = Arc::clone(&self.current_media);
-                    let is_playing = Arc::clone(&self.is_playing);
-                    let status_callback = Arc::clone(&self.status_callback);
-                    
-                    thread::spawn(move || {
-                        Self::handle_client(stream, media_files, clients, current_media, is_playing, status_callback);
+        let mut stopping = self.stopping.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // Reap finished connections so the set doesn't grow with every client ever seen
+                Some(_) = tasks.join_next() => continue,
+                _ = connection::wait_until_set(&mut stopping) => break,
+            };
+            match accepted {
+                Ok((stream, address)) => {
+                    let server = self.clone();
+                    tasks.spawn(async move {
+                        server.handle_client(stream, address).await;
                     });
                 }
                 Err(e) => {
@@ -207,244 +876,821 @@ impl MediaServer {    pub fn new() -> Self {
             }
         }
         
-        Ok(())
-    }    fn handle_client(
-        stream: TcpStream,
-        media_files: Arc<Mutex<HashMap<String, MediaFile>>>,
-        clients: Arc<Mutex<HashMap<String, Arc<Mutex<TcpStream>>>>>,
-        current_media: Arc<Mutex<Option<String>>>,
-        is_playing: Arc<Mutex<bool>>,
-        status_callback: Arc<Mutex<Option<Box<dyn Fn(String) + Send + Sync>>>>,
-    ) {
-        let peer_addr = stream.peer_addr().unwrap_or_else(|_| "unknown".parse().unwrap());
+        // Dropping the listener frees the port. Each connection's writer sends
+        // ServerShutdown and closes the socket, which ends its handler
+        drop(listener);
+        self.log_status(&format!("Shutting down media server on port {}", port));
+        while tasks.join_next().await.is_some() {}
         
-        // Log status through callback
-        if let Some(callback) = status_callback.lock().unwrap().as_ref() {
-            callback(format!("New client connected: {}", peer_addr));
+        // Nobody is left to play along with
+        *self.pending_play.lock().unwrap() = None;
+        for task in [&self.play_task, &self.resume_task] {
+            if let Some(task) = task.lock().unwrap().take() {
+                task.abort();
+            }
+        }
+        self.runtime.lock().unwrap().take();
+        if self.playback_session.lock().unwrap().take().is_some() {
+            let server = self.clone();
+            let _ = tokio::task::spawn_blocking(move || server.control_host_player("stop", |player| player.stop())).await;
         }
+        *self.is_playing.lock().unwrap() = false;
+        *self.current_media.lock().unwrap() = None;
         
-        let stream = Arc::new(Mutex::new(stream));
-        let mut reader = BufReader::new(stream.lock().unwrap().try_clone().unwrap());
+        self.log_status(&format!("Media server on port {} stopped", port));
+    }
+
+    /// Makes a running `start_server` stop accepting clients, send each one
+    /// `ServerShutdown`, close their connections and the library watcher, and
+    /// return. Doesn't wait for any of that; callable from any thread, e.g. a
+    /// signal handler or a UI event.
+    pub fn shutdown(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    async fn handle_client(&self, stream: tokio::net::TcpStream, peer_addr: SocketAddr) {
+        self.log_status(&format!("New client connected: {}", peer_addr));
+        
+        let (reader, writer) = stream.into_split();
+        let queue_len = *self.send_queue_len.lock().unwrap();
+        let policy = *self.slow_client_policy.lock().unwrap();
+        let connection = Connection::open(peer_addr, writer, queue_len, policy, self.stopping.subscribe());
+        let mut reader = protocol::FrameReader::new(reader);
         let mut client_id = String::new();
+        let mut work = ClientWork::default();
 
         loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(0) => {
-                    if let Some(callback) = status_callback.lock().unwrap().as_ref() {
-                        callback(format!("Client {} disconnected", peer_addr));
-                    }
-                    if !client_id.is_empty() {
-                        clients.lock().unwrap().remove(&client_id);
-                    }
+            let read = tokio::select! {
+                // Cancel safe, so losing to another branch mid-frame loses no bytes
+                read = reader.read_message() => read,
+                // Reap finished requests so the set doesn't grow with every one served
+                Some(_) = work.tasks.join_next() => continue,
+                // Closed by disconnect_client or a failed write
+                _ = connection.closed() => {
+                    self.log_status(&format!("Client {} disconnected", peer_addr));
+                    self.remove_client(&client_id, &connection);
                     break;
                 }
-                Ok(_) => {
-                    line = line.trim().to_string();
-                    if line.is_empty() {
-                        continue;
-                    }
-                    
-                    if let Ok(message) = serde_json::from_str::<Message>(&line) {
-                        if let Message::Join { client_id: id } = &message {
-                            client_id = id.clone();
-                            clients.lock().unwrap().insert(client_id.clone(), Arc::clone(&stream));
+            };
+            // Taken before anything else is done, so a ClockPong's round trip
+            // doesn't include time spent handling other messages
+            let received_ms = clock::now_millis();
+            
+            match read {
+                Ok(Some(Message::Join { client_id: id, protocol_version, capabilities })) => {
+                    match protocol::negotiate(protocol_version, &capabilities) {
+                        Ok(shared) => {
+                            client_id = id;
+                            self.clients.lock().unwrap().insert(client_id.clone(), ClientConnection {
+                                connection: connection.clone(),
+                                clock: ClockSync::default(),
+                                playback: None,
+                            });
+                            connection.send(Message::Welcome {
+                                client_id: client_id.clone(),
+                                protocol_version: protocol::PROTOCOL_VERSION,
+                                capabilities: shared.clone(),
+                            });
+                            self.log_status(&format!("Client {} joined (protocol v{}, capabilities: {:?})", client_id, protocol_version, shared));
+                            
+                            if shared.contains(&Capability::ClockSync) {
+                                self.spawn_clock_pinger(client_id.clone(), connection.clone());
+                            }
+                        }
+                        Err(reason) => {
+                            self.log_status(&format!("Rejected client {} from {}: {}", id, peer_addr, reason));
+                            connection.send(Message::Rejected { reason });
+                            break;
                         }
-                        
-                        Self::process_message(
-                            message,
-                            &stream,
-                            &media_files,
-                            &clients,
-                            &current_media,
-                            &is_playing,
-                            &status_callback,
-                        );
-                    } else {
-                        eprintln!("Failed to parse message: {}", line);
                     }
                 }
+                Ok(Some(message)) => {
+                    self.process_message(message, received_ms, &connection, &client_id, &mut work);
+                }
+                Ok(None) => {
+                    self.log_status(&format!("Client {} disconnected", peer_addr));
+                    self.remove_client(&client_id, &connection);
+                    break;
+                }
+                Err(FrameError::LegacyPeer) => {
+                    // Answered in its own format, so it can say why rather than just losing the connection
+                    self.log_status(&format!("Rejected client from {}: it sent newline-delimited JSON, so it looks like a pre-framing (protocol version 1) client", peer_addr));
+                    connection.send_legacy(Message::Rejected {
+                        reason: RejectReason::UnsupportedVersion {
+                            client_version: 1,
+                            min_supported: protocol::MIN_PROTOCOL_VERSION,
+                            max_supported: protocol::PROTOCOL_VERSION,
+                        },
+                    });
+                    break;
+                }
+                Err(e) if e.is_recoverable() => {
+                    // The bad frame was consumed whole, so tell the client and keep going
+                    self.log_status(&format!("Rejected frame from {}: {}", peer_addr, e));
+                    connection.send(Message::Error {
+                        message: format!("Rejected frame: {}", e),
+                    });
+                }
                 Err(e) => {
                     eprintln!("Error reading from client {}: {}", peer_addr, e);
-                    // Remove client from clients list
-                    if !client_id.is_empty() {
-                        clients.lock().unwrap().remove(&client_id);
-                    }
+                    self.remove_client(&client_id, &connection);
                     break;
                 }
             }
         }
-    }    fn process_message(
-        message: Message,
-        stream: &Arc<Mutex<TcpStream>>,
-        media_files: &Arc<Mutex<HashMap<String, MediaFile>>>,
-        clients: &Arc<Mutex<HashMap<String, Arc<Mutex<TcpStream>>>>>,
-        current_media: &Arc<Mutex<Option<String>>>,
-        is_playing: &Arc<Mutex<bool>>,
-        status_callback: &Arc<Mutex<Option<Box<dyn Fn(String) + Send + Sync>>>>,
-    ) {
-        match message {
-            Message::Join { client_id } => {
-                let response = Message::Welcome { 
-                    client_id: client_id.clone() 
-                };
-                Self::send_message(stream, &response);
+        
+        connection.close();
+    }
+
+    // Only removes the entry if it still belongs to this connection, so a client
+    // that reconnected under the same id isn't dropped by its old handler
+    fn remove_client(&self, client_id: &str, connection: &Connection) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.get(client_id).is_some_and(|client| client.connection.is_same(connection)) {
+            clients.remove(client_id);
+            // Don't hold up a pending group play for a client that's gone
+            if let Some(pending) = self.pending_play.lock().unwrap().as_mut() {
+                if pending.waiting_for.remove(client_id) {
+                    self.ready_changed.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Pings a client in a short burst to get an initial clock estimate, then
+    /// periodically to track drift, until the client leaves.
+    fn spawn_clock_pinger(&self, client_id: String, connection: Connection) {
+        let clients = Arc::clone(&self.clients);
+        tokio::spawn(async move {
+            let mut sent = 0;
+            loop {
+                let connected = clients
+                    .lock()
+                    .unwrap()
+                    .get(&client_id)
+                    .is_some_and(|client| client.connection.is_same(&connection));
+                if !connected {
+                    break;
+                }
+                
+                // The connection's writer stamps origin_ms as the ping goes out.
+                // A ping dropped for a slow client is simply missed
+                if !connection.send(Message::ClockPing { origin_ms: 0 }) && connection.is_closed() {
+                    break;
+                }
+                sent += 1;
                 
-                if let Some(callback) = status_callback.lock().unwrap().as_ref() {
-                    callback(format!("Client {} joined", client_id));
+                let interval = if sent < CLOCK_SYNC_BURST { CLOCK_SYNC_BURST_INTERVAL } else { CLOCK_SYNC_INTERVAL };
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    // Handles one message from a client. Anything that takes a while, like a
+    // transfer or a thumbnail, runs as a task in `work` so later messages from
+    // the same client are read in the meantime
+    fn process_message(&self, message: Message, received_ms: u64, connection: &Connection, client_id: &str, work: &mut ClientWork) {
+        match message {
+            Message::ClockPong { origin_ms, receive_ms, transmit_ms } => {
+                if let Some(client) = self.clients.lock().unwrap().get_mut(client_id) {
+                    client.clock.add_sample(origin_ms, receive_ms, transmit_ms, received_ms);
+                }
+            }
+            
+            Message::PositionReport { filename, position_ms, at_ms, playing } => {
+                self.handle_position_report(client_id, filename, position_ms, at_ms, playing);
+            }
+            
+            Message::MediaCorrupt { filename, expected_hash, actual_hash } => {
+                self.log_status(&format!(
+                    "Client {} received a corrupt copy of {} (expected SHA-256 {}, got {})",
+                    client_id, filename, expected_hash, actual_hash
+                ));
+            }
+            
+            Message::MediaReady { filename } => {
+                let mut pending = self.pending_play.lock().unwrap();
+                if let Some(pending) = pending.as_mut().filter(|pending| pending.filename == filename) {
+                    if pending.waiting_for.remove(client_id) {
+                        pending.ready.push(client_id.to_string());
+                        self.ready_changed.notify_one();
+                        self.log_status(&format!("Client {} is ready to play {}", client_id, filename));
+                    }
                 }
             }
             
             Message::RequestMediaList => {
-                let media_files = media_files.lock().unwrap();
-                let files: Vec<String> = media_files.keys().cloned().collect();
-                let response = Message::MediaList { files };
-                Self::send_message(stream, &response);
+                let mut files: Vec<MediaEntry> = self.media_files.lock().unwrap().values().map(MediaFile::entry).collect();
+                files.sort_by(|a, b| a.path.cmp(&b.path));
+                connection.send(Message::MediaList { files });
+            }
+            
+            Message::RequestThumbnail { id } => {
+                let server = self.clone();
+                let connection = connection.clone();
+                let turn = Arc::clone(&work.thumbnail_turn);
+                work.tasks.spawn(async move {
+                    let _turn = turn.lock().await;
+                    server.send_thumbnail(&connection, id).await;
+                });
             }
             
-            Message::RequestMedia { filename } => {
-                let media_files = media_files.lock().unwrap();
-                if let Some(media_file) = media_files.get(&filename) {
+            // Only transfers the file; group plays are started by the host through play_media
+            Message::RequestMedia { id } => {
+                if let Some(media_file) = self.media_file_by_id(&id) {
+                    self.log_status(&format!("Client requested media: {} ({} bytes)", media_file.filename, media_file.size));
+                    
+                    let server = self.clone();
+                    let connection = connection.clone();
+                    let turn = Arc::clone(&work.transfer_turn);
+                    work.tasks.spawn(async move {
+                        let _turn = turn.lock().await;
+                        let filename = media_file.filename.clone();
+                        let timestamp = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs();
+                        
+                        // Stream media data to the requesting client
+                        server.log_status(&format!("Sending media data to CLIENT for: {} ({} bytes)", filename, media_file.size));
+                        if let Err(e) = server.send_media(&connection, &media_file, timestamp, None).await {
+                            eprintln!("Error sending media {}: {}", filename, e);
+                        }
+                    });
+                } else {
+                    connection.send(Message::Error {
+                        message: format!("No media file with id '{}'", id),
+                    });
+                }
+            }
+            
+            // Finishing an earlier download
+            Message::RequestMediaRange { id, offset, len, hash } => {
+                let Some(media_file) = self.media_file_by_id(&id) else {
+                    connection.send(Message::Error {
+                        message: format!("No media file with id '{}'", id),
+                    });
+                    return;
+                };
+                
+                self.log_status(&format!("Client resumed media: {} from byte {} of {}", media_file.filename, offset, media_file.size));
+                let server = self.clone();
+                let connection = connection.clone();
+                let turn = Arc::clone(&work.transfer_turn);
+                work.tasks.spawn(async move {
+                    let _turn = turn.lock().await;
                     let timestamp = SystemTime::now()
                         .duration_since(UNIX_EPOCH)
                         .unwrap()
                         .as_secs();
-                    
-                    if let Some(callback) = status_callback.lock().unwrap().as_ref() {
-                        callback(format!("Client requested media: {} ({} bytes)", filename, media_file.data.len()));
+                    if let Err(e) = server.send_media(&connection, &media_file, timestamp, Some((offset, len, hash))).await {
+                        eprintln!("Error sending media {}: {}", media_file.filename, e);
                     }
-                    
-                    // Send media data to the requesting client
-                    let response = Message::MediaData {
-                        filename: media_file.filename.clone(),
-                        data: media_file.data.clone(),
-                        media_type: media_file.media_type.clone(),
-                        timestamp,
-                    };
-                    
-                    if let Some(callback) = status_callback.lock().unwrap().as_ref() {
-                        callback(format!("Sending media data to CLIENT for: {} ({} bytes)", filename, media_file.data.len()));
-                    }
-                    Self::send_message(stream, &response);
-                    
-                    // Set as current media and start playing
-                    *current_media.lock().unwrap() = Some(filename.clone());
-                    *is_playing.lock().unwrap() = true;
-                    
-                    // Play media ONLY on the server/host side
-                    if let Err(e) = Self::play_media_on_host(&media_file.filename, &media_file.data, &media_file.media_type) {
-                        eprintln!("Error playing media on host: {}", e);
-                    } else {
-                        if let Some(callback) = status_callback.lock().unwrap().as_ref() {
-                            callback(format!("Started playing {} on HOST", filename));
-                        }
-                    }
-                    
-                    // Send play command to all OTHER clients (not the requesting one)
-                    let play_command = Message::PlayCommand {
-                        filename: filename.clone(),
-                        timestamp,
-                    };
-                    Self::broadcast_to_others(clients, stream, &play_command);
-                    
-                } else {
-                    let response = Message::Error {
-                        message: format!("Media file '{}' not found", filename),
-                    };
-                    Self::send_message(stream, &response);
-                }
+                });
             }
             
             _ => {}
         }
     }
 
-    fn send_message(stream: &Arc<Mutex<TcpStream>>, message: &Message) {
-        if let Ok(data) = serde_json::to_string(message) {
-            if let Ok(mut stream) = stream.lock() {
-                let line = format!("{}\n", data);
-                if let Err(e) = stream.write_all(line.as_bytes()) {
-                    eprintln!("Error sending message: {}", e);
-                    return;
-                }
-                if let Err(e) = stream.flush() {
-                    eprintln!("Error flushing stream: {}", e);
-                }
+    // Answers a RequestThumbnail
+    async fn send_thumbnail(&self, connection: &Connection, id: MediaId) {
+        let response = match self.media_file_by_id(&id) {
+            Some(media_file) => {
+                // Decoding and scaling an image is too slow to do on the async runtime
+                let server = self.clone();
+                let filename = media_file.filename.clone();
+                let thumbnail = tokio::task::spawn_blocking(move || server.thumbnail_of(&media_file).map_err(|e| e.to_string()))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()));
+                // A file that can't be decoded is answered like one without a
+                // picture, so the client stops waiting for it
+                let data = thumbnail.unwrap_or_else(|e| {
+                    eprintln!("No thumbnail for '{}': {}", filename, e);
+                    None
+                });
+                Message::Thumbnail { id, data }
             }
+            None => Message::Error {
+                message: format!("No media file with id '{}'", id),
+            },
+        };
+        connection.send(response);
+    }
+
+    // The library entry a client asked for by id. Cloned so the library lock
+    // isn't held for a whole transfer
+    fn media_file_by_id(&self, id: &MediaId) -> Option<MediaFile> {
+        let key = self.media_ids.lock().unwrap().get(id)?.clone();
+        self.media_files.lock().unwrap().get(&key).cloned()
+    }
+
+    /// JPEG thumbnail of a library file, or `None` if it has no picture to show.
+    /// Generated on first request and cached on disk.
+    pub fn thumbnail(&self, filename: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let media_file = self
+            .media_files
+            .lock()
+            .unwrap()
+            .get(filename)
+            .cloned()
+            .ok_or_else(|| format!("Media file '{}' not found", filename))?;
+        self.thumbnail_of(&media_file)
+    }
+
+    // Thumbnails are cached by content, so a file the background hasher hasn't
+    // reached yet is hashed here first
+    fn thumbnail_of(&self, media_file: &MediaFile) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let hash = match &media_file.hash {
+            Some(hash) => hash.clone(),
+            None => self.content_hash(&media_file.path, (media_file.size, media_file.modified))?,
+        };
+        self.thumbnails.get(media_file, &hash)
+    }
+
+    /// Records a client's position and, if it has drifted past the threshold from
+    /// where the group should be, sends it a seek or rate correction.
+    fn handle_position_report(&self, client_id: &str, filename: String, position_ms: u64, at_ms: u64, playing: bool) {
+        let session = self.playback_session.lock().unwrap().clone();
+        let threshold_ms = self.drift_threshold.lock().unwrap().as_millis() as i64;
+        
+        let mut clients = self.clients.lock().unwrap();
+        let Some(client) = clients.get_mut(client_id) else {
+            return;
+        };
+        
+        let estimate = client.clock.estimate();
+        let reported_at_ms = estimate.map_or(at_ms, |estimate| estimate.to_server_time(at_ms));
+        let session = session.filter(|session| session.filename == filename);
+        let drift_ms = session
+            .as_ref()
+            .filter(|session| playing && session.paused_at_position_ms.is_none())
+            .and_then(|session| session.expected_position_at(reported_at_ms))
+            .map(|expected_ms| position_ms as i64 - expected_ms as i64);
+        let rate = client
+            .playback
+            .as_ref()
+            .filter(|playback| playback.filename == filename)
+            .map_or(1.0, |playback| playback.rate);
+        
+        let correction = drift_ms.and_then(|drift| {
+            drift_correction(drift, threshold_ms, rate, || {
+                // Aim for where the group will be when the seek arrives
+                let one_way_ms = estimate.map_or(0, |estimate| estimate.round_trip_ms / 2);
+                session.as_ref()?.expected_position_at(clock::now_millis() + one_way_ms)
+            })
+        });
+        
+        let new_rate = match correction {
+            Some(Correction::Rate { rate }) => rate,
+            _ => rate,
+        };
+        client.playback = Some(ClientPlayback {
+            filename: filename.clone(),
+            position_ms,
+            reported_at_ms,
+            playing,
+            drift_ms,
+            rate: new_rate,
+        });
+        
+        if let Some(correction) = correction {
+            client.connection.send(Message::SyncCorrection {
+                filename,
+                correction,
+            });
+            drop(clients);
+            self.log_status(&format!("Client {} drifted {} ms, sent {:?}", client_id, drift_ms.unwrap_or(0), correction));
         }
     }
 
-    fn broadcast_to_others(
-        clients: &Arc<Mutex<HashMap<String, Arc<Mutex<TcpStream>>>>>,
-        sender_stream: &Arc<Mutex<TcpStream>>,
-        message: &Message,
-    ) {
-        let clients = clients.lock().unwrap();
-        let sender_addr = sender_stream.lock().unwrap().peer_addr().ok();
+    /// Streams a file as `MediaStart`, one `MediaChunk` per `MEDIA_CHUNK_SIZE`
+    /// bytes, then `MediaEnd`. `range` is the offset, length and hash of an
+    /// interrupted download to finish; it is only honoured while the file still
+    /// has that hash, and otherwise the whole file is sent, as for `None`.
+    /// Other messages for the client can be queued between chunks. Chunks are read
+    /// from disk as the client's writer catches up, so memory use doesn't grow with
+    /// file size.
+    async fn send_media(&self, connection: &Connection, media_file: &MediaFile, timestamp: u64, range: Option<(u64, u64, String)>) -> Result<(), Box<dyn std::error::Error>> {
+        let opened = media_file.clone();
+        let server = self.clone();
+        let (mut file, size, modified, hash) = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            let file = fs::File::open(&opened.path)?;
+            // The file may have changed since it was scanned; announce what will actually be sent
+            let metadata = file.metadata()?;
+            let size = metadata.len();
+            let modified = metadata.modified().ok();
+            // Hashed here if the background hasher hasn't got to it yet
+            let hash = match opened.hash.clone() {
+                Some(hash) if (size, modified) == (opened.size, opened.modified) => hash,
+                _ => server.content_hash(&opened.path, (size, modified))?,
+            };
+            Ok((file, size, modified, hash))
+        })
+        .await??;
+        // A range only continues the version of the file it was asked for;
+        // checked here, so a changed file isn't sent in part only for the
+        // client to refuse it
+        let (offset, len) = match range {
+            Some((offset, len, expected)) if expected == hash && offset <= size => (offset, len),
+            Some(_) => {
+                self.log_status(&format!("{} changed since the client's earlier download, sending all of it", media_file.filename));
+                (0, u64::MAX)
+            }
+            None => (0, u64::MAX),
+        };
+        let end = offset.saturating_add(len).min(size);
         
-        for (_client_id, client_stream) in clients.iter() {
-            if let Ok(client_addr) = client_stream.lock().unwrap().peer_addr() {
-                if Some(client_addr) != sender_addr {
-                    Self::send_message(client_stream, message);
-                }
+        let closed = || format!("connection to {} closed", connection.address());
+        if !connection.deliver(Message::MediaStart {
+            filename: media_file.filename.clone(),
+            size,
+            media_type: media_file.media_type.clone(),
+            hash,
+            offset,
+            timestamp,
+        }).await {
+            return Err(closed().into());
+        }
+        
+        let mut offset = offset;
+        while offset < end {
+            let len = (end - offset).min(MEDIA_CHUNK_SIZE as u64) as usize;
+            let server = self.clone();
+            let path = media_file.path.clone();
+            let (returned, chunk) = tokio::task::spawn_blocking(move || {
+                let chunk = server.read_chunk(&mut file, &path, modified, offset, len);
+                (file, chunk)
+            })
+            .await?;
+            file = returned;
+            let chunk = Message::MediaChunk {
+                filename: media_file.filename.clone(),
+                offset,
+                data: chunk?.to_vec(),
+            };
+            if !connection.send_chunk(chunk).await {
+                return Err(closed().into());
             }
+            offset += len as u64;
+        }
+        
+        if !connection.deliver(Message::MediaEnd {
+            filename: media_file.filename.clone(),
+        }).await {
+            return Err(closed().into());
         }
+        Ok(())
     }
 
-    pub fn play_media(&self, filename: &str) {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
+    fn read_chunk(&self, file: &mut fs::File, path: &Path, modified: Option<SystemTime>, offset: u64, len: usize) -> std::io::Result<Arc<Vec<u8>>> {
+        if let Some(chunk) = self.chunk_cache.lock().unwrap().get(path, modified, offset) {
+            if chunk.len() == len {
+                return Ok(chunk);
+            }
+        }
+        
+        let mut chunk = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut chunk)?;
+        let chunk = Arc::new(chunk);
+        self.chunk_cache
+            .lock()
             .unwrap()
-            .as_secs();
-            
-        let command = Message::PlayCommand {
+            .insert(path.to_path_buf(), modified, offset, Arc::clone(&chunk));
+        Ok(chunk)
+    }
+
+    /// Starts synchronized playback of `filename` on the host and every joined client.
+    ///
+    /// Clients are first asked to get the file locally. Once all of them report
+    /// `MediaReady` (or the ready timeout passes), a start instant `play_lead_time`
+    /// in the future is fixed and sent to each client converted to its own clock.
+    pub fn play_media(&self, filename: &str) -> Result<(), String> {
+        if !self.media_files.lock().unwrap().contains_key(filename) {
+            return Err(format!("Media file '{}' not found", filename));
+        }
+        let runtime = self.runtime().ok_or("Server is not running")?;
+        
+        // Register the pending play before asking, so no MediaReady can arrive unseen
+        let clients = self.clients.lock().unwrap();
+        *self.pending_play.lock().unwrap() = Some(PendingPlay {
             filename: filename.to_string(),
-            timestamp,
+            waiting_for: clients.keys().cloned().collect(),
+            ready: Vec::new(),
+        });
+        
+        for client in clients.values() {
+            client.connection.send(Message::PrepareMedia { filename: filename.to_string() });
+        }
+        self.log_status(&format!("Preparing {} on {} client(s)", filename, clients.len()));
+        drop(clients);
+        
+        // Replaces whatever earlier play was still waiting or about to start the host
+        let server = self.clone();
+        let filename = filename.to_string();
+        let task = runtime.spawn(async move { server.commit_play_when_ready(filename).await });
+        if let Some(previous) = self.play_task.lock().unwrap().replace(task.abort_handle()) {
+            previous.abort();
+        }
+        
+        Ok(())
+    }
+
+    async fn commit_play_when_ready(self, filename: String) {
+        let deadline = Instant::now() + *self.ready_timeout.lock().unwrap();
+        let lead_time = *self.play_lead_time.lock().unwrap();
+        let (pending, start_at_ms) = loop {
+            {
+                // The session is locked before the pending play is taken and only let
+                // go once every PlayCommand is queued, so stop_media either cancels the
+                // pending play first or finds the session and sends Stop after them
+                let mut session = self.playback_session.lock().unwrap();
+                let ready = {
+                    let mut pending = self.pending_play.lock().unwrap();
+                    match pending.as_ref() {
+                        // Superseded by a newer play_media call, or stopped
+                        Some(current) if current.filename != filename => return,
+                        None => return,
+                        Some(current) if current.waiting_for.is_empty() || Instant::now() >= deadline => pending.take(),
+                        Some(_) => None,
+                    }
+                };
+                if let Some(pending) = ready {
+                    let start_at_ms = clock::now_millis() + lead_time.as_millis() as u64;
+                    *session = Some(PlaybackSession {
+                        filename: filename.clone(),
+                        start_at_ms,
+                        scheduled_at_ms: start_at_ms,
+                        paused_at_position_ms: None,
+                    });
+                    self.send_play_commands(&filename, &pending.ready, start_at_ms);
+                    // Set under the session lock, so a stop_media that follows clears them
+                    *self.current_media.lock().unwrap() = Some(filename.clone());
+                    *self.is_playing.lock().unwrap() = true;
+                    break (pending, start_at_ms);
+                }
+            }
+            // Woken by MediaReady or a client leaving; the deadline is checked on the next pass
+            let _ = tokio::time::timeout_at(deadline.into(), self.ready_changed.notified()).await;
         };
         
-        // Broadcast to all connected clients
+        if !pending.waiting_for.is_empty() {
+            let mut missing: Vec<&String> = pending.waiting_for.iter().collect();
+            missing.sort();
+            self.log_status(&format!("Started {} without clients that were not ready: {:?}", filename, missing));
+        }
+        
+        self.log_status(&format!("Playing {} on {} client(s) in {} ms", filename, pending.ready.len(), lead_time.as_millis()));
+        
+        if *self.host_playback.lock().unwrap() == HostPlayback::Off {
+            return;
+        }
+        
+        // Play on the host at the same instant as the clients
+        let Some(media_file) = self.media_files.lock().unwrap().get(&filename).cloned() else { return };
+        let server = self.clone();
+        let loaded = tokio::task::spawn_blocking(move || server.load_media_on_host(&media_file).map_err(|e| e.to_string())).await;
+        match loaded {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("Error playing media on host: {}", e);
+                return;
+            }
+            Err(_) => return,
+        }
+        clock::wait_until(start_at_ms).await;
+        let _ = tokio::task::spawn_blocking(move || self.start_host_play(&filename, start_at_ms)).await;
+    }
+
+    // Starts the host player on the group play scheduled at `start_at_ms`, then
+    // keeps it in step if it is a participant
+    fn start_host_play(&self, filename: &str, start_at_ms: u64) {
+        // Start the host only if this play wasn't stopped, replaced or paused
+        // during the lead time; the session stays locked so a pause or stop
+        // that comes next acts on a started player
+        let started = {
+            let session = self.playback_session.lock().unwrap();
+            let Some(session) = session.as_ref() else { return };
+            if session.filename != filename || session.scheduled_at_ms != start_at_ms {
+                return;
+            }
+            session.paused_at_position_ms.is_none() && match self.host_player.lock().unwrap().play_at(start_at_ms) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Error playing media on host: {}", e);
+                    return;
+                }
+            }
+        };
+        if started {
+            self.log_status(&format!("Started playing {} on HOST", filename));
+        }
+        
+        if *self.host_playback.lock().unwrap() == HostPlayback::Participant {
+            self.correct_host_drift(filename, start_at_ms);
+        }
+    }
+
+    // Sends each ready client a PlayCommand for `start_at_ms` on the server's
+    // clock, converted to the client's own
+    fn send_play_commands(&self, filename: &str, ready: &[String], start_at_ms: u64) {
         let clients = self.clients.lock().unwrap();
-        for (_client_id, client_stream) in clients.iter() {
-            Self::send_message(client_stream, &command);
+        for client_id in ready {
+            if let Some(client) = clients.get(client_id) {
+                let client_start_ms = client
+                    .clock
+                    .estimate()
+                    .map_or(start_at_ms, |estimate| estimate.to_client_time(start_at_ms));
+                client.connection.send(Message::PlayCommand {
+                    filename: filename.to_string(),
+                    start_at_ms: client_start_ms,
+                });
+            }
+        }
+    }
+
+    // Keeps the host player in line with the group play scheduled at
+    // `scheduled_at_ms`, the way client position reports are handled, until
+    // that play ends
+    fn correct_host_drift(&self, filename: &str, scheduled_at_ms: u64) {
+        let mut rate = 1.0;
+        loop {
+            thread::sleep(POSITION_REPORT_INTERVAL);
+            let Some(session) = self.playback_session.lock().unwrap().clone() else { break };
+            if session.filename != filename || session.scheduled_at_ms != scheduled_at_ms {
+                break;
+            }
+            if session.paused_at_position_ms.is_some() {
+                continue;
+            }
+            
+            let mut player = self.host_player.lock().unwrap();
+            let Some(position_ms) = player.position() else { continue };
+            let now_ms = clock::now_millis();
+            let Some(expected_ms) = session.expected_position_at(now_ms) else { continue };
+            let drift_ms = position_ms as i64 - expected_ms as i64;
+            let threshold_ms = self.drift_threshold.lock().unwrap().as_millis() as i64;
+            
+            let Some(correction) = drift_correction(drift_ms, threshold_ms, rate, || Some(expected_ms)) else { continue };
+            let applied = match correction {
+                Correction::Seek { position_ms } => player.seek(position_ms),
+                Correction::Rate { rate: new_rate } => {
+                    rate = new_rate;
+                    player.set_rate(new_rate)
+                }
+            };
+            drop(player);
+            match applied {
+                Ok(()) => self.log_status(&format!("Host drifted {} ms, applied {:?}", drift_ms, correction)),
+                Err(e) => eprintln!("Host player failed to apply sync correction: {}", e),
+            }
+        }
+    }
+
+    /// Pauses the group play everywhere at the group's current position.
+    pub fn pause_media(&self) -> Result<u64, String> {
+        let position_ms = {
+            let mut session = self.playback_session.lock().unwrap();
+            let session = session.as_mut().ok_or("Nothing is playing")?;
+            if let Some(position_ms) = session.paused_at_position_ms {
+                return Ok(position_ms);
+            }
+            let position_ms = session.expected_position_at(clock::now_millis()).unwrap_or(0);
+            session.paused_at_position_ms = Some(position_ms);
+            position_ms
+        };
+        if let Some(task) = self.resume_task.lock().unwrap().take() {
+            task.abort();
         }
         
-        *self.current_media.lock().unwrap() = Some(filename.to_string());
-        *self.is_playing.lock().unwrap() = true;
+        self.broadcast(&Message::Pause { position_ms });
+        self.control_host_player("pause", |player| {
+            player.pause()?;
+            player.seek(position_ms)
+        });
+        *self.is_playing.lock().unwrap() = false;
         
-        println!("Playing: {}", filename);
-    }    pub fn pause_media(&self) {
-        let command = Message::PauseCommand;
+        self.log_status(&format!("Media paused at {} ms", position_ms));
+        Ok(position_ms)
+    }
+
+    /// Resumes a paused group play from where it was paused, `play_lead_time` from now.
+    pub fn resume_media(&self) -> Result<(), String> {
+        let runtime = self.runtime().ok_or("Server is not running")?;
+        let resume_at_ms = clock::now_millis() + self.play_lead_time.lock().unwrap().as_millis() as u64;
+        let scheduled_at_ms = {
+            let mut session = self.playback_session.lock().unwrap();
+            let session = session.as_mut().ok_or("Nothing is playing")?;
+            let position_ms = session.paused_at_position_ms.take().ok_or("Media is not paused")?;
+            session.start_at_ms = resume_at_ms.saturating_sub(position_ms);
+            session.scheduled_at_ms
+        };
+        
+        // Resume the host at the same instant, unless this play is paused,
+        // stopped or replaced before then
+        let server = self.clone();
+        let task = runtime.spawn(async move {
+            clock::wait_until(resume_at_ms).await;
+            let _ = tokio::task::spawn_blocking(move || {
+                let session = server.playback_session.lock().unwrap();
+                let current = session
+                    .as_ref()
+                    .is_some_and(|session| session.scheduled_at_ms == scheduled_at_ms && session.paused_at_position_ms.is_none());
+                if current {
+                    server.control_host_player("resume", |player| player.play_at(resume_at_ms));
+                }
+            })
+            .await;
+        });
+        if let Some(previous) = self.resume_task.lock().unwrap().replace(task.abort_handle()) {
+            previous.abort();
+        }
         
-        // Broadcast to all connected clients
         let clients = self.clients.lock().unwrap();
-        for (_client_id, client_stream) in clients.iter() {
-            Self::send_message(client_stream, &command);
+        for client in clients.values() {
+            let at = client
+                .clock
+                .estimate()
+                .map_or(resume_at_ms, |estimate| estimate.to_client_time(resume_at_ms));
+            client.connection.send(Message::Resume { at });
+        }
+        drop(clients);
+        *self.is_playing.lock().unwrap() = true;
+        
+        self.log_status("Media resumed");
+        Ok(())
+    }
+
+    /// Moves the group play to `position_ms`, keeping it paused if it was.
+    pub fn seek_media(&self, position_ms: u64) -> Result<(), String> {
+        {
+            let mut session = self.playback_session.lock().unwrap();
+            let session = session.as_mut().ok_or("Nothing is playing")?;
+            if session.paused_at_position_ms.is_some() {
+                session.paused_at_position_ms = Some(position_ms);
+            } else {
+                session.start_at_ms = clock::now_millis().saturating_sub(position_ms);
+            }
         }
         
+        self.broadcast(&Message::Seek { position_ms });
+        self.control_host_player("seek", |player| player.seek(position_ms));
+        self.log_status(&format!("Seeked to {} ms", position_ms));
+        Ok(())
+    }
+
+    /// Ends the group play on every client, or cancels one still waiting for
+    /// clients to get the file.
+    pub fn stop_media(&self) -> Result<(), String> {
+        // Cleared first, so commit_play_when_ready finds nothing to start
+        let pending = self.pending_play.lock().unwrap().take();
+        for task in [&self.play_task, &self.resume_task] {
+            if let Some(task) = task.lock().unwrap().take() {
+                task.abort();
+            }
+        }
+        let session = self.playback_session.lock().unwrap().take();
+        let filename = match (session, pending) {
+            (Some(session), _) => session.filename,
+            (None, Some(pending)) => pending.filename,
+            (None, None) => return Err("Nothing is playing".to_string()),
+        };
+        
+        self.broadcast(&Message::Stop);
+        self.control_host_player("stop", |player| player.stop());
         *self.is_playing.lock().unwrap() = false;
+        *self.current_media.lock().unwrap() = None;
         
-        println!("Media paused");
+        self.log_status(&format!("Stopped {}", filename));
+        Ok(())
+    }
+
+    fn broadcast(&self, message: &Message) {
+        let clients = self.clients.lock().unwrap();
+        for client in clients.values() {
+            client.connection.send(message.clone());
+        }
     }
 
-    pub fn get_connected_clients(&self) -> Vec<(String, String)> {
+    pub fn get_connected_clients(&self) -> Vec<ConnectedClient> {
         let clients = self.clients.lock().unwrap();
         clients
             .iter()
-            .filter_map(|(client_id, stream)| {
-                if let Ok(addr) = stream.lock().unwrap().peer_addr() {
-                    Some((client_id.clone(), addr.to_string()))
-                } else {
-                    None
-                }
+            .map(|(client_id, client)| ConnectedClient {
+                id: client_id.clone(),
+                address: client.connection.address().to_string(),
+                clock: client.clock.estimate(),
+                playback: client.playback.clone(),
+                queued_messages: client.connection.queued(),
             })
             .collect()
-    }    pub fn disconnect_client(&self, client_id: &str) -> bool {
+    }
+
+    pub fn disconnect_client(&self, client_id: &str) -> bool {
         let mut clients = self.clients.lock().unwrap();
-        if let Some(client_stream) = clients.remove(client_id) {
-            // Try to close the connection gracefully
-            if let Ok(stream) = client_stream.lock() {
-                let _ = stream.shutdown(Shutdown::Both);
-            }
+        if let Some(client) = clients.remove(client_id) {
+            client.connection.disconnect();
             println!("Disconnected client: {}", client_id);
             true
         } else {
@@ -454,10 +1700,99 @@ impl MediaServer {    pub fn new() -> Self {
     }
 }
 
+// A file being reassembled on the client from MediaChunk messages
+struct IncomingTransfer {
+    file: fs::File,
+    path: PathBuf,
+    size: u64,
+    received: u64,
+    media_type: MediaType,
+    // What the file should hash to once complete
+    hash: String,
+    // How much of the file the partial download record last vouched for
+    checkpoint: u64,
+}
+
+impl IncomingTransfer {
+    // Records how far the file has got once the bytes are safely on disk
+    fn save_partial(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()?;
+        PartialDownload {
+            size: self.size,
+            hash: self.hash.clone(),
+            verified: self.received,
+        }
+        .save(&self.path)?;
+        self.checkpoint = self.received;
+        Ok(())
+    }
+}
+
+// A file the client has in its media cache
+#[derive(Clone)]
+struct LocalMedia {
+    path: PathBuf,
+    size: u64,
+    media_type: MediaType,
+}
+
+// The group play currently running on this client
+struct Playback {
+    filename: String,
+    // Scheduled start; tells apart two plays of the same file
+    start_at_ms: u64,
+    paused: bool,
+    // Instant a pending Resume takes effect; cleared by a later Pause
+    resume_at_ms: Option<u64>,
+}
+
+// A thumbnail this connection asked the server for
+struct ThumbnailRequest {
+    // Content hash of the version of the file it was asked for
+    hash: Option<String>,
+    state: ThumbnailState,
+}
+
+enum ThumbnailState {
+    Requested(Instant),
+    Saved(PathBuf),
+    // The server has no picture for this file
+    Missing,
+}
+
 #[derive(Clone)]
 pub struct MediaClient {
     server_addr: String,
     client_id: String,
+    transfers: Arc<Mutex<HashMap<String, IncomingTransfer>>>,
+    // Ranges refused at MediaStart, and transfers given up after a local disk
+    // error; their chunks are dropped quietly until MediaEnd
+    refused_transfers: Arc<Mutex<HashSet<String>>>,
+    cache: Arc<Mutex<MediaCache>>,
+    // Complete downloads being hashed before they go into the cache
+    verifying: Arc<Mutex<HashSet<String>>>,
+    // Files the server asked us to prepare; MediaReady is sent once each is local
+    awaiting_ready: Arc<Mutex<HashSet<String>>>,
+    // A PlayCommand that arrived before its file did
+    scheduled_play: Arc<Mutex<Option<(String, u64)>>>,
+    playback: Arc<Mutex<Option<Playback>>>,
+    player: SharedPlayer,
+    // What the server agreed to in its Welcome
+    capabilities: Arc<Mutex<Vec<Capability>>>,
+    // The server's library, kept current from MediaListChanged
+    media_list: Arc<Mutex<Vec<MediaEntry>>>,
+    media_list_callback: MediaListCallback,
+    // Cached file the player last loaded, kept out of cache eviction
+    loaded_media: Arc<Mutex<Option<PathBuf>>>,
+    // Socket to the server while connected, kept so `disconnect` can close it
+    connection: Arc<Mutex<Option<TcpStream>>>,
+    // The same socket as the message loop writes to it, for requests made from other threads
+    writer: Arc<Mutex<Option<SharedStream>>>,
+    // Set by `disconnect`, so a connection still being made is given up
+    disconnected: Arc<Mutex<bool>>,
+    // Thumbnails for the current connection; deleted when it ends
+    scratch: Arc<Mutex<Option<ScratchDir>>>,
+    thumbnails: Arc<Mutex<HashMap<MediaId, ThumbnailRequest>>>,
 }
 
 impl MediaClient {
@@ -465,62 +1800,307 @@ impl MediaClient {
         Self {
             server_addr,
             client_id,
+            transfers: Arc::new(Mutex::new(HashMap::new())),
+            refused_transfers: Arc::new(Mutex::new(HashSet::new())),
+            cache: Arc::new(Mutex::new(MediaCache::new(media_cache::default_dir(), DEFAULT_MEDIA_CACHE_SIZE))),
+            verifying: Arc::new(Mutex::new(HashSet::new())),
+            awaiting_ready: Arc::new(Mutex::new(HashSet::new())),
+            scheduled_play: Arc::new(Mutex::new(None)),
+            playback: Arc::new(Mutex::new(None)),
+            player: player::shared(PlayerBackend::Auto.create().unwrap()),
+            capabilities: Arc::new(Mutex::new(Vec::new())),
+            media_list: Arc::new(Mutex::new(Vec::new())),
+            media_list_callback: Arc::new(Mutex::new(None)),
+            loaded_media: Arc::new(Mutex::new(None)),
+            connection: Arc::new(Mutex::new(None)),
+            writer: Arc::new(Mutex::new(None)),
+            disconnected: Arc::new(Mutex::new(false)),
+            thumbnails: Arc::new(Mutex::new(HashMap::new())),
+            scratch: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Player the received media is played on.
+    pub fn set_player(&self, player: Box<dyn Player>) {
+        *self.player.lock().unwrap() = player;
+    }
+
+    /// Directory received media is kept in, and how many bytes of it to keep
+    /// before the least recently used files are deleted.
+    pub fn set_media_cache(&self, dir: PathBuf, max_bytes: u64) {
+        *self.cache.lock().unwrap() = MediaCache::new(dir, max_bytes);
+    }
+
+    /// Called with the whole media list when the server first sends it and
+    /// again after every change to it.
+    pub fn set_media_list_callback<F>(&self, callback: F)
+    where
+        F: Fn(&[MediaEntry]) + Send + Sync + 'static,
+    {
+        *self.media_list_callback.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    // Hands the current media list to the callback, if one is set
+    fn media_list_updated(&self) {
+        let media_list = self.media_list.lock().unwrap();
+        if let Some(ref callback) = *self.media_list_callback.lock().unwrap() {
+            callback(&media_list);
+        }
+    }
+
+    /// JPEG thumbnail of a file in the server's list, or `None` if it has no
+    /// picture or none arrived within `timeout`. The first call for each version
+    /// of a file asks the server; later ones are answered from the scratch directory.
+    pub fn thumbnail(&self, id: &MediaId, timeout: Duration) -> Option<Vec<u8>> {
+        if !self.capabilities.lock().unwrap().contains(&Capability::Thumbnails) {
+            return None;
+        }
+        let hash = self.media_list.lock().unwrap().iter().find(|entry| entry.id == *id)?.hash.clone();
+        let deadline = Instant::now() + timeout;
+        
+        loop {
+            let ask = {
+                let mut thumbnails = self.thumbnails.lock().unwrap();
+                let ask = match thumbnails.get(id) {
+                    Some(request) if request.hash == hash => match &request.state {
+                        ThumbnailState::Saved(path) => return fs::read(path).ok(),
+                        ThumbnailState::Missing => return None,
+                        ThumbnailState::Requested(at) => at.elapsed() >= THUMBNAIL_RETRY_INTERVAL,
+                    },
+                    _ => true,
+                };
+                if ask {
+                    let state = ThumbnailState::Requested(Instant::now());
+                    thumbnails.insert(id.clone(), ThumbnailRequest { hash: hash.clone(), state });
+                }
+                ask
+            };
+            if ask {
+                let writer = self.writer.lock().unwrap().clone()?;
+                self.send_message_arc(&writer, &Message::RequestThumbnail { id: id.clone() }).ok()?;
+            }
+            
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(THUMBNAIL_POLL_INTERVAL);
         }
     }
 
     pub fn connect(&self) -> Result<(), Box<dyn std::error::Error>> {
         let stream = TcpStream::connect(&self.server_addr)?;
+        {
+            let mut connection = self.connection.lock().unwrap();
+            if *self.disconnected.lock().unwrap() {
+                return Err("disconnected before the connection was made".into());
+            }
+            *connection = Some(stream.try_clone()?);
+        }
         println!("Connected to media server at {}", self.server_addr);
+        println!("Media cache: {}", self.cache.lock().unwrap().dir().display());
+        
+        let scratch = match ScratchDir::create(&format!("client-{}", self.client_id)) {
+            Ok(scratch) => scratch,
+            Err(e) => {
+                self.connection.lock().unwrap().take();
+                return Err(e.into());
+            }
+        };
+        println!("Scratch directory: {}", scratch.path().display());
+        *self.scratch.lock().unwrap() = Some(scratch);
 
         // Send join message
         let join_msg = Message::Join {
             client_id: self.client_id.clone(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities: protocol::local_capabilities(),
         };
-        self.send_message(&stream, &join_msg)?;
-
+        
         // Handle server messages
-        self.handle_server_messages(stream)?;
+        let result = self
+            .send_message(&stream, &join_msg)
+            .and_then(|()| self.handle_server_messages(stream));
         
-        Ok(())
+        // Dropping the scratch directory deletes it
+        self.connection.lock().unwrap().take();
+        self.scratch.lock().unwrap().take();
+        result
+    }
+
+    /// Closes the connection to the server, making `connect` return. A
+    /// `connect` still waiting for the server gives up once it gets through.
+    pub fn disconnect(&self) {
+        let connection = self.connection.lock().unwrap();
+        *self.disconnected.lock().unwrap() = true;
+        if let Some(stream) = connection.as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
     }
 
     fn handle_server_messages(&self, stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
         let mut reader = BufReader::new(stream.try_clone()?);
         let stream = Arc::new(Mutex::new(stream));
+        *self.writer.lock().unwrap() = Some(Arc::clone(&stream));
         
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(0) => {
+        let result = loop {
+            match protocol::read_message(&mut reader) {
+                Ok(Some(message)) => {
+                    if let Err(e) = self.process_server_message(message, &stream) {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => {
                     println!("Server disconnected");
-                    break;
+                    break Ok(());
                 }
-                Ok(_) => {
-                    line = line.trim().to_string();
-                    if line.is_empty() {
-                        continue;
-                    }
-                    
-                    if let Ok(message) = serde_json::from_str::<Message>(&line) {
-                        self.process_server_message(message, &stream)?;
-                    } else {
-                        eprintln!("Failed to parse server message: {}", line);
-                    }
+                Err(e) if e.is_recoverable() => {
+                    eprintln!("Rejected frame from server: {}", e);
                 }
                 Err(e) => {
                     eprintln!("Error reading from server: {}", e);
-                    break;
+                    break Ok(());
                 }
             }
+        };
+        
+        // Whatever was still arriving can be picked up by the next connection
+        for (filename, mut transfer) in self.transfers.lock().unwrap().drain() {
+            match transfer.save_partial() {
+                Ok(()) => println!("Kept {} of {} bytes of {} to resume later", transfer.received, transfer.size, filename),
+                Err(e) => eprintln!("Could not record partial download of {}: {}", filename, e),
+            }
+        }
+        self.refused_transfers.lock().unwrap().clear();
+        self.writer.lock().unwrap().take();
+        self.thumbnails.lock().unwrap().clear();
+        
+        result
+    }
+
+    // The cached copy of a file in the server's list, if we have its current version
+    fn local_copy(&self, filename: &str) -> Option<LocalMedia> {
+        let entry = self.media_list.lock().unwrap().iter().find(|entry| entry.path == filename).cloned()?;
+        let path = self.cache.lock().unwrap().get(entry.hash.as_deref()?, &entry.media_type)?;
+        Some(LocalMedia {
+            path,
+            size: entry.size,
+            media_type: entry.media_type,
+        })
+    }
+
+    // Asks for a file, or only for its missing part when an earlier download of
+    // the same version of it was interrupted. Nothing is sent if it is already
+    // cached, or if the server never listed it, since files are asked for by id.
+    // Until the server has hashed the file neither can be checked, and the
+    // whole file is asked for.
+    fn request_media(&self, stream: &Arc<Mutex<TcpStream>>, filename: String) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(entry) = self.media_list.lock().unwrap().iter().find(|entry| entry.path == filename).cloned() else {
+            eprintln!("Cannot request {}: it is not in the server's media list", filename);
+            return Ok(());
+        };
+        
+        if let Some(hash) = &entry.hash {
+            let cache = self.cache.lock().unwrap();
+            if cache.get(hash, &entry.media_type).is_some() {
+                println!("{} is already in the media cache", filename);
+                return Ok(());
+            }
+            
+            let download = cache.download_path(hash, &entry.media_type, &self.client_id)?;
+            let partial = PartialDownload::load(&download)
+                .filter(|partial| partial.hash == *hash && partial.size == entry.size && partial.verified <= partial.size);
+            if let Some(partial) = partial {
+                println!("Resuming {} from byte {} of {}", filename, partial.verified, partial.size);
+                let request = Message::RequestMediaRange {
+                    id: entry.id,
+                    offset: partial.verified,
+                    len: partial.size - partial.verified,
+                    hash: hash.clone(),
+                };
+                return self.send_message_arc(stream, &request);
+            }
+        }
+        
+        self.send_message_arc(stream, &Message::RequestMedia { id: entry.id })
+    }
+
+    // Checks a complete download against the hash the server sent and moves it
+    // into the media cache, or deletes it and tells the server if it doesn't match
+    fn verify_download(&self, filename: &str, transfer: IncomingTransfer, stream: &Arc<Mutex<TcpStream>>) {
+        drop(transfer.file);
+        // Hash what actually landed on disk, not what came off the wire
+        let actual_hash = match content_hash::hash_file(&transfer.path) {
+            Ok(actual_hash) => actual_hash,
+            Err(e) => {
+                eprintln!("Could not check {}: {}, dropping it", filename, e);
+                let _ = fs::remove_file(&transfer.path);
+                return;
+            }
+        };
+        if actual_hash != transfer.hash {
+            eprintln!("Integrity check failed for {}: expected SHA-256 {}, got {}",
+                      filename, transfer.hash, actual_hash);
+            let _ = fs::remove_file(&transfer.path);
+            let corrupt = Message::MediaCorrupt {
+                filename: filename.to_string(),
+                expected_hash: transfer.hash,
+                actual_hash,
+            };
+            if let Err(e) = self.send_message_arc(stream, &corrupt) {
+                eprintln!("Could not report corrupt transfer of {}: {}", filename, e);
+            }
+            return;
+        }
+        
+        println!("Received media: {} ({} bytes, verified)", filename, transfer.size);
+        let mut in_use: Vec<PathBuf> = self.transfers.lock().unwrap().values().map(|other| other.path.clone()).collect();
+        in_use.extend(self.loaded_media.lock().unwrap().clone());
+        if let Err(e) = self.cache.lock().unwrap().insert(&transfer.path, &transfer.hash, &transfer.media_type, &in_use) {
+            eprintln!("Could not add {} to the media cache: {}", filename, e);
+            return;
+        }
+        
+        if let Err(e) = self.media_arrived(filename, stream) {
+            eprintln!("Could not report {} as ready: {}", filename, e);
+        }
+    }
+
+    // Whether a file is on its way: being received, or received and being checked
+    fn receiving(&self, filename: &str) -> bool {
+        self.transfers.lock().unwrap().contains_key(filename) || self.verifying.lock().unwrap().contains(filename)
+    }
+
+    // Gives up a transfer after a local disk error without dropping the
+    // connection. What is already on disk is kept to resume from, if it can
+    // still be recorded; the rest of the file's chunks are ignored
+    fn abandon_transfer(&self, filename: &str, mut transfer: IncomingTransfer, error: std::io::Error) {
+        eprintln!("Could not write {} to {}: {}, dropping the transfer", filename, transfer.path.display(), error);
+        if let Err(e) = transfer.save_partial() {
+            eprintln!("Could not record partial download of {}: {}", filename, e);
+        }
+        self.refused_transfers.lock().unwrap().insert(filename.to_string());
+    }
+
+    // Tells the server a file it is waiting for is here, and starts a play that
+    // was only waiting for the file
+    fn media_arrived(&self, filename: &str, stream: &Arc<Mutex<TcpStream>>) -> Result<(), Box<dyn std::error::Error>> {
+        if self.awaiting_ready.lock().unwrap().remove(filename) {
+            self.send_message_arc(stream, &Message::MediaReady { filename: filename.to_string() })?;
         }
         
+        let scheduled = self.scheduled_play.lock().unwrap().take_if(|(name, _)| name == filename);
+        if let Some((_, start_at_ms)) = scheduled {
+            self.start_playback_at(filename, start_at_ms, stream);
+        }
         Ok(())
     }
 
     fn process_server_message(&self, message: Message, stream: &Arc<Mutex<TcpStream>>) -> Result<(), Box<dyn std::error::Error>> {
         match message {
-            Message::Welcome { client_id } => {
-                println!("Welcome! Client ID: {}", client_id);
+            Message::Welcome { client_id, protocol_version, capabilities } => {
+                println!("Welcome! Client ID: {} (server protocol v{}, capabilities: {:?})", 
+                         client_id, protocol_version, capabilities);
+                *self.capabilities.lock().unwrap() = capabilities;
                 
                 // Request media list
                 let request = Message::RequestMediaList;
@@ -528,161 +2108,468 @@ impl MediaClient {
             }
             
             Message::MediaList { files } => {
+                let files = safe_entries(files);
                 println!("Available media files:");
                 for (i, file) in files.iter().enumerate() {
-                    println!("  {}. {}", i + 1, file);
+                    println!("  {}. {}", i + 1, describe_entry(file));
                 }
                 
-                // Auto-request first media file for demo
-                if !files.is_empty() {
-                    println!("Requesting: {}", files[0]);
-                    let request = Message::RequestMedia {
-                        filename: files[0].clone(),
-                    };
-                    self.send_message_arc(stream, &request)?;
+                *self.media_list.lock().unwrap() = files;
+                self.media_list_updated();
+                
+                // A PrepareMedia or PlayCommand that came before the list couldn't be
+                // acted on yet, since files are requested by their listed id
+                let mut waiting: Vec<String> = self.awaiting_ready.lock().unwrap().iter().cloned().collect();
+                waiting.extend(self.scheduled_play.lock().unwrap().as_ref().map(|(name, _)| name.clone()));
+                waiting.sort();
+                waiting.dedup();
+                for filename in &waiting {
+                    if self.local_copy(filename).is_some() {
+                        self.media_arrived(filename, stream)?;
+                    } else {
+                        self.request_media(stream, filename.clone())?;
+                    }
                 }
             }
             
-            Message::MediaData { filename, data, media_type, timestamp } => {
-                println!("Received media: {} ({} bytes, type: {}, timestamp: {})", 
-                         filename, data.len(), media_type, timestamp);
+            Message::MediaListChanged { added, removed, updated } => {
+                let (added, updated) = (safe_entries(added), safe_entries(updated));
+                for file in &added {
+                    println!("  + {}", describe_entry(file));
+                }
+                for file in &removed {
+                    println!("  - {}", file);
+                }
+                for file in &updated {
+                    println!("  * {}", describe_entry(file));
+                }
                 
-                // Play media on CLIENT device
-                self.handle_media_playback(&filename, &data, &media_type, timestamp)?;
+                // Changed files come with a new hash, so a cached copy of the old version is never used for them
+                {
+                    let mut media_list = self.media_list.lock().unwrap();
+                    media_list.retain(|entry| {
+                        !removed.contains(&entry.path) && !updated.iter().any(|changed| changed.path == entry.path)
+                    });
+                    media_list.extend(added);
+                    media_list.extend(updated);
+                    media_list.sort_by(|a, b| a.path.cmp(&b.path));
+                }
+                self.media_list_updated();
             }
             
-            Message::PlayCommand { filename, timestamp } => {
-                println!("Play command received for: {} at timestamp {}", filename, timestamp);
-                // This client should start synchronized playback
-                // (Implementation depends on your sync requirements)
+            Message::MediaStart { filename, size, media_type, hash, offset, timestamp } => {
+                println!("Receiving media: {} ({} bytes, type: {}, timestamp: {})", 
+                         filename, size, media_type, timestamp);
+                
+                if let Err(e) = library::validate_key(&filename) {
+                    eprintln!("Refusing transfer: {}", e);
+                    return Ok(());
+                }
+                let path = match self.cache.lock().unwrap().download_path(&hash, &media_type, &self.client_id) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        eprintln!("Cannot receive {}: {}", filename, e);
+                        return Ok(());
+                    }
+                };
+                let opened = if offset == 0 {
+                    PartialDownload::discard(&path);
+                    fs::File::create(&path).and_then(|file| file.set_len(size).map(|()| file))
+                } else {
+                    // A range only continues the exact download it was asked for. Anything
+                    // else, e.g. the file changed on the server meanwhile, starts over
+                    let expected = PartialDownload { size, hash: hash.clone(), verified: offset };
+                    if PartialDownload::load(&path) != Some(expected) {
+                        eprintln!("No partial download of {} to continue at byte {}, asking for the whole file", filename, offset);
+                        PartialDownload::discard(&path);
+                        self.refused_transfers.lock().unwrap().insert(filename.clone());
+                        let id = self.media_list.lock().unwrap().iter().find(|entry| entry.path == filename).map(|entry| entry.id.clone());
+                        if let Some(id) = id {
+                            self.send_message_arc(stream, &Message::RequestMedia { id })?;
+                        }
+                        return Ok(());
+                    }
+                    fs::OpenOptions::new().write(true).open(&path)
+                };
+                let file = match opened {
+                    Ok(file) => file,
+                    Err(e) => {
+                        eprintln!("Cannot write {} to {}: {}", filename, path.display(), e);
+                        self.refused_transfers.lock().unwrap().insert(filename);
+                        return Ok(());
+                    }
+                };
+                self.transfers.lock().unwrap().insert(filename, IncomingTransfer {
+                    file,
+                    path,
+                    size,
+                    received: offset,
+                    media_type,
+                    hash,
+                    checkpoint: offset,
+                });
             }
             
-            Message::PauseCommand => {
-                println!("Pause command received");
-                // Implement pause functionality
+            Message::MediaChunk { filename, offset, data } => {
+                let mut transfers = self.transfers.lock().unwrap();
+                match transfers.get_mut(&filename) {
+                    // Chunks arrive in order, so everything before `received` is in place
+                    Some(transfer) if offset == transfer.received && offset + data.len() as u64 <= transfer.size => {
+                        let written = transfer
+                            .file
+                            .seek(SeekFrom::Start(offset))
+                            .and_then(|_| transfer.file.write_all(&data));
+                        if let Err(e) = written {
+                            let transfer = transfers.remove(&filename).unwrap();
+                            self.abandon_transfer(&filename, transfer, e);
+                            return Ok(());
+                        }
+                        transfer.received += data.len() as u64;
+                        if transfer.received - transfer.checkpoint >= PARTIAL_CHECKPOINT_INTERVAL {
+                            if let Err(e) = transfer.save_partial() {
+                                let transfer = transfers.remove(&filename).unwrap();
+                                self.abandon_transfer(&filename, transfer, e);
+                            }
+                        }
+                    }
+                    Some(transfer) => {
+                        eprintln!("Chunk for {} at offset {} doesn't continue from byte {} of {}, dropping transfer",
+                                  filename, offset, transfer.received, transfer.size);
+                        transfers.remove(&filename);
+                    }
+                    None => {
+                        if !self.refused_transfers.lock().unwrap().contains(&filename) {
+                            eprintln!("Received chunk for unknown transfer: {}", filename);
+                        }
+                    }
+                }
             }
             
-            Message::Error { message } => {
-                eprintln!("Server error: {}", message);
+            Message::Thumbnail { id, data } => {
+                let Some(entry) = self.media_list.lock().unwrap().iter().find(|entry| entry.id == id).cloned() else {
+                    eprintln!("Received thumbnail for unknown media id {}", id);
+                    return Ok(());
+                };
+                let state = match data {
+                    Some(data) => {
+                        // Named by id, since two paths can sanitize to the same file name
+                        let Some(path) = self.scratch.lock().unwrap().as_ref().map(|scratch| scratch.file(&format!("{}.jpg", id))) else {
+                            return Ok(());
+                        };
+                        if let Err(e) = fs::write(&path, &data) {
+                            eprintln!("Could not save thumbnail for {} to {}: {}", entry.path, path.display(), e);
+                            return Ok(());
+                        }
+                        println!("Saved thumbnail for {} to {} ({} bytes)", entry.path, path.display(), data.len());
+                        ThumbnailState::Saved(path)
+                    }
+                    None => ThumbnailState::Missing,
+                };
+                self.thumbnails.lock().unwrap().insert(id, ThumbnailRequest { hash: entry.hash, state });
             }
             
-            _ => {}
-        }
-        
-        Ok(())
-    }
-
-    // This function now only plays on the CLIENT machine
-    fn handle_media_playback(&self, filename: &str, data: &[u8], media_type: &str, _timestamp: u64) -> Result<(), Box<dyn std::error::Error>> {
-        // Create a temporary file for the media on CLIENT
-        let temp_file = format!("client_temp_{}_{}", self.client_id, filename);
-        fs::write(&temp_file, data)?;
-        
-        println!("Playing media on CLIENT {}: {} ({} bytes)", self.client_id, filename, data.len());
-        
-        match media_type {
-            "video" => {
-                println!("Playing video on CLIENT: {} ({} bytes)", filename, data.len());
-                
-                #[cfg(target_os = "windows")]
-                {
-                    std::process::Command::new("cmd")
-                        .args(&["/C", "start", "", &temp_file])
-                        .spawn()?;
-                }
-                
-                #[cfg(target_os = "macos")]
-                {
-                    std::process::Command::new("open")
-                        .arg(&temp_file)
-                        .spawn()?;
+            Message::MediaEnd { filename } => {
+                if self.refused_transfers.lock().unwrap().remove(&filename) {
+                    return Ok(());
                 }
-                
-                #[cfg(target_os = "linux")]
-                {
-                    std::process::Command::new("xdg-open")
-                        .arg(&temp_file)
-                        .spawn()?;
+                let transfer = self.transfers.lock().unwrap().remove(&filename);
+                if let Some(mut transfer) = transfer {
+                    let kept = match transfer.file.flush() {
+                        Err(e) => {
+                            eprintln!("Could not write {} to {}: {}, keeping what is on disk to resume",
+                                      filename, transfer.path.display(), e);
+                            true
+                        }
+                        Ok(()) if transfer.received != transfer.size => {
+                            eprintln!("Incomplete transfer for {}: received {} of {} bytes, keeping them to resume", 
+                                      filename, transfer.received, transfer.size);
+                            true
+                        }
+                        Ok(()) => false,
+                    };
+                    if kept {
+                        if let Err(e) = transfer.save_partial() {
+                            eprintln!("Could not record partial download of {}: {}", filename, e);
+                        }
+                        return Ok(());
+                    }
+                    
+                    PartialDownload::discard(&transfer.path);
+                    
+                    // Hashing a large file takes a while; meanwhile clock pings and
+                    // play commands must still be read
+                    self.verifying.lock().unwrap().insert(filename.clone());
+                    let client = self.clone();
+                    let stream = Arc::clone(stream);
+                    thread::spawn(move || {
+                        client.verify_download(&filename, transfer, &stream);
+                        client.verifying.lock().unwrap().remove(&filename);
+                    });
                 }
             }
             
-            "audio" => {
-                println!("Playing audio on CLIENT: {} ({} bytes)", filename, data.len());
-                
-                #[cfg(target_os = "windows")]
-                {
-                    std::process::Command::new("cmd")
-                        .args(&["/C", "start", "", &temp_file])
-                        .spawn()?;
+            Message::PrepareMedia { filename } => {
+                if self.local_copy(&filename).is_some() {
+                    self.send_message_arc(stream, &Message::MediaReady { filename })?;
+                } else {
+                    self.awaiting_ready.lock().unwrap().insert(filename.clone());
+                    if !self.receiving(&filename) {
+                        self.request_media(stream, filename)?;
+                    }
                 }
+            }
+            
+            Message::PlayCommand { filename, start_at_ms } => {
+                let delay_ms = start_at_ms as i64 - clock::now_millis() as i64;
+                println!("Play command received for: {} (starts in {} ms)", filename, delay_ms);
                 
-                #[cfg(target_os = "macos")]
-                {
-                    std::process::Command::new("open")
-                        .arg(&temp_file)
-                        .spawn()?;
+                if self.local_copy(&filename).is_some() {
+                    self.start_playback_at(&filename, start_at_ms, stream);
+                } else {
+                    // Start late rather than not at all once the file is here
+                    *self.scheduled_play.lock().unwrap() = Some((filename.clone(), start_at_ms));
+                    if !self.receiving(&filename) {
+                        self.request_media(stream, filename)?;
+                    }
                 }
-                
-                #[cfg(target_os = "linux")]
-                {
-                    std::process::Command::new("xdg-open")
-                        .arg(&temp_file)
-                        .spawn()?;
+            }
+            
+            Message::Pause { position_ms } => {
+                println!("Pause command received at {} ms", position_ms);
+                if let Some(playback) = self.playback.lock().unwrap().as_mut() {
+                    playback.paused = true;
+                    playback.resume_at_ms = None;
+                    self.control_player("pause", |player| {
+                        player.pause()?;
+                        player.seek(position_ms)
+                    });
                 }
             }
             
-            "image" => {
-                println!("Displaying image on CLIENT: {} ({} bytes)", filename, data.len());
-                
-                #[cfg(target_os = "windows")]
-                {
-                    std::process::Command::new("cmd")
-                        .args(&["/C", "start", "", &temp_file])
-                        .spawn()?;
+            Message::Resume { at } => {
+                println!("Resume command received (resumes in {} ms)", at as i64 - clock::now_millis() as i64);
+                if let Some(playback) = self.playback.lock().unwrap().as_mut().filter(|playback| playback.paused) {
+                    playback.resume_at_ms = Some(at);
+                    let client = self.clone();
+                    thread::spawn(move || {
+                        clock::sleep_until(at);
+                        let mut playback = client.playback.lock().unwrap();
+                        if let Some(playback) = playback.as_mut().filter(|playback| playback.resume_at_ms == Some(at)) {
+                            playback.paused = false;
+                            playback.resume_at_ms = None;
+                            client.control_player("resume", |player| player.play_at(at));
+                        }
+                    });
                 }
-                
-                #[cfg(target_os = "macos")]
-                {
-                    std::process::Command::new("open")
-                        .arg(&temp_file)
-                        .spawn()?;
+            }
+            
+            Message::Seek { position_ms } => {
+                println!("Seek command received: {} ms", position_ms);
+                if self.playback.lock().unwrap().is_some() {
+                    self.control_player("seek", |player| player.seek(position_ms));
                 }
-                
-                #[cfg(target_os = "linux")]
-                {
-                    std::process::Command::new("xdg-open")
-                        .arg(&temp_file)
-                        .spawn()?;
+            }
+            
+            Message::ServerShutdown => {
+                println!("Server is shutting down");
+            }
+            
+            Message::Stop => {
+                println!("Stop command received");
+                *self.playback.lock().unwrap() = None;
+                *self.scheduled_play.lock().unwrap() = None;
+                self.control_player("stop", |player| player.stop());
+            }
+            
+            Message::SyncCorrection { filename, correction } => {
+                let playback = self.playback.lock().unwrap();
+                if playback.as_ref().is_some_and(|playback| playback.filename == filename) {
+                    println!("Sync correction for {}: {:?}", filename, correction);
+                    self.control_player("apply sync correction", |player| match correction {
+                        Correction::Seek { position_ms } => player.seek(position_ms),
+                        Correction::Rate { rate } => player.set_rate(rate),
+                    });
                 }
             }
             
-            _ => {
-                println!("Unknown media type: {}", media_type);
+            Message::ClockPing { origin_ms } => {
+                let receive_ms = clock::now_millis();
+                let pong = Message::ClockPong {
+                    origin_ms,
+                    receive_ms,
+                    transmit_ms: clock::now_millis(),
+                };
+                self.send_message_arc(stream, &pong)?;
+            }
+            
+            Message::Rejected { reason } => {
+                return Err(format!("Server rejected connection: {}", reason).into());
+            }
+            
+            Message::Error { message } => {
+                eprintln!("Server error: {}", message);
             }
+            
+            _ => {}
         }
         
         Ok(())
     }
 
+    /// Plays a received file once the local clock reaches `start_at_ms`, then
+    /// sends a `PositionReport` every `POSITION_REPORT_INTERVAL` until playback
+    /// moves on to something else. Runs on its own thread so the message loop
+    /// isn't blocked.
+    fn start_playback_at(&self, filename: &str, start_at_ms: u64, stream: &Arc<Mutex<TcpStream>>) {
+        let Some(media) = self.local_copy(filename) else {
+            eprintln!("{} is no longer in the media cache", filename);
+            return;
+        };
+        
+        let client = self.clone();
+        let filename = filename.to_string();
+        let stream = Arc::clone(stream);
+        thread::spawn(move || {
+            println!("Playing media on CLIENT {}: {} ({} bytes)", client.client_id, filename, media.size);
+            // Recorded before the lead time, so a Pause or Stop that arrives
+            // before the start finds it
+            *client.playback.lock().unwrap() = Some(Playback {
+                filename: filename.clone(),
+                start_at_ms,
+                paused: false,
+                resume_at_ms: None,
+            });
+            // Load ahead of the start so the player is ready on time
+            if let Err(e) = client.player.lock().unwrap().load(&media.path, &media.media_type) {
+                eprintln!("Error loading media on client: {}", e);
+                return;
+            }
+            *client.loaded_media.lock().unwrap() = Some(media.path.clone());
+            clock::sleep_until(start_at_ms);
+            {
+                let playback = client.playback.lock().unwrap();
+                let current = playback
+                    .as_ref()
+                    .filter(|playback| playback.filename == filename && playback.start_at_ms == start_at_ms);
+                // Stopped or replaced during the lead time
+                let Some(playback) = current else { return };
+                // A paused play waits for Resume to start the player
+                if !playback.paused {
+                    client.control_player("play", |player| player.play_at(start_at_ms));
+                }
+            }
+            
+            loop {
+                thread::sleep(POSITION_REPORT_INTERVAL);
+                let report = {
+                    let playback = client.playback.lock().unwrap();
+                    let current = playback
+                        .as_ref()
+                        .filter(|playback| playback.filename == filename && playback.start_at_ms == start_at_ms);
+                    let Some(playback) = current else { break };
+                    let Some(position_ms) = client.player.lock().unwrap().position() else { continue };
+                    Message::PositionReport {
+                        filename: filename.clone(),
+                        position_ms,
+                        at_ms: clock::now_millis(),
+                        playing: !playback.paused,
+                    }
+                };
+                if client.send_message_arc(&stream, &report).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Runs a command on the player, logging rather than dropping the connection if it fails
+    fn control_player(&self, action: &str, command: impl FnOnce(&mut dyn Player) -> PlayerResult<()>) {
+        if let Err(e) = command(self.player.lock().unwrap().as_mut()) {
+            eprintln!("Player failed to {}: {}", action, e);
+        }
+    }
+
     fn send_message(&self, stream: &TcpStream, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
         let mut stream = stream.try_clone()?;
-        let data = serde_json::to_string(message)?;
-        let line = format!("{}\n", data);
-        stream.write_all(line.as_bytes())?;
-        stream.flush()?;
+        protocol::write_message(&mut stream, message)?;
         Ok(())
     }
 
     fn send_message_arc(&self, stream: &Arc<Mutex<TcpStream>>, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
-        let data = serde_json::to_string(message)?;
-        let line = format!("{}\n", data);
         let mut stream = stream.lock().unwrap();
-        stream.write_all(line.as_bytes())?;
-        stream.flush()?;
+        protocol::write_message(&mut *stream, message)?;
         Ok(())
     }
 }
 
+// Drops list entries whose path isn't a plain relative path, before anything
+// is done with them. A well-behaved server never lists such a file
+fn safe_entries(entries: Vec<MediaEntry>) -> Vec<MediaEntry> {
+    entries
+        .into_iter()
+        .filter(|entry| match library::validate_key(&entry.path) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Ignoring media entry from server: {}", e);
+                false
+            }
+        })
+        .collect()
+}
+
+// One line for a media list entry: path, size, type and whatever metadata the server sent
+fn describe_entry(entry: &MediaEntry) -> String {
+    match &entry.metadata {
+        Some(metadata) => format!("{} ({} bytes, {}, {})", entry.path, entry.size, entry.media_type, metadata),
+        None => format!("{} ({} bytes, {})", entry.path, entry.size, entry.media_type),
+    }
+}
+
+const SERVER_CONSOLE_HELP: &str = "Commands: list | clients | play <file> | pause | resume | seek <seconds> | stop | help";
+
+// Reads group playback commands from stdin while the server runs
+fn run_server_console(server: MediaServer) {
+    println!("{}", SERVER_CONSOLE_HELP);
+    
+    for line in std::io::stdin().lines() {
+        let Ok(line) = line else { break };
+        let line = line.trim();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        
+        let result = match command {
+            "" => Ok(()),
+            "list" => {
+                let mut files: Vec<String> = server.media_files.lock().unwrap().keys().cloned().collect();
+                files.sort();
+                for file in files {
+                    println!("  {}", file);
+                }
+                Ok(())
+            }
+            "clients" => {
+                for client in server.get_connected_clients() {
+                    println!("  {} ({})", client.id, client.address);
+                }
+                Ok(())
+            }
+            "play" if !argument.is_empty() => server.play_media(argument),
+            "pause" => server.pause_media().map(|_| ()),
+            "resume" => server.resume_media(),
+            "seek" => match argument.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => server.seek_media((seconds * 1000.0) as u64),
+                _ => Err("Usage: seek <seconds>".to_string()),
+            },
+            "stop" => server.stop_media(),
+            _ => Err(SERVER_CONSOLE_HELP.to_string()),
+        };
+        
+        if let Err(e) = result {
+            println!("{}", e);
+        }
+    }
+}
+
 // Main application
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -690,8 +2577,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     if args.len() < 2 {
         println!("Usage:");
-        println!("  {} server <port> <media_directory>", args[0]);
-        println!("  {} client <server_ip:port> <client_id>", args[0]);
+        println!("  {} server <port> <media_directory> [--lead-time <ms>] [--drift-threshold <ms>] [--player <backend>] [--host-playback <off|on|participant>] [--cache-size <MiB>] [--include <glob>]... [--exclude <glob>]... [--max-depth <n>] [--watch-interval <seconds>] [--send-queue <messages>] [--slow-client <drop|disconnect>]", args[0]);
+        println!("  {} client <server_ip:port> <client_id> [--player <backend>] [--cache-dir <path>] [--cache-size <MiB>]", args[0]);
         println!("  {} web [port]", args[0]);
         return Ok(());
     }
@@ -699,22 +2586,85 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match args[1].as_str() {
         "server" => {
             if args.len() < 4 {
-                println!("Usage: {} server <port> <media_directory>", args[0]);
+                println!("Usage: {} server <port> <media_directory> [--lead-time <ms>] [--drift-threshold <ms>] [--player <backend>] [--host-playback <off|on|participant>] [--cache-size <MiB>] [--include <glob>]... [--exclude <glob>]... [--max-depth <n>] [--watch-interval <seconds>] [--send-queue <messages>] [--slow-client <drop|disconnect>]", args[0]);
                 return Ok(());
             }
             
             let port: u16 = args[2].parse()?;
             let media_dir = &args[3];
             
-            println!("Starting MEDIA SERVER - Media will play on HOST device");
+            println!("Starting MEDIA SERVER");
             let server = MediaServer::new();
+            
+            let mut scan_options = ScanOptions::default();
+            let mut options = args[4..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--lead-time" => {
+                        let ms: u64 = options.next().ok_or("--lead-time needs a value in milliseconds")?.parse()?;
+                        server.set_play_lead_time(Duration::from_millis(ms));
+                    }
+                    "--drift-threshold" => {
+                        let ms: u64 = options.next().ok_or("--drift-threshold needs a value in milliseconds")?.parse()?;
+                        server.set_drift_threshold(Duration::from_millis(ms));
+                    }
+                    "--player" => {
+                        let backend: PlayerBackend = options.next().ok_or("--player needs a backend name")?.parse()?;
+                        server.set_host_player(backend.create()?);
+                        println!("Host player: {}", backend);
+                    }
+                    "--host-playback" => {
+                        let mode: HostPlayback = options.next().ok_or("--host-playback needs off, on or participant")?.parse()?;
+                        server.set_host_playback(mode);
+                        println!("Host playback: {:?}", mode);
+                    }
+                    "--cache-size" => {
+                        let mib: usize = options.next().ok_or("--cache-size needs a value in MiB")?.parse()?;
+                        server.set_cache_size(mib.checked_mul(1024 * 1024).ok_or("--cache-size is too large")?);
+                    }
+                    "--send-queue" => {
+                        server.set_send_queue_len(options.next().ok_or("--send-queue needs a number of messages")?.parse()?);
+                    }
+                    "--slow-client" => {
+                        let policy: SlowClientPolicy = options.next().ok_or("--slow-client needs drop or disconnect")?.parse()?;
+                        server.set_slow_client_policy(policy);
+                    }
+                    "--include" => {
+                        scan_options.include.push(options.next().ok_or("--include needs a glob pattern")?.clone());
+                    }
+                    "--exclude" => {
+                        scan_options.exclude.push(options.next().ok_or("--exclude needs a glob pattern")?.clone());
+                    }
+                    "--watch-interval" => {
+                        let seconds: u64 = options.next().ok_or("--watch-interval needs a number of seconds")?.parse()?;
+                        server.set_watch_interval((seconds > 0).then(|| Duration::from_secs(seconds)));
+                    }
+                    "--max-depth" => {
+                        scan_options.max_depth = Some(options.next().ok_or("--max-depth needs a number of levels")?.parse()?);
+                    }
+                    other => return Err(format!("Unknown server option: {}", other).into()),
+                }
+            }
+            
+            server.set_scan_options(scan_options);
             server.load_media_path(media_dir)?;
-            server.start_server(port)?;
+            
+            let console_server = server.clone();
+            thread::spawn(move || run_server_console(console_server));
+            
+            // Let clients know the server is going away instead of just dropping them
+            let interrupted = server.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    interrupted.shutdown();
+                }
+            });
+            server.start_server(port).await?;
         }
         
         "client" => {
             if args.len() < 4 {
-                println!("Usage: {} client <server_ip:port> <client_id>", args[0]);
+                println!("Usage: {} client <server_ip:port> <client_id> [--player <backend>] [--cache-dir <path>] [--cache-size <MiB>]", args[0]);
                 return Ok(());
             }
             
@@ -723,6 +2673,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             
             println!("Starting MEDIA CLIENT - Media will play on CLIENT device");
             let client = MediaClient::new(server_addr, client_id);
+            
+            let mut cache_dir = media_cache::default_dir();
+            let mut cache_size = DEFAULT_MEDIA_CACHE_SIZE;
+            let mut options = args[4..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--player" => {
+                        let backend: PlayerBackend = options.next().ok_or("--player needs a backend name")?.parse()?;
+                        client.set_player(backend.create()?);
+                        println!("Player: {}", backend);
+                    }
+                    "--cache-dir" => {
+                        cache_dir = PathBuf::from(options.next().ok_or("--cache-dir needs a directory")?);
+                    }
+                    "--cache-size" => {
+                        let mib: u64 = options.next().ok_or("--cache-size needs a value in MiB")?.parse()?;
+                        cache_size = mib.checked_mul(1024 * 1024).ok_or("--cache-size is too large")?;
+                    }
+                    other => return Err(format!("Unknown client option: {}", other).into()),
+                }
+            }
+            
+            client.set_media_cache(cache_dir, cache_size);
+            
+            // End the connection on Ctrl-C rather than dying mid-transfer, so partial
+            // downloads are recorded and the scratch directory is removed
+            let interrupted = client.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    interrupted.disconnect();
+                }
+            });
             client.connect()?;
         }
         
@@ -738,4 +2720,126 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn large_drift_seeks_to_the_group_position() {
+        assert_eq!(drift_correction(600, 100, 1.0, || Some(42_000)), Some(Correction::Seek { position_ms: 42_000 }));
+        assert_eq!(drift_correction(-600, 100, 0.97, || Some(42_000)), Some(Correction::Seek { position_ms: 42_000 }));
+        // Nothing to seek to yet
+        assert_eq!(drift_correction(600, 100, 1.0, || None), None);
+        // At exactly the seek threshold a rate nudge is still enough
+        assert!(matches!(drift_correction(500, 100, 1.0, || Some(0)), Some(Correction::Rate { .. })));
+    }
+
+    #[test]
+    fn moderate_drift_nudges_the_rate_within_bounds() {
+        let rate = |correction| match correction {
+            Some(Correction::Rate { rate }) => rate,
+            other => panic!("expected a rate nudge, got {:?}", other),
+        };
+        assert!((rate(drift_correction(200, 100, 1.0, || None)) - 0.96).abs() < 1e-9);
+        assert!((rate(drift_correction(-200, 100, 1.0, || None)) - 1.04).abs() < 1e-9);
+        assert_eq!(rate(drift_correction(400, 100, 1.0, || None)), 1.0 - MAX_RATE_ADJUSTMENT);
+        assert_eq!(rate(drift_correction(-1000, 300, 1.0, || None)), 1.0 + MAX_RATE_ADJUSTMENT);
+    }
+
+    #[test]
+    fn an_existing_nudge_is_not_repeated() {
+        assert_eq!(drift_correction(200, 100, 0.96, || None), None);
+        assert_eq!(drift_correction(400, 100, 1.0 - MAX_RATE_ADJUSTMENT, || None), None);
+    }
+
+    #[test]
+    fn rate_returns_to_normal_within_half_the_threshold() {
+        assert_eq!(drift_correction(50, 100, 0.96, || None), Some(Correction::Rate { rate: 1.0 }));
+        assert_eq!(drift_correction(-10, 100, 1.04, || None), Some(Correction::Rate { rate: 1.0 }));
+        // Between half the threshold and the threshold the nudge is left alone
+        assert_eq!(drift_correction(80, 100, 0.96, || None), None);
+        // Already at normal speed
+        assert_eq!(drift_correction(0, 100, 1.0, || None), None);
+    }
+
+    #[test]
+    fn files_are_looked_up_by_media_id() {
+        let dir = TempDir::new("library");
+        fs::write(dir.path().join("a.jpg"), b"\xFF\xD8\xFF\xE0").unwrap();
+        fs::write(dir.path().join("b.jpg"), b"\xFF\xD8\xFF\xE0").unwrap();
+        let server = MediaServer::new();
+        server.load_media_path(dir.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(server.media_file_by_id(&MediaId::for_key("a.jpg")).unwrap().filename, "a.jpg");
+        assert_eq!(server.media_file_by_id(&MediaId::for_key("b.jpg")).unwrap().filename, "b.jpg");
+        assert!(server.media_file_by_id(&MediaId::for_key("c.jpg")).is_none());
+    }
+
+    #[test]
+    fn a_file_whose_id_is_taken_is_kept_out() {
+        let dir = TempDir::new("collision");
+        fs::write(dir.path().join("a.jpg"), b"\xFF\xD8\xFF\xE0").unwrap();
+        let server = MediaServer::new();
+        // Stands in for another key that hashes to the same id
+        server.media_ids.lock().unwrap().insert(MediaId::for_key("a.jpg"), "other.jpg".to_string());
+        server.load_media_path(dir.path().to_str().unwrap()).unwrap();
+
+        assert!(!server.media_files.lock().unwrap().contains_key("a.jpg"));
+        assert!(server.media_file_by_id(&MediaId::for_key("a.jpg")).is_none());
+        assert!(server.skipped_files.lock().unwrap().contains_key(&dir.path().join("a.jpg")));
+        // The rescan leaves it out too
+        assert!(server.rescan_library().added.is_empty());
+    }
+
+    // A server whose group plays only involve clients, with `filename` in its library
+    fn headless_server(dir: &TempDir, filename: &str) -> MediaServer {
+        fs::write(dir.path().join(filename), b"\xFF\xD8\xFF\xE0").unwrap();
+        let server = MediaServer::new();
+        server.set_host_playback(HostPlayback::Off);
+        server.load_media_path(dir.path().to_str().unwrap()).unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn group_play_starts_as_soon_as_the_last_client_is_ready() {
+        let dir = TempDir::new("ready");
+        let server = headless_server(&dir, "a.jpg");
+        *server.pending_play.lock().unwrap() = Some(PendingPlay {
+            filename: "a.jpg".to_string(),
+            waiting_for: HashSet::from(["living-room".to_string()]),
+            ready: Vec::new(),
+        });
+        let task = tokio::spawn(server.clone().commit_play_when_ready("a.jpg".to_string()));
+        tokio::task::yield_now().await;
+        assert!(server.playback_session.lock().unwrap().is_none());
+
+        // What a MediaReady from the last client does
+        server.pending_play.lock().unwrap().as_mut().unwrap().waiting_for.clear();
+        server.ready_changed.notify_one();
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_eq!(server.playback_session.lock().unwrap().as_ref().unwrap().filename, "a.jpg");
+        assert!(*server.is_playing.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn stop_ends_a_group_play_and_forgets_its_task() {
+        let dir = TempDir::new("stop");
+        let server = headless_server(&dir, "a.jpg");
+        server.play_media("a.jpg").unwrap();
+        // No clients, so the play is committed straight away
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.playback_session.lock().unwrap().is_none() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        server.stop_media().unwrap();
+        assert!(server.playback_session.lock().unwrap().is_none());
+        assert!(server.play_task.lock().unwrap().is_none());
+        assert!(!*server.is_playing.lock().unwrap());
+    }
+}
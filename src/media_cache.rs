@@ -0,0 +1,198 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::disk_lru::{self, touch};
+use crate::media_type::MediaType;
+use crate::partial::PartialDownload;
+
+/// Default limit on the total size of a client's media cache.
+pub const DEFAULT_MEDIA_CACHE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+// A download written to this recently is taken to be still arriving, possibly
+// for another client sharing the cache, and is left alone by eviction
+const ACTIVE_DOWNLOAD_WINDOW: Duration = Duration::from_secs(60);
+
+/// Where a client keeps its media cache unless told otherwise.
+pub fn default_dir() -> PathBuf {
+    std::env::temp_dir().join("media-sync-cache")
+}
+
+/// Received media kept on disk under its content hash, so a file is only
+/// transferred again when its contents change. Once the directory grows past
+/// its size limit the least recently used files are deleted; a file's
+/// modification time records its last use.
+pub struct MediaCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl MediaCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The cached copy of the file with this hash, if there is one. Counts as a use.
+    pub fn get(&self, hash: &str, media_type: &MediaType) -> Option<PathBuf> {
+        let path = self.dir.join(entry_name(hash, media_type)?);
+        touch(&path).ok()?;
+        Some(path)
+    }
+
+    /// Where the file with this hash is written while it is being received.
+    /// `owner` keeps clients sharing a cache from writing the same file.
+    pub fn download_path(&self, hash: &str, media_type: &MediaType, owner: &str) -> io::Result<PathBuf> {
+        let name = entry_name(hash, media_type)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("'{}' is not a SHA-256 hash", hash)))?;
+        fs::create_dir_all(&self.dir)?;
+        Ok(self.dir.join(format!("{}.{}.download", name, owner.replace(['/', '\\', '.'], "_"))))
+    }
+
+    /// Moves a verified download into the cache, then makes room for it
+    /// without deleting anything in `in_use`, such as the file being played.
+    pub fn insert(&self, download: &Path, hash: &str, media_type: &MediaType, in_use: &[PathBuf]) -> io::Result<PathBuf> {
+        let name = entry_name(hash, media_type)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("'{}' is not a SHA-256 hash", hash)))?;
+        let path = self.dir.join(name);
+        fs::rename(download, &path)?;
+        touch(&path)?;
+        self.evict(&path, in_use);
+        Ok(path)
+    }
+
+    // Deletes the least recently used files, interrupted downloads included,
+    // until the cache fits its limit. `keep`, `in_use` and downloads that are
+    // still being written are never deleted, even if they alone are over the limit.
+    fn evict(&self, keep: &Path, in_use: &[PathBuf]) {
+        let evicted = disk_lru::evict(
+            &self.dir,
+            self.max_bytes,
+            "media cache",
+            |path| !PartialDownload::is_record(path),
+            |path| path == keep || in_use.iter().any(|used| used == path) || is_active_download(path),
+        );
+        for path in evicted {
+            PartialDownload::discard(&path);
+            println!("Evicted {} from the media cache", path.display());
+        }
+    }
+}
+
+// File name for a cached file. The hash comes from the server, so anything
+// but a hex digest is refused rather than put into a path.
+fn entry_name(hash: &str, media_type: &MediaType) -> Option<String> {
+    let valid = hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit());
+    valid.then(|| format!("{}.{}", hash.to_ascii_lowercase(), media_type.extension()))
+}
+
+fn is_active_download(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "download")
+        && fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().map_or(true, |age| age < ACTIVE_DOWNLOAD_WINDOW))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::time::SystemTime;
+
+    fn hash(n: u8) -> String {
+        format!("{:064x}", n)
+    }
+
+    fn mp4() -> MediaType {
+        MediaType::from_mime("video/mp4").unwrap()
+    }
+
+    // Writes a `len`-byte file at `path` last used `age` ago
+    fn write_aged(path: &Path, len: usize, age: Duration) {
+        fs::write(path, vec![0; len]).unwrap();
+        fs::File::options().write(true).open(path).unwrap().set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    // Adds a cached file for `hash(n)` through a download, as a client would
+    fn insert(cache: &MediaCache, n: u8, len: usize, in_use: &[PathBuf]) -> PathBuf {
+        let download = cache.download_path(&hash(n), &mp4(), "test").unwrap();
+        fs::write(&download, vec![0; len]).unwrap();
+        cache.insert(&download, &hash(n), &mp4(), in_use).unwrap()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let dir = TempDir::new("lru");
+        let cache = MediaCache::new(dir.path().to_path_buf(), 30);
+        let oldest = dir.path().join(entry_name(&hash(1), &mp4()).unwrap());
+        let middle = dir.path().join(entry_name(&hash(2), &mp4()).unwrap());
+        write_aged(&oldest, 10, Duration::from_secs(300));
+        write_aged(&middle, 10, Duration::from_secs(200));
+        // A use moves it to the back of the queue
+        assert_eq!(cache.get(&hash(1), &mp4()), Some(oldest.clone()));
+
+        let newest = insert(&cache, 3, 15, &[]);
+        assert!(oldest.exists());
+        assert!(!middle.exists());
+        assert!(newest.exists());
+        assert_eq!(cache.get(&hash(2), &mp4()), None);
+    }
+
+    #[test]
+    fn keeps_the_new_file_and_files_in_use() {
+        let dir = TempDir::new("keep");
+        let cache = MediaCache::new(dir.path().to_path_buf(), 10);
+        let playing = dir.path().join(entry_name(&hash(1), &mp4()).unwrap());
+        let idle = dir.path().join(entry_name(&hash(2), &mp4()).unwrap());
+        write_aged(&playing, 10, Duration::from_secs(300));
+        write_aged(&idle, 10, Duration::from_secs(200));
+
+        // Over the limit on its own, but just added
+        let added = insert(&cache, 3, 20, std::slice::from_ref(&playing));
+        assert!(added.exists());
+        assert!(playing.exists());
+        assert!(!idle.exists());
+    }
+
+    #[test]
+    fn keeps_downloads_still_being_written() {
+        let dir = TempDir::new("downloads");
+        let cache = MediaCache::new(dir.path().to_path_buf(), 10);
+        let active = cache.download_path(&hash(1), &mp4(), "other").unwrap();
+        let abandoned = cache.download_path(&hash(2), &mp4(), "gone").unwrap();
+        write_aged(&active, 10, Duration::ZERO);
+        write_aged(&abandoned, 10, ACTIVE_DOWNLOAD_WINDOW + Duration::from_secs(60));
+        PartialDownload { size: 20, hash: hash(2), verified: 10 }.save(&abandoned).unwrap();
+
+        insert(&cache, 3, 5, &[]);
+        assert!(active.exists());
+        assert!(!abandoned.exists());
+        // The abandoned download's record goes with it
+        assert_eq!(PartialDownload::load(&abandoned), None);
+    }
+
+    #[test]
+    fn only_sha256_hex_digests_name_entries() {
+        assert_eq!(entry_name(&hash(0xAB), &mp4()), Some(format!("{}.mp4", hash(0xAB))));
+        assert_eq!(entry_name(&hash(0xAB).to_ascii_uppercase(), &mp4()), Some(format!("{}.mp4", hash(0xAB))));
+        for bad in [
+            String::new(),
+            "0".repeat(63),
+            "0".repeat(65),
+            format!("{}g", "0".repeat(63)),
+            format!("../{}", "0".repeat(61)),
+            format!("{}/", "0".repeat(63)),
+            format!("{}é", "0".repeat(62)),
+        ] {
+            assert_eq!(entry_name(&bad, &mp4()), None, "{:?}", bad);
+        }
+
+        let dir = TempDir::new("names");
+        let cache = MediaCache::new(dir.path().to_path_buf(), 10);
+        assert_eq!(cache.get("../../etc/passwd", &mp4()), None);
+        assert!(cache.download_path("not-a-hash", &mp4(), "test").is_err());
+    }
+}
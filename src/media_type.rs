@@ -0,0 +1,120 @@
+use std::fmt;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Video,
+    Audio,
+    Image,
+}
+
+impl fmt::Display for MediaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MediaKind::Video => "video",
+            MediaKind::Audio => "audio",
+            MediaKind::Image => "image",
+        };
+        f.write_str(name)
+    }
+}
+
+struct Format {
+    kind: MediaKind,
+    mime: &'static str,
+    extensions: &'static [&'static str],
+}
+
+/// Every format the library accepts. Supporting a new one means adding a row
+/// here and, in `detect::sniff`, the signature that identifies it.
+const FORMATS: &[Format] = &[
+    Format { kind: MediaKind::Video, mime: "video/mp4", extensions: &["mp4", "m4v"] },
+    Format { kind: MediaKind::Video, mime: "video/quicktime", extensions: &["mov"] },
+    Format { kind: MediaKind::Video, mime: "video/3gpp", extensions: &["3gp"] },
+    Format { kind: MediaKind::Video, mime: "video/x-matroska", extensions: &["mkv"] },
+    Format { kind: MediaKind::Video, mime: "video/webm", extensions: &["webm"] },
+    Format { kind: MediaKind::Video, mime: "video/x-msvideo", extensions: &["avi"] },
+    Format { kind: MediaKind::Video, mime: "video/ogg", extensions: &["ogv"] },
+    Format { kind: MediaKind::Audio, mime: "audio/mpeg", extensions: &["mp3"] },
+    Format { kind: MediaKind::Audio, mime: "audio/mp4", extensions: &["m4a", "m4b"] },
+    Format { kind: MediaKind::Audio, mime: "audio/aac", extensions: &["aac"] },
+    Format { kind: MediaKind::Audio, mime: "audio/wav", extensions: &["wav"] },
+    Format { kind: MediaKind::Audio, mime: "audio/flac", extensions: &["flac"] },
+    Format { kind: MediaKind::Audio, mime: "audio/ogg", extensions: &["ogg", "oga", "opus"] },
+    Format { kind: MediaKind::Image, mime: "image/png", extensions: &["png"] },
+    Format { kind: MediaKind::Image, mime: "image/jpeg", extensions: &["jpg", "jpeg"] },
+    Format { kind: MediaKind::Image, mime: "image/gif", extensions: &["gif"] },
+    Format { kind: MediaKind::Image, mime: "image/bmp", extensions: &["bmp"] },
+    Format { kind: MediaKind::Image, mime: "image/webp", extensions: &["webp"] },
+    Format { kind: MediaKind::Image, mime: "image/heic", extensions: &["heic", "heif"] },
+];
+
+/// A supported media format, as carried in the library, the protocol and the web API.
+/// Only the kind and MIME type of a row in `FORMATS` deserialize, so whatever a
+/// peer sends is one of the known formats.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "UncheckedMediaType")]
+pub struct MediaType {
+    pub kind: MediaKind,
+    pub mime: String,
+}
+
+// A MediaType as received, before it is checked against FORMATS
+#[derive(Deserialize)]
+struct UncheckedMediaType {
+    kind: MediaKind,
+    mime: String,
+}
+
+impl TryFrom<UncheckedMediaType> for MediaType {
+    type Error = String;
+
+    fn try_from(unchecked: UncheckedMediaType) -> Result<Self, Self::Error> {
+        FORMATS
+            .iter()
+            .find(|format| format.kind == unchecked.kind && format.mime == unchecked.mime)
+            .map(Self::from_format)
+            .ok_or_else(|| format!("'{}' is not a supported {} format", unchecked.mime.escape_debug(), unchecked.kind))
+    }
+}
+
+impl MediaType {
+    fn from_format(format: &Format) -> Self {
+        Self {
+            kind: format.kind,
+            mime: format.mime.to_string(),
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        FORMATS
+            .iter()
+            .find(|format| format.mime.eq_ignore_ascii_case(mime))
+            .map(Self::from_format)
+    }
+
+    /// The type a file is expected to have going by its extension alone.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        FORMATS
+            .iter()
+            .find(|format| format.extensions.contains(&extension.as_str()))
+            .map(Self::from_format)
+    }
+
+    /// The usual file extension for this type, for naming files saved by content.
+    pub fn extension(&self) -> &'static str {
+        FORMATS
+            .iter()
+            .find(|format| format.mime == self.mime)
+            .map_or("bin", |format| format.extensions[0])
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.mime)
+    }
+}
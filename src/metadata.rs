@@ -0,0 +1,1781 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use crate::media_type::MediaType;
+
+/// Largest `moov`/`meta` box, tag or header chunk read into memory. Anything
+/// bigger is skipped rather than allocated.
+const MAX_HEADER_LEN: u64 = 16 * 1024 * 1024;
+
+/// How much of a Matroska file is searched for its Info and Tracks elements,
+/// which writers put ahead of the first cluster.
+const MATROSKA_HEAD_LEN: usize = 1024 * 1024;
+
+/// How much of the start of a JPEG is searched for its EXIF and frame headers.
+const JPEG_HEAD_LEN: usize = 256 * 1024;
+
+/// Window read from each end of an Ogg file to find its first and last pages.
+const OGG_WINDOW_LEN: u64 = 64 * 1024;
+
+/// What could be read from a file's headers. Every field is optional since
+/// formats, and the writers filling them in, differ in what they record.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct MediaMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    /// Recording or capture date as written in the file, e.g. `2021` or `2021-06-04 18:30:00`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+}
+
+impl MediaMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == MediaMetadata::default()
+    }
+}
+
+impl fmt::Display for MediaMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(duration_ms) = self.duration_ms {
+            let seconds = duration_ms / 1000;
+            parts.push(if seconds >= 3600 {
+                format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+            } else {
+                format!("{}:{:02}", seconds / 60, seconds % 60)
+            });
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            parts.push(format!("{}x{}", width, height));
+        }
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => parts.push(format!("{} - {}", artist, title)),
+            (Some(name), None) | (None, Some(name)) => parts.push(name.clone()),
+            (None, None) => {}
+        }
+        parts.extend(self.album.iter().cloned());
+        parts.extend(self.date.iter().cloned());
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Reads duration, dimensions and tags from a file already identified as
+/// `media_type`. Headers that are missing or malformed leave their fields
+/// empty; only failing to read the file is an error.
+pub fn extract(path: &Path, media_type: &MediaType) -> io::Result<MediaMetadata> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut metadata = MediaMetadata::default();
+
+    match media_type.mime.as_str() {
+        "video/mp4" | "video/quicktime" | "video/3gpp" | "audio/mp4" | "image/heic" => {
+            read_mp4(&mut file, size, &mut metadata)?
+        }
+        "video/x-matroska" | "video/webm" => {
+            let head = read_range(&mut file, 0, MATROSKA_HEAD_LEN)?;
+            read_matroska(&head, &mut metadata);
+        }
+        "audio/mpeg" => read_mp3(&mut file, size, &mut metadata)?,
+        "audio/flac" => read_flac(&mut file, &mut metadata)?,
+        "audio/ogg" | "video/ogg" => read_ogg(&mut file, size, &mut metadata)?,
+        "audio/wav" | "video/x-msvideo" | "image/webp" => read_riff(&mut file, size, &mut metadata)?,
+        "image/png" => {
+            let header = read_range(&mut file, 0, 24)?;
+            if header.get(12..16) == Some(&b"IHDR"[..]) {
+                metadata.width = be_u32(&header, 16);
+                metadata.height = be_u32(&header, 20);
+            }
+        }
+        "image/jpeg" => {
+            let head = read_range(&mut file, 0, JPEG_HEAD_LEN)?;
+            read_jpeg(&head, &mut metadata);
+        }
+        "image/gif" => {
+            let header = read_range(&mut file, 0, 10)?;
+            metadata.width = le_u16(&header, 6).map(u32::from);
+            metadata.height = le_u16(&header, 8).map(u32::from);
+        }
+        "image/bmp" => {
+            let header = read_range(&mut file, 0, 26)?;
+            if le_u32(&header, 14) == Some(12) {
+                metadata.width = le_u16(&header, 18).map(u32::from);
+                metadata.height = le_u16(&header, 20).map(u32::from);
+            } else {
+                // Height is negative for top-down bitmaps
+                metadata.width = le_u32(&header, 18).map(|width| (width as i32).unsigned_abs());
+                metadata.height = le_u32(&header, 22).map(|height| (height as i32).unsigned_abs());
+            }
+        }
+        _ => {}
+    }
+
+    Ok(metadata)
+}
+
+/// The picture embedded in a file's tags (ID3 `APIC`, FLAC `PICTURE` or MP4
+/// `covr`), preferring the front cover. Returned as stored, usually JPEG or PNG.
+pub fn cover_art(path: &Path, media_type: &MediaType) -> io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    // Pictures found, with their ID3/FLAC picture type
+    let mut pictures: Vec<(u32, Vec<u8>)> = Vec::new();
+
+    match media_type.mime.as_str() {
+        "audio/mpeg" => {
+            if let Some(tag) = read_id3v2(&mut file)? {
+                for (id, frame) in &tag.frames {
+                    if id == "APIC" || id == "PIC" {
+                        pictures.extend(id3_picture(id, frame).map(|(kind, data)| (kind as u32, data.to_vec())));
+                    }
+                }
+            }
+        }
+        "audio/flac" => {
+            for (block_type, offset, len) in flac_blocks(&mut file)? {
+                if block_type == 6 && len <= MAX_HEADER_LEN {
+                    let block = read_range(&mut file, offset, len as usize)?;
+                    pictures.extend(flac_picture(&block).map(|(kind, data)| (kind, data.to_vec())));
+                }
+            }
+        }
+        "audio/mp4" | "video/mp4" | "video/quicktime" => {
+            for (_, moov) in read_top_level_boxes(&mut file, size, &[b"moov"])? {
+                for (box_type, udta) in child_boxes(&moov) {
+                    if &box_type == b"udta" {
+                        let covers = ilst_items(udta).into_iter().filter(|(tag, _)| tag == b"covr");
+                        pictures.extend(covers.map(|(_, data)| (FRONT_COVER, data.to_vec())));
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let front = pictures.iter().position(|(kind, _)| *kind == FRONT_COVER).unwrap_or(0);
+    Ok((!pictures.is_empty()).then(|| pictures.swap_remove(front).1))
+}
+
+/// The EXIF orientation of a JPEG (1 to 8; 1 is upright), if it has one.
+pub fn exif_orientation(path: &Path) -> io::Result<Option<u16>> {
+    let mut file = File::open(path)?;
+    let head = read_range(&mut file, 0, JPEG_HEAD_LEN)?;
+    Ok(read_jpeg(&head, &mut MediaMetadata::default()))
+}
+
+// ID3 and FLAC picture type of the front cover
+const FRONT_COVER: u32 = 3;
+
+// MP4, MOV, 3GP, M4A and HEIF are all ISO base media files: a tree of boxes,
+// each a 32-bit size (or 1 and a 64-bit size after the type), a type and a body
+fn read_mp4(file: &mut File, size: u64, metadata: &mut MediaMetadata) -> io::Result<()> {
+    for (box_type, body) in read_top_level_boxes(file, size, &[b"moov", b"meta"])? {
+        if &box_type == b"moov" {
+            read_moov(&body, metadata);
+        } else {
+            read_heif_meta(body.get(4..).unwrap_or_default(), metadata);
+        }
+    }
+    Ok(())
+}
+
+// Bodies of the top-level boxes of the wanted types, skipping past the rest
+// (such as the media data) without reading them
+fn read_top_level_boxes(file: &mut File, size: u64, wanted: &[&[u8; 4]]) -> io::Result<Vec<([u8; 4], Vec<u8>)>> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= size {
+        let header = read_range(file, offset, 16)?;
+        let Some((box_type, header_len, box_len)) = box_header(&header, size - offset) else { break };
+        let body_len = box_len - header_len;
+        if wanted.contains(&&box_type) && body_len <= MAX_HEADER_LEN {
+            boxes.push((box_type, read_range(file, offset + header_len, body_len as usize)?));
+        }
+        offset += box_len;
+    }
+    Ok(boxes)
+}
+
+// Type, header length and total length of the box at the start of `data`,
+// given how many bytes remain in the enclosing box or file
+fn box_header(data: &[u8], remaining: u64) -> Option<([u8; 4], u64, u64)> {
+    let box_type: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+    let (header_len, box_len) = match be_u32(data, 0)? {
+        // Extends to the end of the file
+        0 => (8, remaining),
+        1 => (16, be_u64(data, 8)?),
+        len => (8, len as u64),
+    };
+    (box_len >= header_len && box_len <= remaining).then_some((box_type, header_len, box_len))
+}
+
+fn child_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut children = Vec::new();
+    let mut at = 0;
+    while let Some((box_type, header_len, box_len)) = box_header(&data[at..], (data.len() - at) as u64) {
+        children.push((box_type, &data[at + header_len as usize..at + box_len as usize]));
+        at += box_len as usize;
+        if at + 8 > data.len() {
+            break;
+        }
+    }
+    children
+}
+
+fn read_moov(moov: &[u8], metadata: &mut MediaMetadata) {
+    for (box_type, body) in child_boxes(moov) {
+        match &box_type {
+            b"mvhd" => {
+                let (timescale, duration) = if body.first() == Some(&1) {
+                    (be_u32(body, 20), be_u64(body, 24))
+                } else {
+                    (be_u32(body, 12), be_u32(body, 16).map(u64::from))
+                };
+                if let (Some(timescale @ 1..), Some(duration)) = (timescale, duration) {
+                    metadata.duration_ms = Some((duration as u128 * 1000 / timescale as u128) as u64);
+                }
+            }
+            b"trak" => {
+                // Width and height close tkhd as 16.16 fixed point; audio tracks leave them 0
+                let tkhd = child_boxes(body).into_iter().find(|(box_type, _)| box_type == b"tkhd");
+                if let Some((_, tkhd)) = tkhd.filter(|(_, tkhd)| tkhd.len() >= 8) {
+                    let width = be_u32(tkhd, tkhd.len() - 8).unwrap_or(0) >> 16;
+                    let height = be_u32(tkhd, tkhd.len() - 4).unwrap_or(0) >> 16;
+                    if width > 0 && height > 0 && metadata.width.is_none() {
+                        metadata.width = Some(width);
+                        metadata.height = Some(height);
+                    }
+                }
+            }
+            b"udta" => {
+                for (tag, data) in ilst_items(body) {
+                    let text = String::from_utf8_lossy(data);
+                    match &tag {
+                        b"\xA9nam" => set_text(&mut metadata.title, &text),
+                        b"\xA9ART" => set_text(&mut metadata.artist, &text),
+                        b"\xA9alb" => set_text(&mut metadata.album, &text),
+                        b"\xA9day" => set_text(&mut metadata.date, &text),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// iTunes-style tags from a `udta` box, as (tag, value). Each tag in
+// meta/ilst holds a `data` box of a type, a locale and then the value
+fn ilst_items(udta: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut items = Vec::new();
+    for (box_type, meta) in child_boxes(udta) {
+        if &box_type != b"meta" {
+            continue;
+        }
+        // ISO meta is a full box with 4 bytes of version and flags; QuickTime's isn't
+        let children = if meta.get(4..8) == Some(&b"hdlr"[..]) { meta } else { meta.get(4..).unwrap_or_default() };
+        for (box_type, ilst) in child_boxes(children) {
+            if &box_type != b"ilst" {
+                continue;
+            }
+            for (tag, item) in child_boxes(ilst) {
+                let data = child_boxes(item).into_iter().find(|(box_type, _)| box_type == b"data");
+                if let Some(value) = data.and_then(|(_, data)| data.get(8..)) {
+                    items.push((tag, value));
+                }
+            }
+        }
+    }
+    items
+}
+
+// HEIF keeps image sizes as `ispe` properties under meta/iprp/ipco. Thumbnails
+// have their own, so the largest is taken as the primary image
+fn read_heif_meta(meta: &[u8], metadata: &mut MediaMetadata) {
+    let properties = child_boxes(meta)
+        .into_iter()
+        .filter(|(box_type, _)| box_type == b"iprp")
+        .flat_map(|(_, iprp)| child_boxes(iprp))
+        .filter(|(box_type, _)| box_type == b"ipco")
+        .flat_map(|(_, ipco)| child_boxes(ipco));
+    let largest = properties
+        .filter(|(box_type, _)| box_type == b"ispe")
+        .filter_map(|(_, ispe)| Some((be_u32(ispe, 4)?, be_u32(ispe, 8)?)))
+        .max_by_key(|(width, height)| *width as u64 * *height as u64);
+    if let Some((width, height)) = largest {
+        metadata.width = Some(width);
+        metadata.height = Some(height);
+    }
+}
+
+// Matroska element IDs
+const EBML_SEGMENT: u64 = 0x1853_8067;
+const EBML_INFO: u64 = 0x1549_A966;
+const EBML_TIMECODE_SCALE: u64 = 0x2A_D7B1;
+const EBML_DURATION: u64 = 0x4489;
+const EBML_TITLE: u64 = 0x7BA9;
+const EBML_DATE_UTC: u64 = 0x4461;
+const EBML_TRACKS: u64 = 0x1654_AE6B;
+const EBML_TRACK_ENTRY: u64 = 0xAE;
+const EBML_VIDEO: u64 = 0xE0;
+const EBML_PIXEL_WIDTH: u64 = 0xB0;
+const EBML_PIXEL_HEIGHT: u64 = 0xBA;
+const EBML_TAGS: u64 = 0x1254_C367;
+const EBML_TAG: u64 = 0x7373;
+const EBML_SIMPLE_TAG: u64 = 0x67C8;
+const EBML_TAG_NAME: u64 = 0x45A3;
+const EBML_TAG_STRING: u64 = 0x4487;
+const EBML_CLUSTER: u64 = 0x1F43_B675;
+
+fn read_matroska(head: &[u8], metadata: &mut MediaMetadata) {
+    for (id, segment) in ebml_elements(head) {
+        if id != EBML_SEGMENT {
+            continue;
+        }
+        for (id, body) in ebml_elements(segment) {
+            match id {
+                EBML_INFO => read_matroska_info(body, metadata),
+                EBML_TRACKS => {
+                    let video = ebml_elements(body)
+                        .into_iter()
+                        .filter(|(id, _)| *id == EBML_TRACK_ENTRY)
+                        .flat_map(|(_, entry)| ebml_elements(entry))
+                        .find(|(id, _)| *id == EBML_VIDEO);
+                    if let Some((_, video)) = video {
+                        for (id, value) in ebml_elements(video) {
+                            match id {
+                                EBML_PIXEL_WIDTH => metadata.width = Some(ebml_uint(value) as u32),
+                                EBML_PIXEL_HEIGHT => metadata.height = Some(ebml_uint(value) as u32),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                EBML_TAGS => {
+                    let simple_tags = ebml_elements(body)
+                        .into_iter()
+                        .filter(|(id, _)| *id == EBML_TAG)
+                        .flat_map(|(_, tag)| ebml_elements(tag))
+                        .filter(|(id, _)| *id == EBML_SIMPLE_TAG);
+                    for (_, simple_tag) in simple_tags {
+                        let children = ebml_elements(simple_tag);
+                        let find = |wanted| children.iter().find(|(id, _)| *id == wanted).map(|(_, value)| String::from_utf8_lossy(value));
+                        let (Some(name), Some(value)) = (find(EBML_TAG_NAME), find(EBML_TAG_STRING)) else { continue };
+                        match name.to_ascii_uppercase().as_str() {
+                            "TITLE" => set_text(&mut metadata.title, &value),
+                            "ARTIST" => set_text(&mut metadata.artist, &value),
+                            "ALBUM" => set_text(&mut metadata.album, &value),
+                            "DATE_RELEASED" | "DATE_RECORDED" => set_text(&mut metadata.date, &value),
+                            _ => {}
+                        }
+                    }
+                }
+                // Everything we want comes before the media data
+                EBML_CLUSTER => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn read_matroska_info(info: &[u8], metadata: &mut MediaMetadata) {
+    // Durations are in units of the timecode scale, which is in nanoseconds
+    let mut timecode_scale = 1_000_000;
+    let mut duration = None;
+    for (id, value) in ebml_elements(info) {
+        match id {
+            EBML_TIMECODE_SCALE => timecode_scale = ebml_uint(value),
+            EBML_DURATION => {
+                duration = match value.len() {
+                    4 => Some(f32::from_be_bytes(value.try_into().unwrap()) as f64),
+                    8 => Some(f64::from_be_bytes(value.try_into().unwrap())),
+                    _ => None,
+                }
+            }
+            EBML_TITLE => set_text(&mut metadata.title, &String::from_utf8_lossy(value)),
+            EBML_DATE_UTC => {
+                // Nanoseconds since the start of the millennium
+                let nanoseconds = ebml_uint(value) as i64;
+                let epoch = NaiveDate::from_ymd_opt(2001, 1, 1).and_then(|date| date.and_hms_opt(0, 0, 0));
+                if let Some(date) = epoch.and_then(|epoch| epoch.checked_add_signed(Duration::nanoseconds(nanoseconds))) {
+                    metadata.date = Some(date.format("%Y-%m-%d %H:%M:%S").to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(duration) = duration.filter(|duration| duration.is_finite() && *duration > 0.0) {
+        metadata.duration_ms = Some((duration * timecode_scale as f64 / 1_000_000.0) as u64);
+    }
+}
+
+// Elements of an EBML master element as (id, body). A body whose size is
+// unknown or runs past the data is cut off at the end of the data
+fn ebml_elements(data: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut elements = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let Some((id, id_len)) = ebml_vint(data, at, true) else { break };
+        let Some((size, size_len)) = ebml_vint(data, at + id_len, false) else { break };
+        let start = at + id_len + size_len;
+        let unknown_size = size == (1 << (7 * size_len)) - 1;
+        let end = if unknown_size { data.len() } else { start.saturating_add(size as usize).min(data.len()) };
+        if start > end {
+            break;
+        }
+        elements.push((id, &data[start..end]));
+        at = end;
+    }
+    elements
+}
+
+// A variable-length integer: the number of leading zero bits in the first byte
+// gives the length. IDs keep the length marker bit, sizes don't
+fn ebml_vint(data: &[u8], at: usize, keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.get(at)?;
+    if first == 0 {
+        return None;
+    }
+    let len = first.leading_zeros() as usize + 1;
+    let bytes = data.get(at..at + len)?;
+    let first = if keep_marker { first as u64 } else { first as u64 & (0xFF >> len) };
+    let value = bytes[1..].iter().fold(first, |value, byte| value << 8 | *byte as u64);
+    Some((value, len))
+}
+
+fn ebml_uint(data: &[u8]) -> u64 {
+    data.iter().take(8).fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+fn read_mp3(file: &mut File, size: u64, metadata: &mut MediaMetadata) -> io::Result<()> {
+    let mut audio_start = 0;
+    let mut audio_end = size;
+
+    if let Some(tag) = read_id3v2(file)? {
+        for (id, frame) in &tag.frames {
+            let field = match id.as_str() {
+                "TIT2" | "TT2" => &mut metadata.title,
+                "TPE1" | "TP1" => &mut metadata.artist,
+                "TALB" | "TAL" => &mut metadata.album,
+                "TDRC" | "TYER" | "TYE" => &mut metadata.date,
+                _ => continue,
+            };
+            if let Some(text) = id3_text(frame) {
+                set_text(field, &text);
+            }
+        }
+        audio_start = tag.len;
+    }
+
+    // ID3v1 is a fixed 128-byte block at the very end
+    if size >= audio_start + 128 {
+        let tag = read_range(file, size - 128, 128)?;
+        if tag.starts_with(b"TAG") {
+            set_text(&mut metadata.title, &latin1(&tag[3..33]));
+            set_text(&mut metadata.artist, &latin1(&tag[33..63]));
+            set_text(&mut metadata.album, &latin1(&tag[63..93]));
+            set_text(&mut metadata.date, &latin1(&tag[93..97]));
+            audio_end -= 128;
+        }
+    }
+
+    let frames = read_range(file, audio_start, 64 * 1024)?;
+    metadata.duration_ms = mp3_duration(&frames, audio_end.saturating_sub(audio_start));
+    Ok(())
+}
+
+struct Id3v2Tag {
+    // Frame IDs and bodies, with any unsynchronisation and extra header fields removed
+    frames: Vec<(String, Vec<u8>)>,
+    // Bytes taken by the tag, header and footer included
+    len: u64,
+}
+
+// The ID3v2 tag at the start of an MP3, if there is one
+fn read_id3v2(file: &mut File) -> io::Result<Option<Id3v2Tag>> {
+    let header = read_range(file, 0, 10)?;
+    if header.len() < 10 || !header.starts_with(b"ID3") {
+        return Ok(None);
+    }
+    let tag_len = syncsafe(&header[6..10]) as u64;
+    let footer_len = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    let tag = read_range(file, 10, tag_len.min(MAX_HEADER_LEN) as usize)?;
+    Ok(Some(Id3v2Tag {
+        frames: id3v2_frames(header[3], header[5], &tag),
+        len: 10 + tag_len + footer_len,
+    }))
+}
+
+fn id3v2_frames(version: u8, flags: u8, tag: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut frames = Vec::new();
+
+    // Before v2.4 unsynchronisation applies to the whole tag
+    let unsynchronised;
+    let mut tag = tag;
+    if flags & 0x80 != 0 && version < 4 {
+        unsynchronised = remove_unsynchronisation(tag);
+        tag = &unsynchronised;
+    }
+
+    let mut at = 0;
+    if flags & 0x40 != 0 && version >= 3 {
+        // Extended header; its size counts itself in v2.4 but not in v2.3
+        at = match version {
+            3 => be_u32(tag, 0).map_or(tag.len(), |len| len as usize + 4),
+            _ => tag.get(0..4).map_or(tag.len(), |len| syncsafe(len) as usize),
+        };
+    }
+
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    while at + header_len <= tag.len() {
+        let id = &tag[at..at + id_len];
+        if id[0] == 0 {
+            // Padding
+            break;
+        }
+        let frame_len = match version {
+            2 => (tag[at + 3] as usize) << 16 | (tag[at + 4] as usize) << 8 | tag[at + 5] as usize,
+            3 => be_u32(tag, at + 4).unwrap_or(0) as usize,
+            _ => syncsafe(&tag[at + 4..at + 8]) as usize,
+        };
+        let start = at + header_len;
+        let Some(frame) = tag.get(start..start + frame_len) else { break };
+        at = start + frame_len;
+
+        let format_flags = if version == 2 { 0 } else { tag[start - 1] };
+        let frame = match version {
+            2 => frame.to_vec(),
+            3 => {
+                // Compressed or encrypted frames can't be read
+                if format_flags & 0xC0 != 0 {
+                    continue;
+                }
+                frame.get(if format_flags & 0x20 != 0 { 1 } else { 0 }..).unwrap_or_default().to_vec()
+            }
+            _ => {
+                if format_flags & 0x0C != 0 {
+                    continue;
+                }
+                let skip = if format_flags & 0x40 != 0 { 1 } else { 0 } + if format_flags & 0x01 != 0 { 4 } else { 0 };
+                let frame = frame.get(skip..).unwrap_or_default();
+                if format_flags & 0x02 != 0 {
+                    remove_unsynchronisation(frame)
+                } else {
+                    frame.to_vec()
+                }
+            }
+        };
+        frames.push((String::from_utf8_lossy(id).into_owned(), frame));
+    }
+    frames
+}
+
+// A text frame: an encoding byte, then one or more NUL-separated strings
+fn id3_text(frame: &[u8]) -> Option<String> {
+    let (encoding, text) = frame.split_first()?;
+    let text = match encoding {
+        0 => latin1(text),
+        1 => match text {
+            [0xFF, 0xFE, rest @ ..] => utf16(rest, false),
+            [0xFE, 0xFF, rest @ ..] => utf16(rest, true),
+            _ => utf16(text, false),
+        },
+        2 => utf16(text, true),
+        _ => String::from_utf8_lossy(text.split(|byte| *byte == 0).next().unwrap_or_default()).into_owned(),
+    };
+    Some(text)
+}
+
+// APIC: an encoding byte, a MIME type, the picture type, a description and
+// then the image. ID3v2.2's PIC has a three-letter format instead of the MIME type
+fn id3_picture<'a>(id: &str, frame: &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (encoding, rest) = frame.split_first()?;
+    let rest = if id == "PIC" {
+        rest.get(3..)?
+    } else {
+        &rest[rest.iter().position(|byte| *byte == 0)? + 1..]
+    };
+    let (picture_type, rest) = rest.split_first()?;
+    let description_len = if matches!(encoding, 1 | 2) {
+        // UTF-16 text ends with a two-byte NUL on a character boundary
+        (0..rest.len() / 2).map(|i| i * 2).find(|at| rest[*at] == 0 && rest[at + 1] == 0)? + 2
+    } else {
+        rest.iter().position(|byte| *byte == 0)? + 1
+    };
+    Some((*picture_type, rest.get(description_len..)?))
+}
+
+// Undoes the 0x00 that writers insert after every 0xFF so tag data can't look like a frame sync
+fn remove_unsynchronisation(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == 0 && i > 0 && data[i - 1] == 0xFF {
+            continue;
+        }
+        output.push(*byte);
+    }
+    output
+}
+
+// Duration from the first MPEG audio frame in `data`: exact from a Xing/Info or
+// VBRI header when the encoder wrote one, otherwise assuming a constant bitrate
+fn mp3_duration(data: &[u8], audio_len: u64) -> Option<u64> {
+    let (at, frame) = (0..data.len().saturating_sub(4))
+        .filter(|at| data[*at] == 0xFF)
+        .find_map(|at| Some((at, MpegFrame::parse(data[at..at + 4].try_into().ok()?)?)))?;
+
+    let xing = at + 4 + frame.side_info_len;
+    let frame_count = match data.get(xing..xing + 4) {
+        Some(b"Xing") | Some(b"Info") if be_u32(data, xing + 4)? & 1 != 0 => be_u32(data, xing + 8),
+        _ if data.get(at + 36..at + 40) == Some(&b"VBRI"[..]) => be_u32(data, at + 50),
+        _ => None,
+    };
+
+    match frame_count {
+        Some(frames) => Some(frames as u64 * frame.samples_per_frame as u64 * 1000 / frame.sample_rate as u64),
+        // A kilobit per second is a bit per millisecond
+        None => Some(audio_len * 8 / frame.bitrate_kbps as u64),
+    }
+}
+
+struct MpegFrame {
+    bitrate_kbps: u32,
+    sample_rate: u32,
+    samples_per_frame: u32,
+    // Length of the Layer III side information that precedes a Xing header
+    side_info_len: usize,
+}
+
+impl MpegFrame {
+    fn parse(header: [u8; 4]) -> Option<Self> {
+        if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+        // 0 is MPEG 2.5, 2 is MPEG 2 and 3 is MPEG 1
+        let version = (header[1] >> 3) & 0x03;
+        // 1 is Layer III, 2 Layer II and 3 Layer I
+        let layer = (header[1] >> 1) & 0x03;
+        let bitrate_index = (header[2] >> 4) as usize;
+        let sample_rate_index = ((header[2] >> 2) & 0x03) as usize;
+        if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+            return None;
+        }
+        let mpeg1 = version == 3;
+        let mono = header[3] >> 6 == 3;
+
+        const BITRATES: [[u32; 14]; 5] = [
+            [32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+            [32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+            [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+            [32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+            [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        ];
+        let table = match (mpeg1, layer) {
+            (true, 3) => 0,
+            (true, 2) => 1,
+            (true, _) => 2,
+            (false, 3) => 3,
+            (false, _) => 4,
+        };
+
+        let sample_rate = [44100, 48000, 32000][sample_rate_index]
+            / match version {
+                3 => 1,
+                2 => 2,
+                _ => 4,
+            };
+
+        Some(Self {
+            bitrate_kbps: BITRATES[table][bitrate_index - 1],
+            sample_rate,
+            samples_per_frame: match layer {
+                3 => 384,
+                2 => 1152,
+                _ if mpeg1 => 1152,
+                _ => 576,
+            },
+            side_info_len: match (mpeg1, mono) {
+                (true, false) => 32,
+                (true, true) | (false, false) => 17,
+                (false, true) => 9,
+            },
+        })
+    }
+}
+
+fn read_flac(file: &mut File, metadata: &mut MediaMetadata) -> io::Result<()> {
+    for (block_type, offset, block_len) in flac_blocks(file)? {
+        match block_type {
+            // STREAMINFO: a 20-bit sample rate and 36-bit sample count starting at byte 10
+            0 => {
+                let info = read_range(file, offset, 18)?;
+                if info.len() == 18 {
+                    let sample_rate = (info[10] as u64) << 12 | (info[11] as u64) << 4 | (info[12] as u64) >> 4;
+                    let samples = ((info[13] & 0x0F) as u64) << 32 | be_u32(&info, 14).unwrap_or(0) as u64;
+                    if sample_rate > 0 && samples > 0 {
+                        metadata.duration_ms = Some(samples * 1000 / sample_rate);
+                    }
+                }
+            }
+            // VORBIS_COMMENT
+            4 if block_len <= MAX_HEADER_LEN => {
+                let comment = read_range(file, offset, block_len as usize)?;
+                read_vorbis_comment(&comment, metadata);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// FLAC is "fLaC" then metadata blocks, each with a last-block flag, a type and
+// a 24-bit length. Returns each block's type, body offset and length
+fn flac_blocks(file: &mut File) -> io::Result<Vec<(u8, u64, u64)>> {
+    let mut blocks = Vec::new();
+    let mut offset = 4;
+    loop {
+        let header = read_range(file, offset, 4)?;
+        if header.len() < 4 {
+            break;
+        }
+        let block_len = (header[1] as u64) << 16 | (header[2] as u64) << 8 | header[3] as u64;
+        blocks.push((header[0] & 0x7F, offset + 4, block_len));
+        offset += 4 + block_len;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    Ok(blocks)
+}
+
+// PICTURE: the picture type, length-prefixed MIME type and description, four
+// 32-bit size and colour fields and then the length-prefixed image
+fn flac_picture(block: &[u8]) -> Option<(u32, &[u8])> {
+    let picture_type = be_u32(block, 0)?;
+    let mut at = 8 + be_u32(block, 4)? as usize;
+    at += 4 + be_u32(block, at)? as usize + 16;
+    let len = be_u32(block, at)? as usize;
+    Some((picture_type, block.get(at + 4..at + 4 + len)?))
+}
+
+// Vorbis comments, shared by FLAC and Ogg: a vendor string and then KEY=value
+// strings, all prefixed with little-endian 32-bit lengths
+fn read_vorbis_comment(data: &[u8], metadata: &mut MediaMetadata) -> Option<()> {
+    let mut at = 4 + le_u32(data, 0)? as usize;
+    let count = le_u32(data, at)?;
+    at += 4;
+    for _ in 0..count {
+        let len = le_u32(data, at)? as usize;
+        let comment = String::from_utf8_lossy(data.get(at + 4..at + 4 + len)?);
+        at += 4 + len;
+        let Some((key, value)) = comment.split_once('=') else { continue };
+        match key.to_ascii_uppercase().as_str() {
+            "TITLE" => set_text(&mut metadata.title, value),
+            "ARTIST" => set_text(&mut metadata.artist, value),
+            "ALBUM" => set_text(&mut metadata.album, value),
+            "DATE" => set_text(&mut metadata.date, value),
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+// The first logical stream's identification and comment headers give the
+// codec, sample rate and tags; the granule position of its last page gives
+// the length in samples
+fn read_ogg(file: &mut File, size: u64, metadata: &mut MediaMetadata) -> io::Result<()> {
+    let head = read_range(file, 0, OGG_WINDOW_LEN as usize)?;
+    let Some((serial, packets)) = ogg_packets(&head, 2) else { return Ok(()) };
+    let identification = packets.first().map(Vec::as_slice).unwrap_or_default();
+    let comment = packets.get(1).map(Vec::as_slice).unwrap_or_default();
+
+    // Samples per second and samples to drop from the start
+    let clock = if identification.starts_with(b"\x01vorbis") {
+        if comment.starts_with(b"\x03vorbis") {
+            read_vorbis_comment(&comment[7..], metadata);
+        }
+        le_u32(identification, 12).map(|rate| (rate as u64, 0))
+    } else if identification.starts_with(b"OpusHead") {
+        if comment.starts_with(b"OpusTags") {
+            read_vorbis_comment(&comment[8..], metadata);
+        }
+        le_u16(identification, 10).map(|pre_skip| (48000, pre_skip as u64))
+    } else if identification.starts_with(b"\x80theora") {
+        if comment.starts_with(b"\x81theora") {
+            read_vorbis_comment(&comment[7..], metadata);
+        }
+        // Picture size as 24-bit big-endian values after the macroblock counts
+        let be_u24 = |at: usize| identification.get(at..at + 3).map(|b| (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32);
+        metadata.width = be_u24(14);
+        metadata.height = be_u24(17);
+        // Theora granule positions encode keyframe numbers, not a sample count
+        None
+    } else {
+        None
+    };
+
+    if let Some((rate @ 1.., pre_skip)) = clock {
+        let tail_start = size.saturating_sub(OGG_WINDOW_LEN);
+        let tail = read_range(file, tail_start, OGG_WINDOW_LEN as usize)?;
+        let last_granule = (0..tail.len().saturating_sub(27))
+            .rev()
+            .filter(|at| &tail[*at..*at + 4] == b"OggS" && le_u32(&tail, at + 14) == Some(serial))
+            .find_map(|at| le_u64(&tail, at + 6).filter(|granule| *granule != u64::MAX));
+        if let Some(granule) = last_granule {
+            metadata.duration_ms = Some((granule.saturating_sub(pre_skip) as u128 * 1000 / rate as u128) as u64);
+        }
+    }
+    Ok(())
+}
+
+// The serial number of the first logical stream in `data` and up to `limit`
+// of its packets, reassembled from the page segments
+fn ogg_packets(data: &[u8], limit: usize) -> Option<(u32, Vec<Vec<u8>>)> {
+    let mut serial = None;
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut at = 0;
+    while packets.len() < limit {
+        if data.get(at..at + 4)? != b"OggS" {
+            break;
+        }
+        let page_serial = le_u32(data, at + 14)?;
+        let segment_count = *data.get(at + 26)? as usize;
+        let segments = data.get(at + 27..at + 27 + segment_count)?;
+        let mut body = at + 27 + segment_count;
+        let first_stream = *serial.get_or_insert(page_serial) == page_serial;
+        for segment_len in segments {
+            let segment_len = *segment_len as usize;
+            if first_stream {
+                packet.extend_from_slice(data.get(body..body + segment_len)?);
+                // A segment shorter than 255 bytes ends the packet
+                if segment_len < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                }
+            }
+            body += segment_len;
+        }
+        at = body;
+    }
+    Some((serial?, packets))
+}
+
+// WAV, AVI and WebP are RIFF files: "RIFF", a length, a form type and then
+// chunks of a four-character ID, a little-endian length and an even-padded body
+fn read_riff(file: &mut File, size: u64, metadata: &mut MediaMetadata) -> io::Result<()> {
+    let mut byte_rate = None;
+    let mut data_len = None;
+    let mut offset = 12;
+    while offset + 8 <= size {
+        let header = read_range(file, offset, 12)?;
+        let (Some(id), Some(chunk_len)) = (header.get(0..4), le_u32(&header, 4)) else { break };
+        let chunk_len = chunk_len as u64;
+        let body_offset = offset + 8;
+        match id {
+            b"fmt " => {
+                let format = read_range(file, body_offset, 16)?;
+                byte_rate = le_u32(&format, 8).filter(|rate| *rate > 0);
+            }
+            b"data" => data_len = Some(chunk_len.min(size - body_offset)),
+            b"LIST" if chunk_len <= MAX_HEADER_LEN => {
+                // Only the header lists are read, not AVI's list of media data
+                let list_type = header.get(8..12).unwrap_or_default();
+                if list_type == b"hdrl" || list_type == b"INFO" {
+                    let list = read_range(file, body_offset, chunk_len as usize)?;
+                    read_riff_list(&list, metadata);
+                }
+            }
+            b"VP8X" | b"VP8 " | b"VP8L" => {
+                let bitstream = read_range(file, body_offset, 16)?;
+                read_webp_size(id, &bitstream, metadata);
+            }
+            _ => {}
+        }
+        offset = body_offset + chunk_len + (chunk_len & 1);
+    }
+
+    if let (Some(byte_rate), Some(data_len)) = (byte_rate, data_len) {
+        metadata.duration_ms = Some(data_len * 1000 / byte_rate as u64);
+    }
+    Ok(())
+}
+
+// Sub-chunks of a LIST chunk body, which starts with the list type
+fn read_riff_list(list: &[u8], metadata: &mut MediaMetadata) {
+    let mut at = 4;
+    while let (Some(id), Some(len)) = (list.get(at..at + 4), le_u32(list, at + 4)) {
+        let len = len as usize;
+        let Some(body) = list.get(at + 8..at + 8 + len) else { break };
+        let text = latin1(body);
+        match id {
+            // AVI main header: microseconds per frame, frame count and frame size
+            b"avih" => {
+                if let (Some(frame_us), Some(frames)) = (le_u32(body, 0), le_u32(body, 16)) {
+                    metadata.duration_ms = Some(frame_us as u64 * frames as u64 / 1000);
+                }
+                metadata.width = le_u32(body, 32);
+                metadata.height = le_u32(body, 36);
+            }
+            b"INAM" => set_text(&mut metadata.title, &text),
+            b"IART" => set_text(&mut metadata.artist, &text),
+            b"IPRD" => set_text(&mut metadata.album, &text),
+            b"ICRD" => set_text(&mut metadata.date, &text),
+            _ => {}
+        }
+        at += 8 + len + (len & 1);
+    }
+}
+
+fn read_webp_size(chunk: &[u8], bitstream: &[u8], metadata: &mut MediaMetadata) {
+    if metadata.width.is_some() {
+        return;
+    }
+    let size = match chunk {
+        // Extended format: canvas size minus one, 24 bits each
+        b"VP8X" => bitstream.get(4..10).map(|b| {
+            (
+                (b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16) + 1,
+                (b[3] as u32 | (b[4] as u32) << 8 | (b[5] as u32) << 16) + 1,
+            )
+        }),
+        // Lossy: 14-bit sizes after the keyframe start code
+        b"VP8 " if bitstream.get(3..6) == Some(&[0x9D, 0x01, 0x2A][..]) => {
+            Some((le_u16(bitstream, 6).unwrap_or(0) as u32 & 0x3FFF, le_u16(bitstream, 8).unwrap_or(0) as u32 & 0x3FFF))
+        }
+        // Lossless: a signature byte then 14-bit sizes minus one
+        b"VP8L" if bitstream.first() == Some(&0x2F) => le_u32(bitstream, 1).map(|bits| ((bits & 0x3FFF) + 1, (bits >> 14 & 0x3FFF) + 1)),
+        _ => None,
+    };
+    if let Some((width, height)) = size {
+        metadata.width = Some(width);
+        metadata.height = Some(height);
+    }
+}
+
+// Walks JPEG marker segments up to the start of the scan, picking up the frame
+// size from the SOF segment and tags from an EXIF APP1 segment. Returns the
+// EXIF orientation
+fn read_jpeg(data: &[u8], metadata: &mut MediaMetadata) -> Option<u16> {
+    let mut orientation = None;
+    let mut at = 2;
+    while at + 4 <= data.len() {
+        if data[at] != 0xFF {
+            break;
+        }
+        let marker = data[at + 1];
+        match marker {
+            // Fill byte
+            0xFF => {
+                at += 1;
+                continue;
+            }
+            // Markers without a length
+            0x01 | 0xD0..=0xD9 => {
+                at += 2;
+                continue;
+            }
+            // Start of scan: compressed data follows
+            0xDA => break,
+            _ => {}
+        }
+        let Some(len) = be_u16(data, at + 2).map(usize::from) else { break };
+        let Some(segment) = data.get(at + 4..at + 2 + len) else { break };
+        match marker {
+            0xE1 if segment.starts_with(b"Exif\0\0") => orientation = read_exif(&segment[6..], metadata),
+            // Start of frame, except DHT, JPG and DAC which share the range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                metadata.height = be_u16(segment, 1).map(u32::from);
+                metadata.width = be_u16(segment, 3).map(u32::from);
+            }
+            _ => {}
+        }
+        at += 2 + len;
+    }
+
+    // Orientations 5 to 8 are rotated a quarter turn, so they display transposed
+    if matches!(orientation, Some(5..=8)) {
+        std::mem::swap(&mut metadata.width, &mut metadata.height);
+    }
+    orientation
+}
+
+// EXIF is a TIFF structure: a byte order mark, then IFDs of 12-byte entries.
+// Returns the orientation tag
+fn read_exif(tiff: &[u8], metadata: &mut MediaMetadata) -> Option<u16> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| if little_endian { le_u16(tiff, at) } else { be_u16(tiff, at) };
+    let u32_at = |at: usize| if little_endian { le_u32(tiff, at) } else { be_u32(tiff, at) };
+    // ASCII values of more than 4 bytes are stored elsewhere at an offset
+    let ascii_at = |entry: usize| {
+        let count = u32_at(entry + 4)? as usize;
+        let start = if count <= 4 { entry + 8 } else { u32_at(entry + 8)? as usize };
+        Some(latin1(tiff.get(start..start + count)?))
+    };
+
+    let mut orientation = None;
+    let mut original_date = None;
+    let mut ifds = vec![u32_at(4)? as usize];
+    let mut visited = Vec::new();
+    while let Some(ifd) = ifds.pop() {
+        // A sub-IFD pointer back to an earlier IFD would otherwise loop forever
+        if visited.contains(&ifd) {
+            continue;
+        }
+        visited.push(ifd);
+        let count = u16_at(ifd)? as usize;
+        for entry in (0..count).map(|i| ifd + 2 + i * 12) {
+            match u16_at(entry)? {
+                0x010E => set_text(&mut metadata.title, &ascii_at(entry).unwrap_or_default()),
+                0x0112 => orientation = u16_at(entry + 8),
+                0x0132 => set_text(&mut metadata.date, &exif_date(&ascii_at(entry).unwrap_or_default())),
+                0x013B => set_text(&mut metadata.artist, &ascii_at(entry).unwrap_or_default()),
+                // Pointer to the EXIF sub-IFD
+                0x8769 => ifds.extend(u32_at(entry + 8).map(|offset| offset as usize)),
+                0x9003 => original_date = ascii_at(entry).map(|date| exif_date(&date)),
+                _ => {}
+            }
+        }
+    }
+
+    // When the picture was taken beats when the file was last written
+    if let Some(date) = original_date.filter(|date| !date.trim().is_empty()) {
+        metadata.date = Some(date);
+    }
+    orientation
+}
+
+// EXIF writes dates as "2021:06:04 18:30:00"
+fn exif_date(date: &str) -> String {
+    match date.split_once(' ') {
+        Some((day, time)) => format!("{} {}", day.replace(':', "-"), time),
+        None => date.to_string(),
+    }
+}
+
+// Fills `field` from tag text unless an earlier tag already did
+fn set_text(field: &mut Option<String>, text: &str) {
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if field.is_none() && !text.is_empty() {
+        *field = Some(text.to_string());
+    }
+}
+
+// ISO 8859-1 up to the first NUL
+fn latin1(data: &[u8]) -> String {
+    data.iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect()
+}
+
+// UTF-16 up to the first NUL
+fn utf16(data: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|pair| if big_endian { u16::from_be_bytes([pair[0], pair[1]]) } else { u16::from_le_bytes([pair[0], pair[1]]) })
+        .take_while(|unit| *unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+// ID3v2 sizes use 7 bits per byte so they never contain a frame sync
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, byte| value << 7 | (*byte & 0x7F) as u32)
+}
+
+fn read_range(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len.min(1024 * 1024));
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(len as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn le_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::with_temp_file;
+
+    fn with_file<T>(data: &[u8], read: impl FnOnce(&Path) -> io::Result<T>) -> T {
+        with_temp_file(data, read).unwrap()
+    }
+
+    fn extract_bytes(mime: &str, data: &[u8]) -> MediaMetadata {
+        with_file(data, |path| extract(path, &MediaType::from_mime(mime).unwrap()))
+    }
+
+    // Every prefix of `data`, and `data` with each of its first bytes set to
+    // 0xFF, must read without panicking
+    fn assert_survives_damage(mime: &str, data: &[u8]) {
+        for len in 0..data.len() {
+            if len < 1024 || len % 997 == 0 {
+                extract_bytes(mime, &data[..len]);
+            }
+        }
+        for at in 0..data.len().min(256) {
+            let mut damaged = data.to_vec();
+            damaged[at] = 0xFF;
+            extract_bytes(mime, &damaged);
+        }
+    }
+
+    fn mp4_box(box_type: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = (8 + body.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn mp4_tag(tag: &[u8; 4], value: &str) -> Vec<u8> {
+        let data = mp4_box(b"data", &[&[0, 0, 0, 1, 0, 0, 0, 0], value.as_bytes()].concat());
+        mp4_box(tag, &data)
+    }
+
+    fn tkhd(width: u32, height: u32) -> Vec<u8> {
+        let mut body = vec![0; 76];
+        body.extend_from_slice(&(width << 16).to_be_bytes());
+        body.extend_from_slice(&(height << 16).to_be_bytes());
+        mp4_box(b"tkhd", &body)
+    }
+
+    fn sample_mp4() -> Vec<u8> {
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&90_500u32.to_be_bytes());
+        let tags = [mp4_tag(b"\xA9nam", "Movie"), mp4_tag(b"\xA9ART", "Director"), mp4_tag(b"\xA9day", "2019")].concat();
+        let meta = mp4_box(b"meta", &[&[0, 0, 0, 0][..], &mp4_box(b"ilst", &tags)].concat());
+        let moov = [
+            mp4_box(b"mvhd", &mvhd),
+            // The audio track has no size, so the video track's is used
+            mp4_box(b"trak", &tkhd(0, 0)),
+            mp4_box(b"trak", &tkhd(1920, 1080)),
+            mp4_box(b"udta", &meta),
+        ]
+        .concat();
+        [mp4_box(b"ftyp", b"isom\0\0\0\0"), mp4_box(b"mdat", &[0; 64]), mp4_box(b"moov", &moov)].concat()
+    }
+
+    #[test]
+    fn mp4_duration_size_and_tags() {
+        let metadata = extract_bytes("video/mp4", &sample_mp4());
+        assert_eq!(metadata.duration_ms, Some(90_500));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert_eq!(metadata.title.as_deref(), Some("Movie"));
+        assert_eq!(metadata.artist.as_deref(), Some("Director"));
+        assert_eq!(metadata.date.as_deref(), Some("2019"));
+    }
+
+    #[test]
+    fn mp4_version_1_movie_header() {
+        let mut mvhd = vec![1, 0, 0, 0];
+        mvhd.extend_from_slice(&[0; 16]);
+        mvhd.extend_from_slice(&600u32.to_be_bytes());
+        mvhd.extend_from_slice(&(600u64 * 3 * 3600).to_be_bytes());
+        let mut metadata = MediaMetadata::default();
+        read_moov(&mp4_box(b"mvhd", &mvhd), &mut metadata);
+        assert_eq!(metadata.duration_ms, Some(3 * 3600 * 1000));
+    }
+
+    #[test]
+    fn mp4_zero_timescale_gives_no_duration() {
+        let mut metadata = MediaMetadata::default();
+        read_moov(&mp4_box(b"mvhd", &[0; 100]), &mut metadata);
+        assert_eq!(metadata.duration_ms, None);
+    }
+
+    #[test]
+    fn mp4_malformed_box_sizes() {
+        // Larger than the file, smaller than its header, and a 64-bit size smaller than its header
+        for header in [&[0xFF, 0xFF, 0xFF, 0xFF][..], &[0, 0, 0, 4], &[0, 0, 0, 1]] {
+            let data = [header, b"moov", &[0; 8], &sample_mp4()].concat();
+            assert!(extract_bytes("video/mp4", &data).is_empty());
+        }
+        assert_survives_damage("video/mp4", &sample_mp4());
+    }
+
+    #[test]
+    fn heif_takes_the_largest_image_size() {
+        let ispe = |width: u32, height: u32| mp4_box(b"ispe", &[&[0; 4][..], &width.to_be_bytes(), &height.to_be_bytes()].concat());
+        let ipco = mp4_box(b"ipco", &[ispe(320, 240), ispe(4032, 3024)].concat());
+        let meta = mp4_box(b"meta", &[&[0; 4][..], &mp4_box(b"iprp", &ipco)].concat());
+        let data = [mp4_box(b"ftyp", b"heic\0\0\0\0"), meta].concat();
+        let metadata = extract_bytes("image/heic", &data);
+        assert_eq!((metadata.width, metadata.height), (Some(4032), Some(3024)));
+        assert_survives_damage("image/heic", &data);
+    }
+
+    fn ebml(id: u64, body: &[u8]) -> Vec<u8> {
+        let id = id.to_be_bytes();
+        let mut data = id[id.iter().position(|byte| *byte != 0).unwrap()..].to_vec();
+        if body.len() < 0x7F {
+            data.push(0x80 | body.len() as u8);
+        } else {
+            data.push(0x01);
+            data.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        }
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn simple_tag(name: &str, value: &str) -> Vec<u8> {
+        ebml(EBML_SIMPLE_TAG, &[ebml(EBML_TAG_NAME, name.as_bytes()), ebml(EBML_TAG_STRING, value.as_bytes())].concat())
+    }
+
+    fn sample_matroska() -> Vec<u8> {
+        let info = [
+            ebml(EBML_TIMECODE_SCALE, &1_000_000u64.to_be_bytes()),
+            ebml(EBML_DURATION, &12_345.0f64.to_be_bytes()),
+            ebml(EBML_TITLE, b"Clip"),
+            ebml(EBML_DATE_UTC, &(86_400u64 * 1_000_000_000).to_be_bytes()),
+        ]
+        .concat();
+        let video = [ebml(EBML_PIXEL_WIDTH, &[0x05, 0x00]), ebml(EBML_PIXEL_HEIGHT, &[0x02, 0xD0])].concat();
+        let tracks = ebml(EBML_TRACK_ENTRY, &ebml(EBML_VIDEO, &video));
+        let segment = [
+            ebml(EBML_INFO, &info),
+            ebml(EBML_TRACKS, &tracks),
+            ebml(EBML_TAGS, &ebml(EBML_TAG, &simple_tag("artist", "Someone"))),
+            ebml(EBML_CLUSTER, &[0; 200]),
+            ebml(EBML_TAGS, &ebml(EBML_TAG, &simple_tag("ALBUM", "After the media"))),
+        ]
+        .concat();
+        [ebml(0x1A45_DFA3, &ebml(0x4282, b"matroska")), ebml(EBML_SEGMENT, &segment)].concat()
+    }
+
+    #[test]
+    fn matroska_duration_size_and_tags() {
+        let metadata = extract_bytes("video/x-matroska", &sample_matroska());
+        assert_eq!(metadata.duration_ms, Some(12_345));
+        assert_eq!((metadata.width, metadata.height), (Some(1280), Some(720)));
+        assert_eq!(metadata.title.as_deref(), Some("Clip"));
+        assert_eq!(metadata.artist.as_deref(), Some("Someone"));
+        assert_eq!(metadata.date.as_deref(), Some("2001-01-02 00:00:00"));
+        // Only what comes before the first cluster is read
+        assert_eq!(metadata.album, None);
+    }
+
+    #[test]
+    fn matroska_segment_of_unknown_size() {
+        let info = [ebml(EBML_TIMECODE_SCALE, &1000u64.to_be_bytes()), ebml(EBML_DURATION, &5_000_000.0f32.to_be_bytes())].concat();
+        let data = [&[0x18, 0x53, 0x80, 0x67, 0xFF][..], &ebml(EBML_INFO, &info)].concat();
+        let mut metadata = MediaMetadata::default();
+        read_matroska(&data, &mut metadata);
+        assert_eq!(metadata.duration_ms, Some(5000));
+    }
+
+    #[test]
+    fn matroska_malformed_elements() {
+        for duration in [&[0x40, 0x49, 0x0F][..], &f64::NAN.to_be_bytes(), &(-1.0f64).to_be_bytes()] {
+            let mut metadata = MediaMetadata::default();
+            read_matroska(&ebml(EBML_SEGMENT, &ebml(EBML_INFO, &ebml(EBML_DURATION, duration))), &mut metadata);
+            assert_eq!(metadata.duration_ms, None);
+        }
+        // An ID starting with a zero byte ends the element list
+        let mut metadata = MediaMetadata::default();
+        read_matroska(&[&[0x00][..], &sample_matroska()].concat(), &mut metadata);
+        assert!(metadata.is_empty());
+
+        for len in 0..sample_matroska().len() {
+            read_matroska(&sample_matroska()[..len], &mut MediaMetadata::default());
+        }
+        assert_survives_damage("video/webm", &sample_matroska());
+    }
+
+    fn id3v2_tag(version: u8, flags: u8, frames: &[u8]) -> Vec<u8> {
+        let len = frames.len() as u32;
+        let size = [(len >> 21) as u8 & 0x7F, (len >> 14) as u8 & 0x7F, (len >> 7) as u8 & 0x7F, len as u8 & 0x7F];
+        [b"ID3", &[version, 0, flags][..], &size, frames].concat()
+    }
+
+    fn id3v23_frame(id: &str, body: &[u8]) -> Vec<u8> {
+        [id.as_bytes(), &(body.len() as u32).to_be_bytes(), &[0, 0], body].concat()
+    }
+
+    fn id3v1_tag(title: &str, artist: &str, album: &str, year: &str) -> Vec<u8> {
+        let field = |text: &str, len: usize| {
+            let mut field = text.as_bytes().to_vec();
+            field.resize(len, 0);
+            field
+        };
+        [&b"TAG"[..], &field(title, 30), &field(artist, 30), &field(album, 30), &field(year, 4), &[0; 31]].concat()
+    }
+
+    // An MPEG-1 Layer III frame header: 128 kbit/s, 44.1 kHz, stereo
+    const MPEG_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+
+    // `len` bytes of audio starting with one frame whose side information
+    // is followed by `info`, such as a Xing header
+    fn mpeg_audio(info: &[u8], len: usize) -> Vec<u8> {
+        let mut audio = [&MPEG_HEADER[..], &[0; 32], info].concat();
+        audio.resize(len, 0);
+        audio
+    }
+
+    fn sample_mp3() -> Vec<u8> {
+        let frames = [
+            id3v23_frame("TIT2", b"\0Title"),
+            id3v23_frame("TPE1", &[1, 0xFF, 0xFE, b'A', 0, b'r', 0, b't', 0, 0, 0]),
+            id3v23_frame("TALB", b"\0Album"),
+            id3v23_frame("TYER", b"\x002020"),
+            vec![0; 32],
+        ]
+        .concat();
+        [id3v2_tag(3, 0, &frames), mpeg_audio(&[], 16_000), id3v1_tag("Old title", "Old artist", "", "1999")].concat()
+    }
+
+    #[test]
+    fn mp3_id3v2_tags_take_precedence_and_cbr_duration() {
+        let metadata = extract_bytes("audio/mpeg", &sample_mp3());
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Art"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.date.as_deref(), Some("2020"));
+        // 16,000 bytes between the tags at 128 kbit/s
+        assert_eq!(metadata.duration_ms, Some(1000));
+    }
+
+    #[test]
+    fn mp3_id3v1_only() {
+        let data = [mpeg_audio(&[], 8000), id3v1_tag("Song", "Band", "Record", "1987")].concat();
+        let metadata = extract_bytes("audio/mpeg", &data);
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(metadata.artist.as_deref(), Some("Band"));
+        assert_eq!(metadata.album.as_deref(), Some("Record"));
+        assert_eq!(metadata.date.as_deref(), Some("1987"));
+        assert_eq!(metadata.duration_ms, Some(500));
+    }
+
+    #[test]
+    fn mp3_xing_and_vbri_frame_counts() {
+        let xing = [&b"Xing"[..], &1u32.to_be_bytes(), &1000u32.to_be_bytes()].concat();
+        assert_eq!(extract_bytes("audio/mpeg", &mpeg_audio(&xing, 4000)).duration_ms, Some(1000 * 1152 * 1000 / 44100));
+
+        // Without the frame count flag the bitrate is used
+        let xing = [&b"Info"[..], &0u32.to_be_bytes(), &1000u32.to_be_bytes()].concat();
+        assert_eq!(extract_bytes("audio/mpeg", &mpeg_audio(&xing, 4000)).duration_ms, Some(250));
+
+        let vbri = [&b"VBRI"[..], &[0; 10], &500u32.to_be_bytes()].concat();
+        assert_eq!(extract_bytes("audio/mpeg", &mpeg_audio(&vbri, 4000)).duration_ms, Some(500 * 1152 * 1000 / 44100));
+    }
+
+    #[test]
+    fn mpeg_frame_headers() {
+        let frame = MpegFrame::parse(MPEG_HEADER).unwrap();
+        assert_eq!((frame.bitrate_kbps, frame.sample_rate, frame.samples_per_frame, frame.side_info_len), (128, 44100, 1152, 32));
+        // MPEG-2 Layer III, 64 kbit/s, 22.05 kHz, mono
+        let frame = MpegFrame::parse([0xFF, 0xF3, 0x80, 0xC0]).unwrap();
+        assert_eq!((frame.bitrate_kbps, frame.sample_rate, frame.samples_per_frame, frame.side_info_len), (64, 22050, 576, 9));
+        // Reserved version, reserved layer, free and bad bitrates, reserved sample rate
+        for header in [[0xFF, 0xEB, 0x90, 0], [0xFF, 0xF9, 0x90, 0], [0xFF, 0xFB, 0x00, 0], [0xFF, 0xFB, 0xF0, 0], [0xFF, 0xFB, 0x9C, 0]] {
+            assert!(MpegFrame::parse(header).is_none(), "{:02X?}", header);
+        }
+    }
+
+    #[test]
+    fn id3v22_and_id3v24_frames() {
+        let frames = [&b"TT2"[..], &[0, 0, 6], b"\0Title", b"TP1", &[0, 0, 7], b"\x01\xFE\xFF\0A\0B"].concat();
+        let metadata = extract_bytes("audio/mpeg", &id3v2_tag(2, 0, &frames));
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("AB"));
+
+        let frames = [
+            // Data length indicator, then UTF-8
+            &b"TIT2"[..], &[0, 0, 0, 12, 0, 0x01], &[0, 0, 0, 7], b"\x03T\xC3\xADtulo",
+            // Unsynchronised: a zero byte follows each 0xFF
+            b"TPE1", &[0, 0, 0, 5, 0, 0x02], b"\0A\xFF\0B",
+            // Compressed frames can't be read
+            b"TALB", &[0, 0, 0, 6, 0, 0x08], b"\0Album",
+        ]
+        .concat();
+        let metadata = extract_bytes("audio/mpeg", &id3v2_tag(4, 0, &frames));
+        assert_eq!(metadata.title.as_deref(), Some("Título"));
+        assert_eq!(metadata.artist.as_deref(), Some("A\u{FF}B"));
+        assert_eq!(metadata.album, None);
+    }
+
+    #[test]
+    fn id3v23_unsynchronisation_and_extended_header() {
+        let frames = [&[0, 0, 0, 6][..], &[0; 6], &id3v23_frame("TIT2", b"\0x\xFFy")].concat();
+        let unsynchronised: Vec<u8> = frames.iter().flat_map(|byte| if *byte == 0xFF { vec![0xFF, 0] } else { vec![*byte] }).collect();
+        let metadata = extract_bytes("audio/mpeg", &id3v2_tag(3, 0xC0, &unsynchronised));
+        assert_eq!(metadata.title.as_deref(), Some("x\u{FF}y"));
+    }
+
+    #[test]
+    fn mp3_malformed_tags() {
+        // A frame longer than its tag, and a tag longer than the file
+        let frames = [&b"TIT2"[..], &u32::MAX.to_be_bytes(), &[0, 0], b"\0Title"].concat();
+        assert_eq!(extract_bytes("audio/mpeg", &id3v2_tag(3, 0, &frames)).title, None);
+        let mut data = id3v2_tag(3, 0, &id3v23_frame("TIT2", b"\0Title"));
+        data[6..10].copy_from_slice(&[0x7F; 4]);
+        assert_eq!(extract_bytes("audio/mpeg", &data).title.as_deref(), Some("Title"));
+
+        assert_eq!(id3_text(&[]), None);
+        assert_eq!(id3_picture("APIC", b"\0image/png"), None);
+        assert_survives_damage("audio/mpeg", &sample_mp3());
+    }
+
+    #[test]
+    fn mp3_cover_art_prefers_the_front_cover() {
+        let frames = [
+            id3v23_frame("APIC", b"\0image/png\0\x00other\0BACK"),
+            id3v23_frame("APIC", b"\x01image/png\0\x03\xFF\xFEd\0\0\0FRONT"),
+        ]
+        .concat();
+        let data = [id3v2_tag(3, 0, &frames), mpeg_audio(&[], 1000)].concat();
+        let cover = with_file(&data, |path| cover_art(path, &MediaType::from_mime("audio/mpeg").unwrap()));
+        assert_eq!(cover.as_deref(), Some(&b"FRONT"[..]));
+    }
+
+    fn vorbis_comment(comments: &[&str]) -> Vec<u8> {
+        let mut data = [&4u32.to_le_bytes()[..], b"test", &(comments.len() as u32).to_le_bytes()].concat();
+        for comment in comments {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data
+    }
+
+    fn flac_block(block_type: u8, body: &[u8]) -> Vec<u8> {
+        let len = (body.len() as u32).to_be_bytes();
+        [&[block_type, len[1], len[2], len[3]][..], body].concat()
+    }
+
+    fn streaminfo(sample_rate: u32, samples: u64) -> Vec<u8> {
+        let mut info = vec![0; 34];
+        info[10] = (sample_rate >> 12) as u8;
+        info[11] = (sample_rate >> 4) as u8;
+        // The low bits hold the channel count and bits per sample
+        info[12] = (sample_rate << 4) as u8 | 0x02;
+        info[13] = 0xF0 | (samples >> 32) as u8;
+        info[14..18].copy_from_slice(&(samples as u32).to_be_bytes());
+        info
+    }
+
+    fn flac_picture_block(picture_type: u32, image: &[u8]) -> Vec<u8> {
+        let mime = b"image/jpeg";
+        [&picture_type.to_be_bytes()[..], &(mime.len() as u32).to_be_bytes(), mime, &0u32.to_be_bytes(), &[0; 16], &(image.len() as u32).to_be_bytes(), image].concat()
+    }
+
+    fn sample_flac() -> Vec<u8> {
+        let comments = vorbis_comment(&["TITLE=Song", "artist=Band", "ALBUM=Record", "DATE=1999", "NOEQUALS", "GENRE=Rock"]);
+        [
+            &b"fLaC"[..],
+            &flac_block(0, &streaminfo(44100, 44100 * 61)),
+            &flac_block(6, &flac_picture_block(0, b"BACK")),
+            &flac_block(6, &flac_picture_block(FRONT_COVER, b"FRONT")),
+            &flac_block(0x84, &comments),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn flac_duration_and_vorbis_comments() {
+        let metadata = extract_bytes("audio/flac", &sample_flac());
+        assert_eq!(metadata.duration_ms, Some(61_000));
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+        assert_eq!(metadata.artist.as_deref(), Some("Band"));
+        assert_eq!(metadata.album.as_deref(), Some("Record"));
+        assert_eq!(metadata.date.as_deref(), Some("1999"));
+
+        let cover = with_file(&sample_flac(), |path| cover_art(path, &MediaType::from_mime("audio/flac").unwrap()));
+        assert_eq!(cover.as_deref(), Some(&b"FRONT"[..]));
+    }
+
+    #[test]
+    fn flac_36_bit_sample_count() {
+        let data = [&b"fLaC"[..], &flac_block(0x80, &streaminfo(48000, (1 << 32) + 48000))].concat();
+        assert_eq!(extract_bytes("audio/flac", &data).duration_ms, Some(((1u64 << 32) + 48000) * 1000 / 48000));
+    }
+
+    #[test]
+    fn flac_malformed_blocks() {
+        // No sample rate, and a comment count larger than the comments
+        let mut comments = vorbis_comment(&["TITLE=Song"]);
+        comments[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let data = [&b"fLaC"[..], &flac_block(0, &streaminfo(0, 1000)), &flac_block(0x84, &comments)].concat();
+        let metadata = extract_bytes("audio/flac", &data);
+        assert_eq!(metadata.duration_ms, None);
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+
+        assert_eq!(flac_picture(&[0, 0, 0, 3, 0xFF, 0xFF, 0xFF, 0xFF]), None);
+        assert_survives_damage("audio/flac", &sample_flac());
+    }
+
+    fn ogg_page(serial: u32, granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut segments = Vec::new();
+        for packet in packets {
+            segments.extend(std::iter::repeat_n(255, packet.len() / 255));
+            segments.push((packet.len() % 255) as u8);
+        }
+        [&b"OggS"[..], &[0, 0], &granule.to_le_bytes(), &serial.to_le_bytes(), &[0; 8], &[segments.len() as u8], &segments, &packets.concat()].concat()
+    }
+
+    fn sample_vorbis() -> Vec<u8> {
+        let identification = [&b"\x01vorbis"[..], &[0; 4], &[2], &44100u32.to_le_bytes(), &[0; 14]].concat();
+        let description = format!("DESCRIPTION={}", "x".repeat(300));
+        let comment = [&b"\x03vorbis"[..], &vorbis_comment(&["TITLE=Tune", "ARTIST=Player", &description]), &[1]].concat();
+        [
+            ogg_page(7, 0, &[&identification]),
+            // Another logical stream is interleaved with the first
+            ogg_page(9, 0, &[b"\x01vorbis other stream"]),
+            ogg_page(7, 0, &[&comment]),
+            ogg_page(7, 44100 * 4, &[&[0; 300]]),
+            ogg_page(7, 44100 * 5, &[&[0; 300]]),
+            ogg_page(9, 44100 * 60, &[&[0; 300]]),
+            // A page that ends no packet has no granule position
+            ogg_page(7, u64::MAX, &[&[0; 255]]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn ogg_vorbis_duration_and_comments() {
+        let metadata = extract_bytes("audio/ogg", &sample_vorbis());
+        assert_eq!(metadata.duration_ms, Some(5000));
+        assert_eq!(metadata.title.as_deref(), Some("Tune"));
+        assert_eq!(metadata.artist.as_deref(), Some("Player"));
+    }
+
+    #[test]
+    fn ogg_opus_drops_the_pre_skip() {
+        let head = [&b"OpusHead"[..], &[1, 2], &312u16.to_le_bytes(), &48000u32.to_le_bytes(), &[0; 3]].concat();
+        let tags = [&b"OpusTags"[..], &vorbis_comment(&["album=Live"])].concat();
+        let data = [ogg_page(1, 0, &[&head]), ogg_page(1, 0, &[&tags]), ogg_page(1, 48000 * 3 + 312, &[&[0; 100]])].concat();
+        let metadata = extract_bytes("audio/ogg", &data);
+        assert_eq!(metadata.duration_ms, Some(3000));
+        assert_eq!(metadata.album.as_deref(), Some("Live"));
+    }
+
+    #[test]
+    fn ogg_theora_picture_size() {
+        let identification = [&b"\x80theora"[..], &[3, 2, 1], &80u16.to_be_bytes(), &45u16.to_be_bytes(), &[0x00, 0x05, 0x00], &[0x00, 0x02, 0xD0], &[0; 22]].concat();
+        let comment = [&b"\x81theora"[..], &vorbis_comment(&["TITLE=Film"])].concat();
+        let data = [ogg_page(3, 0, &[&identification]), ogg_page(3, 0, &[&comment]), ogg_page(3, 1 << 20, &[&[0; 10]])].concat();
+        let metadata = extract_bytes("video/ogg", &data);
+        assert_eq!((metadata.width, metadata.height), (Some(1280), Some(720)));
+        assert_eq!(metadata.title.as_deref(), Some("Film"));
+        assert_eq!(metadata.duration_ms, None);
+    }
+
+    #[test]
+    fn ogg_malformed_pages() {
+        assert!(extract_bytes("audio/ogg", b"OggS").is_empty());
+        // A zero sample rate
+        let identification = [&b"\x01vorbis"[..], &[0; 4], &[2], &0u32.to_le_bytes(), &[0; 14]].concat();
+        let data = [ogg_page(7, 0, &[&identification]), ogg_page(7, 0, &[b"\x03vorbis"]), ogg_page(7, 1000, &[&[0; 10]])].concat();
+        assert_eq!(extract_bytes("audio/ogg", &data).duration_ms, None);
+        assert_survives_damage("audio/ogg", &sample_vorbis());
+    }
+
+    fn riff_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = [&id[..], &(body.len() as u32).to_le_bytes(), body].concat();
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn riff(form: &[u8; 4], chunks: &[u8]) -> Vec<u8> {
+        [&b"RIFF"[..], &(4 + chunks.len() as u32).to_le_bytes(), form, chunks].concat()
+    }
+
+    fn sample_wav() -> Vec<u8> {
+        // Mono 8-bit at 8 kHz: 8,000 bytes a second
+        let format = [&1u16.to_le_bytes()[..], &1u16.to_le_bytes(), &8000u32.to_le_bytes(), &8000u32.to_le_bytes(), &1u16.to_le_bytes(), &8u16.to_le_bytes()].concat();
+        let info = [&b"INFO"[..], &riff_chunk(b"INAM", b"Odd"), &riff_chunk(b"IART", b"Singer\0"), &riff_chunk(b"ICRD", b"2005")].concat();
+        riff(b"WAVE", &[riff_chunk(b"fmt ", &format), riff_chunk(b"LIST", &info), riff_chunk(b"data", &[0x80; 12_000])].concat())
+    }
+
+    #[test]
+    fn wav_duration_and_info_tags() {
+        let metadata = extract_bytes("audio/wav", &sample_wav());
+        assert_eq!(metadata.duration_ms, Some(1500));
+        assert_eq!(metadata.title.as_deref(), Some("Odd"));
+        assert_eq!(metadata.artist.as_deref(), Some("Singer"));
+        assert_eq!(metadata.date.as_deref(), Some("2005"));
+    }
+
+    #[test]
+    fn wav_data_chunk_cut_short() {
+        let wav = sample_wav();
+        let metadata = extract_bytes("audio/wav", &wav[..wav.len() - 8000]);
+        assert_eq!(metadata.duration_ms, Some(500));
+        assert_survives_damage("audio/wav", &wav);
+    }
+
+    #[test]
+    fn avi_main_header() {
+        let mut avih = vec![0; 56];
+        avih[0..4].copy_from_slice(&40_000u32.to_le_bytes());
+        avih[16..20].copy_from_slice(&250u32.to_le_bytes());
+        avih[32..36].copy_from_slice(&640u32.to_le_bytes());
+        avih[36..40].copy_from_slice(&480u32.to_le_bytes());
+        let data = riff(b"AVI ", &riff_chunk(b"LIST", &[&b"hdrl"[..], &riff_chunk(b"avih", &avih)].concat()));
+        let metadata = extract_bytes("video/x-msvideo", &data);
+        assert_eq!(metadata.duration_ms, Some(10_000));
+        assert_eq!((metadata.width, metadata.height), (Some(640), Some(480)));
+        assert_survives_damage("video/x-msvideo", &data);
+    }
+
+    #[test]
+    fn webp_sizes() {
+        let extended = riff(b"WEBP", &riff_chunk(b"VP8X", &[0, 0, 0, 0, 0x1F, 0x03, 0x00, 0x57, 0x02, 0x00]));
+        let lossy = riff(b"WEBP", &riff_chunk(b"VP8 ", &[&[0x30, 0x01, 0x00, 0x9D, 0x01, 0x2A][..], &400u16.to_le_bytes(), &300u16.to_le_bytes(), &[0; 8]].concat()));
+        let lossless = riff(b"WEBP", &riff_chunk(b"VP8L", &[&[0x2F][..], &(199u32 | 99 << 14).to_le_bytes(), &[0; 11]].concat()));
+        for (data, size) in [(&extended, (800, 600)), (&lossy, (400, 300)), (&lossless, (200, 100))] {
+            let metadata = extract_bytes("image/webp", data);
+            assert_eq!((metadata.width, metadata.height), (Some(size.0), Some(size.1)));
+            assert_survives_damage("image/webp", data);
+        }
+        // A lossy bitstream without its start code
+        let bad = riff(b"WEBP", &riff_chunk(b"VP8 ", &[0; 16]));
+        assert_eq!(extract_bytes("image/webp", &bad).width, None);
+    }
+
+    fn jpeg_segment(marker: u8, body: &[u8]) -> Vec<u8> {
+        [&[0xFF, marker][..], &(body.len() as u16 + 2).to_be_bytes(), body].concat()
+    }
+
+    fn start_of_frame(width: u16, height: u16) -> Vec<u8> {
+        jpeg_segment(0xC0, &[&[8][..], &height.to_be_bytes(), &width.to_be_bytes(), &[1, 1, 0x11, 0]].concat())
+    }
+
+    // Little-endian EXIF: IFD0 with a title, an orientation and a pointer to an
+    // EXIF sub-IFD holding the capture date
+    fn sample_exif(orientation: u16) -> Vec<u8> {
+        let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32| {
+            tiff.extend_from_slice(&[tag.to_le_bytes(), kind.to_le_bytes()].concat());
+            tiff.extend_from_slice(&[count.to_le_bytes(), value.to_le_bytes()].concat());
+        };
+        let mut tiff = b"II\x2A\x00\x08\x00\x00\x00".to_vec();
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        entry(&mut tiff, 0x010E, 2, 12, 68);
+        entry(&mut tiff, 0x0112, 3, 1, orientation as u32);
+        entry(&mut tiff, 0x8769, 4, 1, 50);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        entry(&mut tiff, 0x9003, 2, 20, 80);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"Photo title\0");
+        tiff.extend_from_slice(b"2021:06:04 18:30:00\0");
+        tiff
+    }
+
+    fn sample_jpeg(orientation: u16) -> Vec<u8> {
+        [
+            &[0xFF, 0xD8][..],
+            &jpeg_segment(0xE1, &[&b"Exif\0\0"[..], &sample_exif(orientation)].concat()),
+            // A Huffman table, in the range of the frame markers
+            &jpeg_segment(0xC4, &[0; 20]),
+            &[0xFF],
+            &start_of_frame(1920, 1080),
+            &jpeg_segment(0xDA, &[0; 10]),
+            &start_of_frame(1, 1),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn jpeg_size_and_exif_tags() {
+        let metadata = extract_bytes("image/jpeg", &sample_jpeg(1));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert_eq!(metadata.title.as_deref(), Some("Photo title"));
+        assert_eq!(metadata.date.as_deref(), Some("2021-06-04 18:30:00"));
+        assert_eq!(with_file(&sample_jpeg(1), exif_orientation), Some(1));
+    }
+
+    #[test]
+    fn jpeg_rotated_a_quarter_turn_swaps_its_size() {
+        let mut metadata = MediaMetadata::default();
+        assert_eq!(read_jpeg(&sample_jpeg(6), &mut metadata), Some(6));
+        assert_eq!((metadata.width, metadata.height), (Some(1080), Some(1920)));
+    }
+
+    #[test]
+    fn big_endian_exif() {
+        let mut tiff = b"MM\x00\x2A\x00\x00\x00\x08\x00\x02".to_vec();
+        tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 3, 0, 0]);
+        tiff.extend_from_slice(&[0x01, 0x32, 0, 2, 0, 0, 0, 20, 0, 0, 0, 38]);
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(b"2020:01:02 03:04:05\0");
+        let data = [&[0xFF, 0xD8][..], &jpeg_segment(0xE1, &[&b"Exif\0\0"[..], &tiff].concat())].concat();
+        let mut metadata = MediaMetadata::default();
+        assert_eq!(read_jpeg(&data, &mut metadata), Some(3));
+        assert_eq!(metadata.date.as_deref(), Some("2020-01-02 03:04:05"));
+    }
+
+    #[test]
+    fn jpeg_malformed_segments() {
+        // An IFD whose sub-IFD pointer leads back to itself
+        let tiff = [&b"II\x2A\x00\x08\x00\x00\x00\x01\x00"[..], &[0x69, 0x87, 4, 0, 1, 0, 0, 0, 8, 0, 0, 0], &[0; 4]].concat();
+        let data = [&[0xFF, 0xD8][..], &jpeg_segment(0xE1, &[&b"Exif\0\0"[..], &tiff].concat())].concat();
+        assert_eq!(read_jpeg(&data, &mut MediaMetadata::default()), None);
+
+        // Segment lengths too short to cover their own length field
+        for len in [0, 1] {
+            let data = [&[0xFF, 0xD8, 0xFF, 0xC0, 0, len][..], &start_of_frame(640, 480)].concat();
+            let mut metadata = MediaMetadata::default();
+            read_jpeg(&data, &mut metadata);
+            assert_eq!(metadata.width, None);
+        }
+
+        assert_eq!(read_exif(b"XX\x2A\x00", &mut MediaMetadata::default()), None);
+        assert_survives_damage("image/jpeg", &sample_jpeg(6));
+    }
+
+    #[test]
+    fn png_gif_and_bmp_sizes() {
+        let png = [&b"\x89PNG\r\n\x1A\n\x00\x00\x00\x0DIHDR"[..], &640u32.to_be_bytes(), &480u32.to_be_bytes(), &[8, 6, 0, 0, 0], &[0; 4]].concat();
+        let gif = [&b"GIF89a"[..], &320u16.to_le_bytes(), &200u16.to_le_bytes(), &[0; 3]].concat();
+        // A top-down bitmap has a negative height
+        let bmp = [&b"BM"[..], &[0; 12], &40u32.to_le_bytes(), &1024i32.to_le_bytes(), &(-768i32).to_le_bytes(), &[0; 28]].concat();
+        let os2_bmp = [&b"BM"[..], &[0; 12], &12u32.to_le_bytes(), &64u16.to_le_bytes(), &32u16.to_le_bytes(), &[0; 4]].concat();
+        for (mime, data, size) in [("image/png", &png, (640, 480)), ("image/gif", &gif, (320, 200)), ("image/bmp", &bmp, (1024, 768)), ("image/bmp", &os2_bmp, (64, 32))] {
+            let metadata = extract_bytes(mime, data);
+            assert_eq!((metadata.width, metadata.height), (Some(size.0), Some(size.1)), "{}", mime);
+            assert_survives_damage(mime, data);
+        }
+        // A chunk other than IHDR first
+        let mut not_ihdr = png.clone();
+        not_ihdr[12..16].copy_from_slice(b"IDAT");
+        assert_eq!(extract_bytes("image/png", &not_ihdr).width, None);
+    }
+}
@@ -0,0 +1,50 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+/// A download that stopped part way, recorded in a file next to the data it
+/// belongs to so a later connection, even from a new process, can ask the
+/// server for just the rest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PartialDownload {
+    /// Total size and content hash of the file being downloaded; a resume is
+    /// only valid while the server still has this exact file
+    pub size: u64,
+    pub hash: String,
+    /// Bytes from the start of the file known to be written to disk
+    pub verified: u64,
+}
+
+impl PartialDownload {
+    /// The record for the download into `data_path`, if there is one.
+    pub fn load(data_path: &Path) -> Option<Self> {
+        let record = fs::read(record_path(data_path)).ok()?;
+        serde_json::from_slice(&record).ok()
+    }
+
+    pub fn save(&self, data_path: &Path) -> io::Result<()> {
+        let record = serde_json::to_vec(self).map_err(io::Error::other)?;
+        // Replaced in one step so a crash mid-write can't leave a torn record
+        let partial = record_path(data_path).with_extension("partial.tmp");
+        fs::write(&partial, record)?;
+        fs::rename(&partial, record_path(data_path))
+    }
+
+    /// Forgets the download into `data_path`; the data itself is left alone.
+    pub fn discard(data_path: &Path) {
+        let _ = fs::remove_file(record_path(data_path));
+    }
+
+    /// Whether `path` is a download record rather than downloaded data.
+    pub fn is_record(path: &Path) -> bool {
+        path.extension().is_some_and(|extension| extension == "partial" || extension == "tmp")
+    }
+}
+
+fn record_path(data_path: &Path) -> PathBuf {
+    let mut path = OsString::from(data_path.as_os_str());
+    path.push(".partial");
+    PathBuf::from(path)
+}
@@ -0,0 +1,614 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use crate::clock;
+use crate::media_type::MediaType;
+
+pub type PlayerResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// A player shared between the message loop and the playback threads.
+pub type SharedPlayer = Arc<Mutex<Box<dyn Player>>>;
+
+/// Something that can play one media file at a time under our control.
+///
+/// Positions are milliseconds from the start of the loaded file. Instants are
+/// on the local wall clock (`clock::now_millis`).
+pub trait Player: Send {
+    /// Opens `path` paused at the start, replacing whatever was loaded.
+    fn load(&mut self, path: &Path, media_type: &MediaType) -> PlayerResult<()>;
+
+    /// Starts or resumes playback from the current position so that it lines
+    /// up with having started at `at_ms`. Waits if `at_ms` is still ahead and
+    /// skips the lateness if it has already passed.
+    fn play_at(&mut self, at_ms: u64) -> PlayerResult<()>;
+
+    fn pause(&mut self) -> PlayerResult<()>;
+
+    fn seek(&mut self, position_ms: u64) -> PlayerResult<()>;
+
+    /// Plays faster or slower than normal; 1.0 is normal speed.
+    fn set_rate(&mut self, rate: f64) -> PlayerResult<()>;
+
+    /// Current playback position, if the player knows it.
+    fn position(&mut self) -> Option<u64>;
+
+    fn stop(&mut self) -> PlayerResult<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerBackend {
+    /// mpv if it is installed, otherwise the system opener
+    Auto,
+    /// mpv controlled over its JSON IPC socket
+    Mpv,
+    /// Hand the file to the desktop's default application
+    Open,
+    /// Play nothing; only keep track of where playback would be
+    Null,
+}
+
+impl PlayerBackend {
+    pub fn create(self) -> Result<Box<dyn Player>, String> {
+        match self {
+            PlayerBackend::Auto => match find_in_path("mpv") {
+                Some(program) if cfg!(unix) => PlayerBackend::mpv(program),
+                _ => Ok(Box::new(OpenPlayer::new())),
+            },
+            PlayerBackend::Mpv => {
+                let program = find_in_path("mpv").ok_or("mpv was not found in PATH")?;
+                PlayerBackend::mpv(program)
+            }
+            PlayerBackend::Open => Ok(Box::new(OpenPlayer::new())),
+            PlayerBackend::Null => Ok(Box::new(NullPlayer::new())),
+        }
+    }
+
+    #[cfg(unix)]
+    fn mpv(program: PathBuf) -> Result<Box<dyn Player>, String> {
+        Ok(Box::new(mpv::MpvPlayer::new(program)))
+    }
+
+    #[cfg(not(unix))]
+    fn mpv(_program: PathBuf) -> Result<Box<dyn Player>, String> {
+        Err("the mpv backend is only supported on Unix".to_string())
+    }
+}
+
+impl FromStr for PlayerBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(PlayerBackend::Auto),
+            "mpv" => Ok(PlayerBackend::Mpv),
+            "open" => Ok(PlayerBackend::Open),
+            "null" => Ok(PlayerBackend::Null),
+            other => Err(format!("Unknown player '{}' (expected auto, mpv, open or null)", other)),
+        }
+    }
+}
+
+impl fmt::Display for PlayerBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PlayerBackend::Auto => "auto",
+            PlayerBackend::Mpv => "mpv",
+            PlayerBackend::Open => "open",
+            PlayerBackend::Null => "null",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Wraps a player for sharing between threads.
+pub fn shared(player: Box<dyn Player>) -> SharedPlayer {
+    Arc::new(Mutex::new(player))
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths).find_map(|dir| {
+        let candidate = dir.join(program);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        let candidate = dir.join(format!("{}.exe", program));
+        candidate.is_file().then_some(candidate)
+    })
+}
+
+// Position of a player we can't ask: advances at `rate` from
+// `anchor_position_ms` at local time `anchor_ms` unless paused
+#[derive(Debug, Clone)]
+struct EstimatedPosition {
+    anchor_ms: u64,
+    anchor_position_ms: u64,
+    rate: f64,
+    paused: bool,
+}
+
+impl EstimatedPosition {
+    fn new() -> Self {
+        Self {
+            anchor_ms: 0,
+            anchor_position_ms: 0,
+            rate: 1.0,
+            paused: true,
+        }
+    }
+
+    fn position_at(&self, now_ms: u64) -> u64 {
+        if self.paused {
+            return self.anchor_position_ms;
+        }
+        self.anchor_position_ms + (now_ms.saturating_sub(self.anchor_ms) as f64 * self.rate) as u64
+    }
+
+    fn play_at(&mut self, at_ms: u64) {
+        self.anchor_ms = at_ms;
+        self.paused = false;
+    }
+
+    fn pause(&mut self, now_ms: u64) {
+        self.anchor_position_ms = self.position_at(now_ms);
+        self.paused = true;
+    }
+
+    fn seek(&mut self, position_ms: u64, now_ms: u64) {
+        self.anchor_position_ms = position_ms;
+        self.anchor_ms = now_ms;
+    }
+
+    fn set_rate(&mut self, rate: f64, now_ms: u64) {
+        self.anchor_position_ms = self.position_at(now_ms);
+        self.anchor_ms = now_ms;
+        self.rate = rate;
+    }
+}
+
+/// Opens the file with the desktop's default application (`xdg-open`, `open`
+/// or `start`). That application can't be controlled afterwards, so pause,
+/// seek and rate only move the estimated position that is reported to the server.
+pub struct OpenPlayer {
+    loaded: Option<PathBuf>,
+    opened: bool,
+    position: EstimatedPosition,
+}
+
+impl OpenPlayer {
+    pub fn new() -> Self {
+        Self {
+            loaded: None,
+            opened: false,
+            position: EstimatedPosition::new(),
+        }
+    }
+}
+
+impl Player for OpenPlayer {
+    fn load(&mut self, path: &Path, media_type: &MediaType) -> PlayerResult<()> {
+        println!("Loading {} ({}) for the system player", path.display(), media_type);
+        self.loaded = Some(path.to_path_buf());
+        self.opened = false;
+        self.position = EstimatedPosition::new();
+        Ok(())
+    }
+
+    fn play_at(&mut self, at_ms: u64) -> PlayerResult<()> {
+        let path = self.loaded.as_ref().ok_or("No media loaded")?;
+        clock::sleep_until(at_ms);
+        self.position.play_at(at_ms);
+
+        if self.opened {
+            return Ok(());
+        }
+        self.opened = true;
+
+        #[cfg(target_os = "windows")]
+        {
+            std::process::Command::new("cmd")
+                .arg("/C")
+                .arg("start")
+                .arg("")
+                .arg(path)
+                .spawn()?;
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            std::process::Command::new("open")
+                .arg(path)
+                .spawn()?;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            std::process::Command::new("xdg-open")
+                .arg(path)
+                .spawn()?;
+        }
+
+        Ok(())
+    }
+
+    fn pause(&mut self) -> PlayerResult<()> {
+        self.position.pause(clock::now_millis());
+        Ok(())
+    }
+
+    fn seek(&mut self, position_ms: u64) -> PlayerResult<()> {
+        self.position.seek(position_ms, clock::now_millis());
+        Ok(())
+    }
+
+    fn set_rate(&mut self, rate: f64) -> PlayerResult<()> {
+        self.position.set_rate(rate, clock::now_millis());
+        Ok(())
+    }
+
+    fn position(&mut self) -> Option<u64> {
+        self.loaded.as_ref()?;
+        Some(self.position.position_at(clock::now_millis()))
+    }
+
+    fn stop(&mut self) -> PlayerResult<()> {
+        self.loaded = None;
+        self.opened = false;
+        Ok(())
+    }
+}
+
+// A call made on a `NullPlayer`
+#[derive(Debug, Clone, PartialEq)]
+enum PlayerEvent {
+    Load(PathBuf, MediaType),
+    PlayAt(u64),
+    Pause,
+    Seek(u64),
+    Rate(f64),
+    Stop,
+}
+
+impl fmt::Display for PlayerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerEvent::Load(path, media_type) => write!(f, "load {} ({})", path.display(), media_type),
+            PlayerEvent::PlayAt(at_ms) => write!(f, "play at {}", at_ms),
+            PlayerEvent::Pause => f.write_str("pause"),
+            PlayerEvent::Seek(position_ms) => write!(f, "seek to {} ms", position_ms),
+            PlayerEvent::Rate(rate) => write!(f, "rate {}", rate),
+            PlayerEvent::Stop => f.write_str("stop"),
+        }
+    }
+}
+
+/// Plays nothing. Every call is logged and recorded, and the position is
+/// tracked as if a real player had obeyed it, so a headless machine still
+/// takes part in sync.
+pub struct NullPlayer {
+    loaded: Option<PathBuf>,
+    position: EstimatedPosition,
+    // Every call that succeeded, oldest first; shared so it can still be read
+    // once the player is boxed into a `SharedPlayer`
+    events: Arc<Mutex<Vec<PlayerEvent>>>,
+}
+
+impl NullPlayer {
+    pub fn new() -> Self {
+        Self {
+            loaded: None,
+            position: EstimatedPosition::new(),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn record(&self, event: PlayerEvent) {
+        println!("[null player] {}", event);
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl Player for NullPlayer {
+    fn load(&mut self, path: &Path, media_type: &MediaType) -> PlayerResult<()> {
+        self.record(PlayerEvent::Load(path.to_path_buf(), media_type.clone()));
+        self.loaded = Some(path.to_path_buf());
+        self.position = EstimatedPosition::new();
+        Ok(())
+    }
+
+    fn play_at(&mut self, at_ms: u64) -> PlayerResult<()> {
+        self.loaded.as_ref().ok_or("No media loaded")?;
+        clock::sleep_until(at_ms);
+        self.record(PlayerEvent::PlayAt(at_ms));
+        self.position.play_at(at_ms);
+        Ok(())
+    }
+
+    fn pause(&mut self) -> PlayerResult<()> {
+        self.record(PlayerEvent::Pause);
+        self.position.pause(clock::now_millis());
+        Ok(())
+    }
+
+    fn seek(&mut self, position_ms: u64) -> PlayerResult<()> {
+        self.record(PlayerEvent::Seek(position_ms));
+        self.position.seek(position_ms, clock::now_millis());
+        Ok(())
+    }
+
+    fn set_rate(&mut self, rate: f64) -> PlayerResult<()> {
+        self.record(PlayerEvent::Rate(rate));
+        self.position.set_rate(rate, clock::now_millis());
+        Ok(())
+    }
+
+    fn position(&mut self) -> Option<u64> {
+        self.loaded.as_ref()?;
+        Some(self.position.position_at(clock::now_millis()))
+    }
+
+    fn stop(&mut self) -> PlayerResult<()> {
+        self.record(PlayerEvent::Stop);
+        self.loaded = None;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod mpv {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+    use serde_json::{json, Value};
+    use super::{Player, PlayerResult};
+    use crate::clock;
+    use crate::media_type::MediaType;
+
+    const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+    static NEXT_SOCKET_ID: AtomicU32 = AtomicU32::new(0);
+
+    struct Connection {
+        child: Child,
+        socket_path: PathBuf,
+        writer: UnixStream,
+        reader: BufReader<UnixStream>,
+        next_request_id: u64,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            let _ = writeln!(self.writer, "{}", json!({ "command": ["quit"] }));
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = std::fs::remove_file(&self.socket_path);
+        }
+    }
+
+    /// Drives an mpv process over its JSON IPC socket. mpv is started idle on
+    /// the first `load` and kept running between files.
+    pub struct MpvPlayer {
+        program: PathBuf,
+        connection: Option<Connection>,
+    }
+
+    impl MpvPlayer {
+        pub fn new(program: PathBuf) -> Self {
+            Self {
+                program,
+                connection: None,
+            }
+        }
+
+        fn connection(&mut self) -> PlayerResult<&mut Connection> {
+            if self.connection.is_none() {
+                self.connection = Some(self.spawn()?);
+            }
+            Ok(self.connection.as_mut().unwrap())
+        }
+
+        fn spawn(&self) -> PlayerResult<Connection> {
+            let socket_path = std::env::temp_dir().join(format!(
+                "media-sync-mpv-{}-{}.sock",
+                std::process::id(),
+                NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = std::fs::remove_file(&socket_path);
+
+            let mut child = Command::new(&self.program)
+                .arg("--idle=yes")
+                .arg("--keep-open=yes")
+                .arg("--force-window=yes")
+                .arg("--image-display-duration=inf")
+                .arg("--no-terminal")
+                .arg(format!("--input-ipc-server={}", socket_path.display()))
+                .stdin(Stdio::null())
+                .spawn()?;
+
+            // mpv creates the socket shortly after starting
+            let deadline = Instant::now() + STARTUP_TIMEOUT;
+            let stream = loop {
+                match UnixStream::connect(&socket_path) {
+                    Ok(stream) => break stream,
+                    Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(50)),
+                    Err(e) => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(format!("mpv IPC socket did not come up: {}", e).into());
+                    }
+                }
+            };
+            stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+            Ok(Connection {
+                child,
+                socket_path,
+                reader: BufReader::new(stream.try_clone()?),
+                writer: stream,
+                next_request_id: 0,
+            })
+        }
+
+        // Sends one command and waits for its reply, skipping any events mpv
+        // sends in between
+        fn command(&mut self, command: Value) -> PlayerResult<Value> {
+            let connection = self.connection()?;
+            connection.next_request_id += 1;
+            let request_id = connection.next_request_id;
+            writeln!(connection.writer, "{}", json!({ "command": command, "request_id": request_id }))?;
+
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if connection.reader.read_line(&mut line)? == 0 {
+                    return Err("mpv closed the IPC connection".into());
+                }
+                let reply: Value = serde_json::from_str(&line)?;
+                if reply["request_id"].as_u64() != Some(request_id) {
+                    continue;
+                }
+                return match reply["error"].as_str() {
+                    Some("success") => Ok(reply["data"].clone()),
+                    error => Err(format!("mpv: {} failed: {}", command, error.unwrap_or("unknown error")).into()),
+                };
+            }
+        }
+
+        fn set_property(&mut self, name: &str, value: Value) -> PlayerResult<()> {
+            self.command(json!(["set_property", name, value]))?;
+            Ok(())
+        }
+    }
+
+    impl Player for MpvPlayer {
+        fn load(&mut self, path: &Path, _media_type: &MediaType) -> PlayerResult<()> {
+            self.set_property("pause", json!(true))?;
+            self.set_property("speed", json!(1.0))?;
+            self.command(json!(["loadfile", path, "replace"]))?;
+            Ok(())
+        }
+
+        fn play_at(&mut self, at_ms: u64) -> PlayerResult<()> {
+            clock::sleep_until(at_ms);
+            let late_ms = clock::now_millis().saturating_sub(at_ms);
+            if late_ms > 0 {
+                // The file may still be opening; starting a little late beats not starting
+                if let Err(e) = self.command(json!(["seek", late_ms as f64 / 1000.0, "relative+exact"])) {
+                    eprintln!("Could not skip {} ms of late start: {}", late_ms, e);
+                }
+            }
+            self.set_property("pause", json!(false))
+        }
+
+        fn pause(&mut self) -> PlayerResult<()> {
+            self.set_property("pause", json!(true))
+        }
+
+        fn seek(&mut self, position_ms: u64) -> PlayerResult<()> {
+            self.command(json!(["seek", position_ms as f64 / 1000.0, "absolute+exact"]))?;
+            Ok(())
+        }
+
+        fn set_rate(&mut self, rate: f64) -> PlayerResult<()> {
+            self.set_property("speed", json!(rate))
+        }
+
+        fn position(&mut self) -> Option<u64> {
+            self.connection.as_ref()?;
+            let seconds = self.command(json!(["get_property", "time-pos"])).ok()?.as_f64()?;
+            Some((seconds.max(0.0) * 1000.0) as u64)
+        }
+
+        fn stop(&mut self) -> PlayerResult<()> {
+            if self.connection.is_some() {
+                self.command(json!(["stop"]))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimated_position_follows_play_pause_seek_and_rate() {
+        let mut position = EstimatedPosition::new();
+        assert_eq!(position.position_at(5000), 0);
+
+        position.play_at(1000);
+        // Not started yet, then running at normal speed
+        assert_eq!(position.position_at(500), 0);
+        assert_eq!(position.position_at(3000), 2000);
+
+        position.pause(4000);
+        assert_eq!(position.position_at(10_000), 3000);
+
+        // A seek while paused stays put until playback resumes
+        position.seek(60_000, 10_000);
+        assert_eq!(position.position_at(20_000), 60_000);
+        position.play_at(20_000);
+        assert_eq!(position.position_at(21_000), 61_000);
+
+        position.set_rate(1.5, 21_000);
+        assert_eq!(position.position_at(23_000), 64_000);
+
+        // A seek while playing keeps the rate
+        position.seek(0, 23_000);
+        assert_eq!(position.position_at(25_000), 3000);
+    }
+
+    #[test]
+    fn null_player_records_calls_and_tracks_position() {
+        let player = NullPlayer::new();
+        let events = Arc::clone(&player.events);
+        let player = shared(Box::new(player));
+        let mut player = player.lock().unwrap();
+        let media_type = MediaType::from_mime("audio/flac").unwrap();
+
+        assert!(player.play_at(0).is_err());
+        assert_eq!(player.position(), None);
+
+        player.load(Path::new("song.flac"), &media_type).unwrap();
+        assert_eq!(player.position(), Some(0));
+
+        // Started a second ago
+        let started_at = clock::now_millis() - 1000;
+        player.play_at(started_at).unwrap();
+        let position = player.position().unwrap();
+        assert!((1000..1500).contains(&position), "{}", position);
+
+        player.seek(10_000).unwrap();
+        player.set_rate(2.0).unwrap();
+        player.pause().unwrap();
+        let paused_at = player.position().unwrap();
+        assert!((10_000..11_000).contains(&paused_at), "{}", paused_at);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(player.position(), Some(paused_at));
+
+        player.seek(5000).unwrap();
+        assert_eq!(player.position(), Some(5000));
+
+        player.stop().unwrap();
+        assert_eq!(player.position(), None);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                PlayerEvent::Load(PathBuf::from("song.flac"), media_type),
+                PlayerEvent::PlayAt(started_at),
+                PlayerEvent::Seek(10_000),
+                PlayerEvent::Rate(2.0),
+                PlayerEvent::Pause,
+                PlayerEvent::Seek(5000),
+                PlayerEvent::Stop,
+            ]
+        );
+    }
+}
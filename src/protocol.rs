@@ -0,0 +1,710 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::library::MediaId;
+use crate::media_type::MediaType;
+use crate::metadata::MediaMetadata;
+
+/// Protocol version spoken by this build. Bump on any incompatible change to
+/// `Message` or the framing. Version 1 was the original newline-delimited JSON.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Oldest protocol version a server built from this tree will accept.
+/// Version 7 made `RequestMedia` and `RequestThumbnail` ask by media id;
+/// version 8 lets a `MediaEntry` leave out a hash the server hasn't computed yet;
+/// version 9 sends `Thumbnail` as a binary frame;
+/// version 10 says which version of the file a `RequestMediaRange` continues.
+pub const MIN_PROTOCOL_VERSION: u32 = 10;
+
+/// Size of each `MediaChunk` payload when streaming a file to a client.
+pub const MEDIA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest frame body accepted from the network. Anything bigger is treated
+/// as a corrupt stream rather than allocated.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// Frame type tags
+const TAG_JSON: u8 = 1;
+const TAG_MEDIA_CHUNK: u8 = 2;
+const TAG_THUMBNAIL: u8 = 3;
+
+/// Optional protocol features a peer can advertise in `Join`/`Welcome`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    ChunkedTransfer,
+    ClockSync,
+    /// Serves `Thumbnail`s in answer to `RequestThumbnail`
+    Thumbnails,
+    /// A capability added by a newer build that this one doesn't know about
+    #[serde(other)]
+    Unknown,
+}
+
+/// Capabilities implemented by this build.
+pub fn local_capabilities() -> Vec<Capability> {
+    vec![Capability::ChunkedTransfer, Capability::ClockSync, Capability::Thumbnails]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    /// Jump straight to a position; used for large drift
+    Seek { position_ms: u64 },
+    /// Play slightly faster or slower until back in sync; 1.0 restores normal speed
+    Rate { rate: f64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RejectReason {
+    UnsupportedVersion {
+        client_version: u32,
+        min_supported: u32,
+        max_supported: u32,
+    },
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::UnsupportedVersion { client_version, min_supported, max_supported } => write!(
+                f,
+                "protocol version {} is not supported (server accepts {}..={})",
+                client_version, min_supported, max_supported
+            ),
+        }
+    }
+}
+
+/// Checks a joining client's version and returns the capabilities both sides share.
+pub fn negotiate(client_version: u32, client_capabilities: &[Capability]) -> Result<Vec<Capability>, RejectReason> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&client_version) {
+        return Err(RejectReason::UnsupportedVersion {
+            client_version,
+            min_supported: MIN_PROTOCOL_VERSION,
+            max_supported: PROTOCOL_VERSION,
+        });
+    }
+
+    Ok(local_capabilities()
+        .into_iter()
+        .filter(|capability| client_capabilities.contains(capability))
+        .collect())
+}
+
+/// One file in the server's library, as listed to clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MediaEntry {
+    /// Stable identifier derived from `path`; what `RequestMedia` takes
+    pub id: MediaId,
+    /// Path relative to the media root with `/` separators
+    pub path: String,
+    pub size: u64,
+    /// Sent as `kind` and `mime` fields of the entry itself
+    #[serde(flatten)]
+    pub media_type: MediaType,
+    /// Last modification time in milliseconds since the Unix epoch, if the filesystem has one
+    pub modified_ms: Option<u64>,
+    /// SHA-256 of the file's contents, in hex. Left out until the server has
+    /// hashed the file; the entry is sent again as updated once it has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Left out when nothing could be read from the file's headers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<MediaMetadata>,
+}
+
+// Protocol messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    // Client to Server
+    Join {
+        client_id: String,
+        // Defaults let a pre-handshake Join decode as version 0 and get a typed rejection
+        #[serde(default)]
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    RequestMediaList,
+    // Files are asked for by MediaEntry id; the server only serves ids in its index
+    RequestMedia { id: MediaId },
+    // Part of a file, to finish an interrupted download.
+    // Sent as a normal transfer whose chunks start at `offset`; the server
+    // stops at the end of the file if `len` runs past it. `hash` is the
+    // version the earlier download was of; if the file has changed since,
+    // the whole of it is sent instead
+    RequestMediaRange {
+        id: MediaId,
+        offset: u64,
+        len: u64,
+        hash: String,
+    },
+    RequestThumbnail { id: MediaId },
+    // The received file didn't hash to what MediaStart announced; the copy was discarded
+    MediaCorrupt {
+        filename: String,
+        expected_hash: String,
+        actual_hash: String,
+    },
+
+    // Server to Client
+    Welcome {
+        client_id: String,
+        protocol_version: u32,
+        // Capabilities supported by both server and client
+        capabilities: Vec<Capability>,
+    },
+    Rejected { reason: RejectReason },
+    MediaList { files: Vec<MediaEntry> },
+    // Sent whenever files appear in, vanish from or change in the server's library.
+    // Removed files are given by path
+    MediaListChanged {
+        added: Vec<MediaEntry>,
+        removed: Vec<String>,
+        updated: Vec<MediaEntry>,
+    },
+    // A small JPEG preview of a file; None when it has no picture to show.
+    // Sent as a binary frame, like MediaChunk
+    Thumbnail {
+        id: MediaId,
+        data: Option<Vec<u8>>,
+    },
+    // A file transfer is a MediaStart, a run of MediaChunks and a MediaEnd
+    MediaStart {
+        filename: String,
+        size: u64,
+        media_type: MediaType,
+        // SHA-256 of the whole file in hex, checked by the client after MediaEnd
+        hash: String,
+        // Where the chunks start; non-zero when answering RequestMediaRange
+        offset: u64,
+        timestamp: u64
+    },
+    MediaChunk {
+        filename: String,
+        offset: u64,
+        data: Vec<u8>
+    },
+    MediaEnd { filename: String },
+    // Scheduled playback: the server asks every client to get the file locally,
+    // waits for their MediaReady, then sends each a PlayCommand whose start
+    // instant is already converted to that client's clock
+    PrepareMedia { filename: String },
+    MediaReady { filename: String },
+    PlayCommand {
+        filename: String,
+        start_at_ms: u64
+    },
+    // Group transport controls for the file currently playing
+    Pause { position_ms: u64 },
+    // Continue from the paused position at `at`, on the receiving client's clock
+    Resume { at: u64 },
+    Seek { position_ms: u64 },
+    Stop,
+    Error { message: String },
+    // The server is stopping; the connection is closed right after this
+    ServerShutdown,
+
+    // Drift correction: clients periodically report where they are, and the
+    // server answers with a SyncCorrection when one drifts too far
+    PositionReport {
+        filename: String,
+        position_ms: u64,
+        // Client clock time the position was sampled at
+        at_ms: u64,
+        playing: bool,
+    },
+    SyncCorrection {
+        filename: String,
+        correction: Correction,
+    },
+
+    // Clock synchronization: the server sends ClockPing with its send time and
+    // the client echoes it back with its own receive and transmit times
+    ClockPing { origin_ms: u64 },
+    ClockPong {
+        origin_ms: u64,
+        receive_ms: u64,
+        transmit_ms: u64,
+    },
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    TooLarge(usize),
+    UnknownTag(u8),
+    Malformed(String),
+    /// The peer sent newline-delimited JSON, so it predates framing (protocol
+    /// version 1); what looked like a length was the start of a JSON object
+    LegacyPeer,
+}
+
+impl FrameError {
+    /// Whether the reader is still positioned at a frame boundary after this error.
+    /// Malformed bodies and unknown tags are skipped whole; anything else leaves
+    /// the stream in an unknown state and the connection should be dropped.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, FrameError::UnknownTag(_) | FrameError::Malformed(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "I/O error: {}", e),
+            FrameError::TooLarge(len) => write!(f, "frame of {} bytes exceeds limit of {} bytes", len, MAX_FRAME_LEN),
+            FrameError::UnknownTag(tag) => write!(f, "unknown frame type tag {}", tag),
+            FrameError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+            FrameError::LegacyPeer => write!(f, "peer sent newline-delimited JSON; it looks like a pre-framing (protocol version 1) build"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Encodes a message as one frame: a big-endian `u32` body length, a one-byte
+/// type tag, then the body.
+///
+/// `MediaChunk` gets a binary body (`u64` offset, `u16` filename length,
+/// filename, raw bytes) so file data is never inflated into JSON, and so does
+/// `Thumbnail` (`u16` id length, id, a `u8` that is 1 when a JPEG follows, the
+/// JPEG); every other message is a JSON body.
+pub fn encode_frame(message: &Message) -> Result<Vec<u8>, FrameError> {
+    let (tag, body) = match message {
+        Message::MediaChunk { filename, offset, data } => {
+            let name = filename.as_bytes();
+            let name_len = u16::try_from(name.len())
+                .map_err(|_| FrameError::Malformed(format!("filename of {} bytes is too long", name.len())))?;
+            let mut body = Vec::with_capacity(8 + 2 + name.len() + data.len());
+            body.extend_from_slice(&offset.to_be_bytes());
+            body.extend_from_slice(&name_len.to_be_bytes());
+            body.extend_from_slice(name);
+            body.extend_from_slice(data);
+            (TAG_MEDIA_CHUNK, body)
+        }
+        Message::Thumbnail { id, data } => {
+            let id = id.to_string();
+            let mut body = Vec::with_capacity(2 + id.len() + 1 + data.as_ref().map_or(0, Vec::len));
+            body.extend_from_slice(&(id.len() as u16).to_be_bytes());
+            body.extend_from_slice(id.as_bytes());
+            match data {
+                Some(data) => {
+                    body.push(1);
+                    body.extend_from_slice(data);
+                }
+                None => body.push(0),
+            }
+            (TAG_THUMBNAIL, body)
+        }
+        _ => {
+            let body = serde_json::to_vec(message).map_err(|e| FrameError::Malformed(e.to_string()))?;
+            (TAG_JSON, body)
+        }
+    };
+
+    if body.len() > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(body.len()));
+    }
+
+    let mut frame = Vec::with_capacity(5 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.push(tag);
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+fn decode_body(tag: u8, body: &[u8]) -> Result<Message, FrameError> {
+    match tag {
+        TAG_JSON => serde_json::from_slice(body).map_err(|e| FrameError::Malformed(e.to_string())),
+        TAG_MEDIA_CHUNK => {
+            if body.len() < 10 {
+                return Err(FrameError::Malformed("media chunk header truncated".to_string()));
+            }
+            let offset = u64::from_be_bytes(body[0..8].try_into().unwrap());
+            let name_len = u16::from_be_bytes(body[8..10].try_into().unwrap()) as usize;
+            let name_end = 10 + name_len;
+            if body.len() < name_end {
+                return Err(FrameError::Malformed("media chunk filename truncated".to_string()));
+            }
+            let filename = String::from_utf8(body[10..name_end].to_vec())
+                .map_err(|_| FrameError::Malformed("media chunk filename is not UTF-8".to_string()))?;
+            Ok(Message::MediaChunk {
+                filename,
+                offset,
+                data: body[name_end..].to_vec(),
+            })
+        }
+        TAG_THUMBNAIL => {
+            if body.len() < 2 {
+                return Err(FrameError::Malformed("thumbnail header truncated".to_string()));
+            }
+            let id_len = u16::from_be_bytes(body[0..2].try_into().unwrap()) as usize;
+            let id_end = 2 + id_len;
+            if body.len() <= id_end {
+                return Err(FrameError::Malformed("thumbnail id truncated".to_string()));
+            }
+            let id = String::from_utf8(body[2..id_end].to_vec())
+                .map_err(|_| FrameError::Malformed("thumbnail id is not UTF-8".to_string()))
+                .and_then(|id| MediaId::try_from(id).map_err(FrameError::Malformed))?;
+            let data = match body[id_end] {
+                0 => None,
+                1 => Some(body[id_end + 1..].to_vec()),
+                flag => return Err(FrameError::Malformed(format!("thumbnail has unknown picture flag {}", flag))),
+            };
+            Ok(Message::Thumbnail { id, data })
+        }
+        _ => Err(FrameError::UnknownTag(tag)),
+    }
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), FrameError> {
+    let frame = encode_frame(message)?;
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads one frame. Returns `Ok(None)` when the peer closed the connection
+/// cleanly between frames.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Option<Message>, FrameError> {
+    let mut header = [0u8; 5];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(FrameError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut body = vec![0u8; body_len(&header)?];
+    reader.read_exact(&mut body)?;
+    decode_body(header[4], &body).map(Some)
+}
+
+/// `write_message` for an async writer.
+pub async fn write_message_async<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<(), FrameError> {
+    let frame = encode_frame(message)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Writes a message as protocol version 1 did, as a line of JSON, to tell a
+/// peer that predates framing why it is being turned away.
+pub async fn write_legacy_message_async<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<(), FrameError> {
+    let mut line = serde_json::to_vec(message).map_err(|e| FrameError::Malformed(e.to_string()))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// `read_message` for an async reader. Bytes of a frame that hasn't fully
+/// arrived are kept between calls, so `read_message` is cancel safe: it can
+/// lose a `select!` part way through a frame and the next call picks up
+/// where it left off.
+pub struct FrameReader<R> {
+    reader: R,
+    // Received bytes not yet returned as a frame
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, buffer: Vec::new() }
+    }
+
+    /// Reads one frame. Returns `Ok(None)` when the peer closed the
+    /// connection cleanly between frames.
+    pub async fn read_message(&mut self) -> Result<Option<Message>, FrameError> {
+        loop {
+            if let Some(message) = self.take_frame()? {
+                return message.map(Some);
+            }
+            // Only awaits the read itself; whatever it returned is in the buffer
+            // before anything else can cancel this
+            let wanted = self.wanted()?;
+            self.buffer.reserve(wanted.saturating_sub(self.buffer.len()).max(4096));
+            match self.reader.read_buf(&mut self.buffer).await {
+                Ok(0) if self.buffer.is_empty() => return Ok(None),
+                Ok(0) => return Err(FrameError::Io(io::ErrorKind::UnexpectedEof.into())),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // Bytes needed for the frame at the front of the buffer
+    fn wanted(&self) -> Result<usize, FrameError> {
+        match self.buffer.first_chunk::<5>() {
+            Some(header) => Ok(5 + body_len(header)?),
+            None => Ok(5),
+        }
+    }
+
+    // The frame at the front of the buffer, removed from it, once all of it is there
+    fn take_frame(&mut self) -> Result<Option<Result<Message, FrameError>>, FrameError> {
+        let wanted = self.wanted()?;
+        if self.buffer.len() < wanted {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = self.buffer.drain(..wanted).collect();
+        Ok(Some(decode_body(frame[4], &frame[5..])))
+    }
+}
+
+fn body_len(header: &[u8; 5]) -> Result<usize, FrameError> {
+    // A length starting with this byte is far over MAX_FRAME_LEN, so it can
+    // only be the opening brace of a version 1 message
+    if header[0] == b'{' {
+        return Err(FrameError::LegacyPeer);
+    }
+    let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_all(bytes: Vec<u8>) -> Result<Option<Message>, FrameError> {
+        read_message(&mut Cursor::new(bytes))
+    }
+
+    fn frame(len: u32, tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.push(tag);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    fn json_message_round_trips() {
+        let encoded = encode_frame(&Message::Join {
+            client_id: "living-room".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::ClockSync],
+        })
+        .unwrap();
+        assert_eq!(encoded[4], TAG_JSON);
+
+        let Some(Message::Join { client_id, protocol_version, capabilities }) = read_all(encoded).unwrap() else {
+            panic!("expected a Join");
+        };
+        assert_eq!(client_id, "living-room");
+        assert_eq!(protocol_version, PROTOCOL_VERSION);
+        assert_eq!(capabilities, vec![Capability::ClockSync]);
+    }
+
+    #[test]
+    fn media_chunk_round_trips_as_binary() {
+        let data: Vec<u8> = (0..=255).cycle().take(MEDIA_CHUNK_SIZE).collect();
+        let encoded = encode_frame(&Message::MediaChunk {
+            filename: "Ärtist/song.mp3".to_string(),
+            offset: 5 * MEDIA_CHUNK_SIZE as u64,
+            data: data.clone(),
+        })
+        .unwrap();
+        assert_eq!(encoded[4], TAG_MEDIA_CHUNK);
+        // Length, tag, offset, name length and name, then the bytes as they are
+        assert_eq!(encoded.len(), 5 + 8 + 2 + "Ärtist/song.mp3".len() + data.len());
+
+        let Some(Message::MediaChunk { filename, offset, data: decoded }) = read_all(encoded).unwrap() else {
+            panic!("expected a MediaChunk");
+        };
+        assert_eq!(filename, "Ärtist/song.mp3");
+        assert_eq!(offset, 5 * MEDIA_CHUNK_SIZE as u64);
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn thumbnail_round_trips_as_binary() {
+        let id = MediaId::for_key("cover.jpg");
+        for data in [Some(vec![0xff, 0xd8, 0xff, 0xe0]), Some(Vec::new()), None] {
+            let encoded = encode_frame(&Message::Thumbnail { id: id.clone(), data: data.clone() }).unwrap();
+            assert_eq!(encoded[4], TAG_THUMBNAIL);
+            let Some(Message::Thumbnail { id: decoded_id, data: decoded }) = read_all(encoded).unwrap() else {
+                panic!("expected a Thumbnail");
+            };
+            assert_eq!(decoded_id, id);
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn consecutive_frames_are_read_in_order() {
+        let mut stream = encode_frame(&Message::RequestMediaList).unwrap();
+        stream.extend(encode_frame(&Message::MediaChunk { filename: "a".to_string(), offset: 0, data: vec![1, 2, 3] }).unwrap());
+        stream.extend(encode_frame(&Message::MediaEnd { filename: "a".to_string() }).unwrap());
+
+        let mut reader = Cursor::new(stream);
+        assert!(matches!(read_message(&mut reader), Ok(Some(Message::RequestMediaList))));
+        assert!(matches!(read_message(&mut reader), Ok(Some(Message::MediaChunk { .. }))));
+        assert!(matches!(read_message(&mut reader), Ok(Some(Message::MediaEnd { .. }))));
+        assert!(matches!(read_message(&mut reader), Ok(None)));
+    }
+
+    #[test]
+    fn clean_close_between_frames_is_not_an_error() {
+        assert!(matches!(read_all(Vec::new()), Ok(None)));
+    }
+
+    #[test]
+    fn truncated_header_is_an_unrecoverable_error() {
+        let encoded = encode_frame(&Message::RequestMediaList).unwrap();
+        for len in 1..5 {
+            let error = read_all(encoded[..len].to_vec()).unwrap_err();
+            assert!(matches!(&error, FrameError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof), "{}", error);
+            assert!(!error.is_recoverable());
+        }
+    }
+
+    #[test]
+    fn truncated_body_is_an_unrecoverable_error() {
+        let mut encoded = encode_frame(&Message::MediaEnd { filename: "song.mp3".to_string() }).unwrap();
+        encoded.pop();
+        let error = read_all(encoded).unwrap_err();
+        assert!(matches!(&error, FrameError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof), "{}", error);
+        assert!(!error.is_recoverable());
+    }
+
+    #[test]
+    fn oversized_frame_is_refused_before_allocating() {
+        let error = read_all(frame(MAX_FRAME_LEN as u32 + 1, TAG_JSON, b"{}")).unwrap_err();
+        assert!(matches!(error, FrameError::TooLarge(len) if len == MAX_FRAME_LEN + 1));
+        assert!(!error.is_recoverable());
+
+        // Exactly at the limit is still a frame
+        assert!(body_len(&[0x01, 0x00, 0x00, 0x00, TAG_JSON]).is_ok());
+    }
+
+    #[test]
+    fn oversized_message_is_not_encoded() {
+        let error = encode_frame(&Message::MediaChunk {
+            filename: "big".to_string(),
+            offset: 0,
+            data: vec![0; MAX_FRAME_LEN],
+        })
+        .unwrap_err();
+        assert!(matches!(error, FrameError::TooLarge(_)));
+    }
+
+    #[test]
+    fn newline_json_peer_is_recognised() {
+        let error = read_all(b"{\"Join\":{\"client_id\":\"old\"}}\n".to_vec()).unwrap_err();
+        assert!(matches!(error, FrameError::LegacyPeer), "{}", error);
+        assert!(!error.is_recoverable());
+    }
+
+    #[tokio::test]
+    async fn legacy_message_is_one_json_line() {
+        let mut line = Vec::new();
+        write_legacy_message_async(&mut line, &Message::ServerShutdown).await.unwrap();
+        assert_eq!(line, b"\"ServerShutdown\"\n");
+    }
+
+    #[test]
+    fn unknown_tag_is_skipped_whole() {
+        let mut stream = frame(3, 99, b"abc");
+        stream.extend(encode_frame(&Message::RequestMediaList).unwrap());
+
+        let mut reader = Cursor::new(stream);
+        let error = read_message(&mut reader).unwrap_err();
+        assert!(matches!(error, FrameError::UnknownTag(99)));
+        assert!(error.is_recoverable());
+        assert!(matches!(read_message(&mut reader), Ok(Some(Message::RequestMediaList))));
+    }
+
+    #[test]
+    fn malformed_bodies_are_skipped_whole() {
+        let bodies: [(u8, &[u8]); 6] = [
+            (TAG_JSON, b"{\"NoSuchMessage\":{}}"),
+            (TAG_JSON, b"not json"),
+            (TAG_MEDIA_CHUNK, &[0, 0, 0]),
+            (TAG_MEDIA_CHUNK, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 9, b'a']),
+            (TAG_THUMBNAIL, &[0, 3, b'a', b'b', b'c', 1]),
+            (TAG_THUMBNAIL, &[0]),
+        ];
+        for (tag, body) in bodies {
+            let mut stream = frame(body.len() as u32, tag, body);
+            stream.extend(encode_frame(&Message::RequestMediaList).unwrap());
+
+            let mut reader = Cursor::new(stream);
+            let error = read_message(&mut reader).unwrap_err();
+            assert!(matches!(error, FrameError::Malformed(_)), "{}", error);
+            assert!(error.is_recoverable());
+            assert!(matches!(read_message(&mut reader), Ok(Some(Message::RequestMediaList))));
+        }
+    }
+
+    #[test]
+    fn io_and_size_errors_are_not_recoverable() {
+        assert!(!FrameError::Io(io::ErrorKind::ConnectionReset.into()).is_recoverable());
+        assert!(!FrameError::TooLarge(MAX_FRAME_LEN + 1).is_recoverable());
+        assert!(FrameError::UnknownTag(0).is_recoverable());
+        assert!(FrameError::Malformed(String::new()).is_recoverable());
+    }
+
+    #[tokio::test]
+    async fn async_reader_and_writer_match_the_blocking_ones() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        write_message_async(&mut client, &Message::MediaChunk { filename: "a".to_string(), offset: 7, data: vec![9; 3000] })
+            .await
+            .unwrap();
+        drop(client);
+
+        let mut reader = FrameReader::new(server);
+        let Some(Message::MediaChunk { filename, offset, data }) = reader.read_message().await.unwrap() else {
+            panic!("expected a MediaChunk");
+        };
+        assert_eq!((filename.as_str(), offset, data.len()), ("a", 7, 3000));
+        assert!(matches!(reader.read_message().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn frame_reader_keeps_a_split_frame_when_a_read_is_cancelled() {
+        let (mut client, server) = tokio::io::duplex(256 * 1024);
+        let mut reader = FrameReader::new(server);
+        let mut frames = encode_frame(&Message::MediaChunk { filename: "a".to_string(), offset: 0, data: vec![7; 100_000] }).unwrap();
+        frames.extend(encode_frame(&Message::MediaEnd { filename: "a".to_string() }).unwrap());
+
+        // Header and part of the body, then something else wins the race, as a
+        // finished transfer task does in the server's read loop
+        let mut tasks = tokio::task::JoinSet::new();
+        for split in [3, 60_000] {
+            client.write_all(&frames[..split]).await.unwrap();
+            tokio::task::yield_now().await;
+            tasks.spawn(async {});
+            tokio::select! {
+                biased;
+                read = reader.read_message() => panic!("read a frame that hadn't arrived: {:?}", read.map(|_| ())),
+                Some(_) = tasks.join_next() => {}
+            }
+            frames.drain(..split);
+        }
+
+        client.write_all(&frames).await.unwrap();
+        drop(client);
+        let Some(Message::MediaChunk { data, .. }) = reader.read_message().await.unwrap() else {
+            panic!("expected the MediaChunk");
+        };
+        assert_eq!(data, vec![7; 100_000]);
+        assert!(matches!(reader.read_message().await, Ok(Some(Message::MediaEnd { .. }))));
+        assert!(matches!(reader.read_message().await, Ok(None)));
+    }
+}
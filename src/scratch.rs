@@ -0,0 +1,176 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Scratch directories untouched for this long belong to runs that died
+/// without cleaning up, and are deleted when another one starts.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest file name handed out by `ScratchDir::file`, well under the usual
+/// 255-byte limit.
+const MAX_NAME_LEN: usize = 120;
+
+/// A private directory for files that only matter while a connection is up,
+/// such as thumbnails. It is emptied when created, deleted when dropped, and
+/// every file in it is named through `file`, so nothing from the network can
+/// place a file outside it.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates `media-sync-scratch/<owner>-<pid>` under the system temp
+    /// directory. The process id keeps two running processes with the same
+    /// owner apart; whatever a dead process with the same id left is cleared out.
+    pub fn create(owner: &str) -> io::Result<Self> {
+        let root = std::env::temp_dir().join("media-sync-scratch");
+        remove_stale(&root);
+
+        let path = root.join(sanitize_file_name(&format!("{}-{}", owner, std::process::id())));
+        match fs::remove_dir_all(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path for a file called `name` in the directory. `name` may come from
+    /// the network, so it is reduced to a single plain path component first.
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.path.join(sanitize_file_name(name))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            eprintln!("Could not remove scratch directory {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Turns an arbitrary string into a file name that stays in the directory it
+/// is joined to: separators, `..`, control characters and anything else
+/// outside a conservative set become `_`, and the result is never empty or
+/// hidden.
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ' ') { c } else { '_' })
+        .collect();
+    if sanitized.starts_with('.') || sanitized.is_empty() {
+        sanitized.insert(0, '_');
+    }
+    if sanitized.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+    }
+    sanitized
+}
+
+// Deletes scratch directories left by processes that are gone. Where that
+// can't be told, a directory nothing has written to for `STALE_AFTER` is
+// taken to be abandoned
+fn remove_stale(root: &Path) {
+    let Ok(entries) = fs::read_dir(root) else { return };
+    for entry in entries.filter_map(Result::ok) {
+        let owner_alive = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.rsplit_once('-'))
+            .and_then(|(_, pid)| pid.parse::<u32>().ok())
+            .and_then(process_alive);
+        let stale = match owner_alive {
+            Some(alive) => !alive,
+            None => entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > STALE_AFTER),
+        };
+        if stale {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+// Whether process `pid` is running, where the platform makes that cheap to find out
+fn process_alive(pid: u32) -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        Some(Path::new("/proc").join(pid.to_string()).exists())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_file_name_keeps_names_in_one_component() {
+        let cases = [
+            ("movie.mp4", "movie.mp4"),
+            ("dir/movie.mp4", "dir_movie.mp4"),
+            ("dir\\movie.mp4", "dir_movie.mp4"),
+            ("../../etc/passwd", "_.._.._etc_passwd"),
+            ("..", "_.."),
+            (".", "_."),
+            (".hidden", "_.hidden"),
+            ("a\u{0}b\nc\u{7f}", "a_b_c_"),
+            ("", "_"),
+            ("tab\there", "tab_here"),
+            ("café ñ.mp3", "café ñ.mp3"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(sanitize_file_name(name), expected, "name {:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_file_name_truncates_on_a_char_boundary() {
+        // 'é' is two bytes, so byte MAX_NAME_LEN falls inside one
+        let name = format!("a{}", "é".repeat(MAX_NAME_LEN));
+        let sanitized = sanitize_file_name(&name);
+        assert_eq!(sanitized.len(), MAX_NAME_LEN - 1);
+        assert!(name.starts_with(&sanitized));
+
+        let ascii = "x".repeat(MAX_NAME_LEN * 2);
+        assert_eq!(sanitize_file_name(&ascii).len(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn remove_stale_keeps_directories_of_running_processes() {
+        let root = std::env::temp_dir().join(format!("media-sync-scratch-test-{}", std::process::id()));
+        let live = root.join(format!("client-{}", std::process::id()));
+        let other = root.join("client-notapid");
+        fs::create_dir_all(&live).unwrap();
+        fs::create_dir_all(&other).unwrap();
+
+        remove_stale(&root);
+        assert!(live.exists());
+        // Liveness unknown and recently written, so kept on age
+        assert!(other.exists());
+
+        if cfg!(target_os = "linux") {
+            // Beyond the kernel's pid limit, so never a running process
+            let dead = root.join("client-4294967295");
+            fs::create_dir_all(&dead).unwrap();
+            remove_stale(&root);
+            assert!(!dead.exists());
+            assert!(live.exists());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Writes `data` to a file only the calling test uses, runs `read` on it and
+/// removes it again.
+pub fn with_temp_file<T>(data: &[u8], read: impl FnOnce(&Path) -> T) -> T {
+    let path = std::env::temp_dir().join(format!("media-sync-test-{}-{:?}", std::process::id(), std::thread::current().id()));
+    fs::write(&path, data).unwrap();
+    let result = read(&path);
+    fs::remove_file(&path).unwrap();
+    result
+}
+
+/// A directory only the calling test uses, deleted when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates an empty directory; `name` tells apart several in one test.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("media-sync-test-{}-{:?}-{}", std::process::id(), std::thread::current().id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat};
+use crate::disk_lru::{self, touch};
+use crate::media_type::MediaKind;
+use crate::metadata;
+use crate::MediaFile;
+
+/// Longest side of a thumbnail, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+const JPEG_QUALITY: u8 = 80;
+
+// Thumbnails are a few KiB each, so this holds thousands; past it the least
+// recently served ones are deleted
+const MAX_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+// A thumbnail still being written after this long was left by a writer that
+// died part way
+const ABANDONED_PART_AGE: Duration = Duration::from_secs(60);
+
+// Numbers each writer's temporary file, so concurrent writers never share one
+static NEXT_PART: AtomicU64 = AtomicU64::new(0);
+
+/// Small JPEG previews of library files, kept on disk so each one is decoded
+/// and scaled once per version of its source file rather than on every request.
+/// A thumbnail's modification time records when it was last served, and the
+/// least recently served are deleted once the directory outgrows its limit.
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// JPEG thumbnail for `media_file`, whose content hash is `hash`, or `None`
+    /// if it has no picture to show. Images are scaled down themselves; audio
+    /// and video use their embedded cover art.
+    pub fn get(&self, media_file: &MediaFile, hash: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        // Named by content, so an edited file gets a fresh thumbnail and a moved or copied one keeps it
+        let cached = self.dir.join(format!("{}.jpg", hash));
+        if let Ok(jpeg) = fs::read(&cached) {
+            // Only affects which thumbnails are evicted first
+            let _ = touch(&cached);
+            return Ok(Some(jpeg));
+        }
+
+        let Some(image) = load_picture(media_file)? else { return Ok(None) };
+        let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&thumbnail)?;
+
+        // Written under a name of this writer's own first, so a concurrent
+        // request never reads half a file and two writers never share one
+        fs::create_dir_all(&self.dir)?;
+        let partial = cached.with_extension(format!("{}-{}.part", std::process::id(), NEXT_PART.fetch_add(1, Ordering::Relaxed)));
+        if let Err(e) = fs::write(&partial, &jpeg).and_then(|()| fs::rename(&partial, &cached)) {
+            let _ = fs::remove_file(&partial);
+            return Err(e.into());
+        }
+        self.evict(&cached);
+        Ok(Some(jpeg))
+    }
+
+    // Deletes the least recently served thumbnails, never `keep`, until the
+    // directory fits MAX_CACHE_BYTES, and whatever writers that died left behind
+    fn evict(&self, keep: &Path) {
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries.filter_map(Result::ok) {
+                let abandoned = entry.path().extension().is_some_and(|extension| extension == "part")
+                    && entry
+                        .metadata()
+                        .and_then(|metadata| metadata.modified())
+                        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > ABANDONED_PART_AGE));
+                if abandoned {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+        disk_lru::evict(
+            &self.dir,
+            MAX_CACHE_BYTES,
+            "thumbnail cache",
+            |path| path.extension().is_some_and(|extension| extension == "jpg"),
+            |path| path == keep,
+        );
+    }
+}
+
+fn load_picture(media_file: &MediaFile) -> Result<Option<DynamicImage>, Box<dyn std::error::Error>> {
+    if media_file.media_type.kind != MediaKind::Image {
+        return Ok(metadata::cover_art(&media_file.path, &media_file.media_type)?
+            .map(|picture| image::load_from_memory(&picture))
+            .transpose()?);
+    }
+
+    // Formats the image crate can't decode, such as HEIC, go without
+    let Some(format) = ImageFormat::from_mime_type(&media_file.media_type.mime) else { return Ok(None) };
+    let image = image::load_from_memory_with_format(&fs::read(&media_file.path)?, format)?;
+    if format != ImageFormat::Jpeg {
+        return Ok(Some(image));
+    }
+
+    // Cameras store pictures as shot and record which way up they belong
+    let image = match metadata::exif_orientation(&media_file.path)? {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    };
+    Ok(Some(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::time::SystemTime;
+
+    #[test]
+    fn eviction_removes_abandoned_partial_writes_only() {
+        let dir = TempDir::new("thumbnails");
+        let cache = ThumbnailCache::new(dir.path().to_path_buf());
+        let kept = dir.path().join("a.jpg");
+        let abandoned = dir.path().join("b.1-0.part");
+        let writing = dir.path().join("b.1-1.part");
+        for path in [&kept, &abandoned, &writing] {
+            fs::write(path, b"jpeg").unwrap();
+        }
+        let long_ago = SystemTime::now() - ABANDONED_PART_AGE * 2;
+        fs::File::options().write(true).open(&abandoned).unwrap().set_modified(long_ago).unwrap();
+
+        cache.evict(&kept);
+        assert!(kept.exists());
+        assert!(!abandoned.exists());
+        assert!(writing.exists());
+    }
+}
@@ -1,13 +1,21 @@
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::convert::Infallible;
+use std::time::Duration;
+use warp::http::StatusCode;
 use warp::{Filter, Reply};
 use serde::{Deserialize, Serialize};
 use base64::{Engine as _, engine::general_purpose};
 use chrono::Utc;
 
-use crate::{MediaServer, MediaClient};
+use crate::{HostPlayback, LibraryChange, MediaFile, MediaServer, MediaClient};
+use crate::connection::SlowClientPolicy;
+use crate::library::{MediaId, ScanOptions};
+use crate::media_type::MediaType;
+use crate::metadata::MediaMetadata;
+use crate::player::PlayerBackend;
+use crate::protocol::MediaEntry;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WebRequest {
@@ -26,16 +34,39 @@ pub struct WebResponse {
 pub struct FileInfo {
     pub name: String,
     pub size: usize,
-    pub media_type: String,
+    pub media_type: MediaType,
+    pub metadata: MediaMetadata,
 }
 
 #[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct ClientInfo {
     pub id: String,
     pub address: String,
     pub connected_time: String,
+    pub clock_offset_ms: Option<i64>,
+    pub round_trip_ms: Option<u64>,
+    pub playing_file: Option<String>,
+    pub position_ms: Option<u64>,
+    pub drift_ms: Option<i64>,
+    pub queued_messages: usize,
 }
 
+/// One batch of library changes, numbered so the page can ask for those it hasn't seen.
+#[derive(Serialize, Clone)]
+pub struct LibraryDelta {
+    pub revision: u64,
+    pub added: Vec<FileInfo>,
+    pub removed: Vec<String>,
+    pub updated: Vec<FileInfo>,
+}
+
+// Oldest deltas are dropped past this; a page that far behind reloads the whole list
+const MAX_LIBRARY_DELTAS: usize = 100;
+
+// How long connect-client waits for the server's media list
+const CLIENT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Serialize, Clone)]
 pub struct LogMessage {
     pub timestamp: String,
@@ -45,9 +76,14 @@ pub struct LogMessage {
 
 pub struct WebServer {
     media_server: Arc<Mutex<Option<MediaServer>>>,
+    // The task running the media server's accept loop, awaited on stop
+    media_server_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
     media_client: Arc<Mutex<Option<MediaClient>>>,
     loaded_files: Arc<Mutex<Vec<FileInfo>>>,
-    available_files: Arc<Mutex<Vec<String>>>,
+    library_deltas: Arc<Mutex<Vec<LibraryDelta>>>,
+    library_revision: Arc<Mutex<u64>>,
+    // The connected server's media list, kept current by the client
+    available_files: Arc<Mutex<Vec<MediaEntry>>>,
     log_messages: Arc<Mutex<Vec<LogMessage>>>,
 }
 
@@ -55,13 +91,51 @@ impl WebServer {
     pub fn new() -> Self {
         Self {
             media_server: Arc::new(Mutex::new(None)),
+            media_server_task: Arc::new(Mutex::new(None)),
             media_client: Arc::new(Mutex::new(None)),
             loaded_files: Arc::new(Mutex::new(Vec::new())),
+            library_deltas: Arc::new(Mutex::new(Vec::new())),
+            library_revision: Arc::new(Mutex::new(0)),
             available_files: Arc::new(Mutex::new(Vec::new())),
             log_messages: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    // Applies a library change to the loaded file list and queues it for the page
+    fn record_library_change(&self, change: &LibraryChange) {
+        let to_info = |file: &MediaFile| FileInfo {
+            name: file.filename.clone(),
+            size: file.size as usize,
+            media_type: file.media_type.clone(),
+            metadata: file.metadata.clone(),
+        };
+        let added: Vec<FileInfo> = change.added.iter().map(to_info).collect();
+        let updated: Vec<FileInfo> = change.updated.iter().map(to_info).collect();
+        
+        {
+            let mut loaded_files = self.loaded_files.lock().unwrap();
+            loaded_files.retain(|file| {
+                !change.removed.contains(&file.name) && !updated.iter().any(|info| info.name == file.name)
+            });
+            loaded_files.extend(added.iter().cloned());
+            loaded_files.extend(updated.iter().cloned());
+            loaded_files.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        
+        let mut revision = self.library_revision.lock().unwrap();
+        *revision += 1;
+        let mut deltas = self.library_deltas.lock().unwrap();
+        deltas.push(LibraryDelta {
+            revision: *revision,
+            added,
+            removed: change.removed.clone(),
+            updated,
+        });
+        if deltas.len() > MAX_LIBRARY_DELTAS {
+            deltas.remove(0);
+        }
+    }
+
     fn add_log_message(&self, level: &str, message: &str) {
         let timestamp = Utc::now().format("%H:%M:%S").to_string();
         let log_message = LogMessage {
@@ -98,6 +172,21 @@ impl WebServer {
             .and(warp::get())
             .and_then(serve_js);
 
+        // Thumbnails of the server's files, as /thumbnail?file=<name>
+        let thumbnail_route = warp::path("thumbnail")
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_web_server(web_server.clone()))
+            .and_then(serve_thumbnail);
+
+        // Thumbnails of the files offered by the server the client is connected to,
+        // as /client-thumbnail?id=<media id>
+        let client_thumbnail_route = warp::path("client-thumbnail")
+            .and(warp::get())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(with_web_server(web_server.clone()))
+            .and_then(serve_client_thumbnail);
+
         // API routes
         let api = warp::path("api")
             .and(warp::post())
@@ -108,6 +197,8 @@ impl WebServer {
         let routes = static_files
             .or(css_route)
             .or(js_route)
+            .or(thumbnail_route)
+            .or(client_thumbnail_route)
             .or(api)
             .with(warp::cors().allow_any_origin());
 
@@ -182,6 +273,58 @@ async fn serve_js() -> Result<impl Reply, warp::Rejection> {
     }
 }
 
+async fn serve_thumbnail(
+    query: HashMap<String, String>,
+    web_server: Arc<WebServer>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let server = web_server.media_server.lock().unwrap().clone();
+    let (Some(server), Some(filename)) = (server, query.get("file").cloned()) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if !server.media_files.lock().unwrap().contains_key(&filename) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    // Decoding and scaling an image is too slow to do on the async runtime
+    let thumbnail = tokio::task::spawn_blocking(move || server.thumbnail(&filename).map_err(|e| e.to_string())).await;
+    match thumbnail {
+        Ok(Ok(Some(jpeg))) => {
+            let reply = warp::reply::with_header(jpeg, "content-type", "image/jpeg");
+            // Revalidate every time; the same name shows new content once the file is edited
+            Ok(warp::reply::with_header(reply, "cache-control", "no-cache").into_response())
+        }
+        Ok(Ok(None)) => Ok(StatusCode::NOT_FOUND.into_response()),
+        Ok(Err(e)) => {
+            web_server.add_log_message("error", &format!("Failed to make thumbnail: {}", e));
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Err(_) => Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+// How long a list thumbnail waits for the server before the list shows none
+const CLIENT_THUMBNAIL_WAIT: Duration = Duration::from_secs(10);
+
+async fn serve_client_thumbnail(
+    query: HashMap<String, String>,
+    web_server: Arc<WebServer>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let client = web_server.media_client.lock().unwrap().clone();
+    let id = query.get("id").cloned().and_then(|id| MediaId::try_from(id).ok());
+    let (Some(client), Some(id)) = (client, id) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    // Waits on the connection to the server, so it is kept off the async runtime
+    match tokio::task::spawn_blocking(move || client.thumbnail(&id, CLIENT_THUMBNAIL_WAIT)).await {
+        Ok(Some(jpeg)) => {
+            let reply = warp::reply::with_header(jpeg, "content-type", "image/jpeg");
+            Ok(warp::reply::with_header(reply, "cache-control", "no-cache").into_response())
+        }
+        _ => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
 async fn handle_api_request(
     request: WebRequest,
     web_server: Arc<WebServer>,
@@ -190,11 +333,16 @@ async fn handle_api_request(
         "stop-server" => handle_stop_server(&web_server).await,
         "get-connected-clients" => handle_get_connected_clients(&web_server).await,
         "get-logs" => handle_get_logs(&web_server).await,
+        "get-library-changes" => handle_get_library_changes(request.params, &web_server).await,
         "disconnect-specific-client" => handle_disconnect_specific_client(request.params, &web_server).await,
         "connect-client" => handle_connect_client(request.params, &web_server).await,
         "disconnect-client" => handle_disconnect_client(&web_server).await,
         "request-media" => handle_request_media(request.params, &web_server).await,
         "stream-media" => handle_stream_media(request.params, &web_server).await,
+        "pause-media" => handle_playback_command(&web_server, "Paused playback", |server| server.pause_media().map(|_| ())).await,
+        "resume-media" => handle_playback_command(&web_server, "Resumed playback", |server| server.resume_media()).await,
+        "seek-media" => handle_seek_media(request.params, &web_server).await,
+        "stop-media" => handle_playback_command(&web_server, "Stopped playback", |server| server.stop_media()).await,
         _ => WebResponse {
             success: false,
             error: Some("Unknown command".to_string()),
@@ -226,27 +374,117 @@ async fn handle_start_server(
         };
     }
 
+    // Only one server at a time; the previous one would otherwise keep its port
+    stop_media_server(web_server).await;
+
     // Remove any surrounding quotes if they exist
     let cleaned_directory = directory.trim_matches('"').trim();
     web_server.add_log_message("INFO", &format!("Cleaned directory: '{}'", cleaned_directory));    let server = MediaServer::new();
+    if let Some(lead_time_ms) = params["leadTimeMs"].as_u64() {
+        server.set_play_lead_time(Duration::from_millis(lead_time_ms));
+    }
+    if let Some(threshold_ms) = params["driftThresholdMs"].as_u64() {
+        server.set_drift_threshold(Duration::from_millis(threshold_ms));
+    }
+    server.set_scan_options(ScanOptions {
+        include: string_list(&params["include"]),
+        exclude: string_list(&params["exclude"]),
+        max_depth: params["maxDepth"].as_u64().map(|depth| depth as usize),
+    });
+    if let Some(cache_mb) = params["cacheSizeMb"].as_u64() {
+        match usize::try_from(cache_mb).ok().and_then(|cache_mb| cache_mb.checked_mul(1024 * 1024)) {
+            Some(bytes) => server.set_cache_size(bytes),
+            None => {
+                let e = format!("Cache size of {} MB is too large", cache_mb);
+                web_server.add_log_message("ERROR", &e);
+                return WebResponse {
+                    success: false,
+                    error: Some(e),
+                    data: None,
+                };
+            }
+        }
+    }
+    if let Some(mode) = params["hostPlayback"].as_str() {
+        match mode.parse::<HostPlayback>() {
+            Ok(mode) => server.set_host_playback(mode),
+            Err(e) => {
+                web_server.add_log_message("ERROR", &e);
+                return WebResponse {
+                    success: false,
+                    error: Some(e),
+                    data: None,
+                };
+            }
+        }
+    }
+    if let Some(len) = params["sendQueueLen"].as_u64() {
+        server.set_send_queue_len(len as usize);
+    }
+    if let Some(policy) = params["slowClientPolicy"].as_str() {
+        match policy.parse::<SlowClientPolicy>() {
+            Ok(policy) => server.set_slow_client_policy(policy),
+            Err(e) => {
+                web_server.add_log_message("ERROR", &e);
+                return WebResponse {
+                    success: false,
+                    error: Some(e),
+                    data: None,
+                };
+            }
+        }
+    }
+    if let Some(backend) = params["player"].as_str() {
+        match backend.parse::<PlayerBackend>().and_then(PlayerBackend::create) {
+            Ok(player) => server.set_host_player(player),
+            Err(e) => {
+                web_server.add_log_message("ERROR", &e);
+                return WebResponse {
+                    success: false,
+                    error: Some(e),
+                    data: None,
+                };
+            }
+        }
+    }
     
     // Set up status callback to capture MediaServer logs
-    let web_server_clone = Arc::clone(&web_server);
+    let web_server_clone = Arc::clone(web_server);
     server.set_status_callback(move |message| {
         web_server_clone.add_log_message("INFO", &message);
     });
     
-    match server.load_media_path(cleaned_directory) {
-        Ok(_) => {
+    let web_server_clone = Arc::clone(web_server);
+    server.set_library_callback(move |change| {
+        web_server_clone.record_library_change(change);
+    });
+    if let Some(seconds) = params["watchIntervalSecs"].as_u64() {
+        server.set_watch_interval((seconds > 0).then(|| Duration::from_secs(seconds)));
+    }
+    
+    // Sniffing and reading the tags of every file is blocking disk work
+    let loader = server.clone();
+    let directory = cleaned_directory.to_string();
+    let loaded = tokio::task::spawn_blocking(move || loader.load_media_path(&directory).map_err(|e| e.to_string()))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    // Bound before answering, so a port that is taken is reported as a failed start
+    let loaded = match loaded {
+        Ok(()) => server.listen(port).await.map_err(|e| format!("Could not listen on port {}: {}", port, e)),
+        Err(e) => Err(format!("Failed to load media files: {}", e)),
+    };
+    match loaded {
+        Ok(listener) => {
             // Get loaded files info
             let files: Vec<FileInfo> = {
                 let media_files = server.media_files.lock().unwrap();
                 media_files
-                    .iter()
-                    .map(|(_, file)| FileInfo {
+                    .values()
+                    .map(|file| FileInfo {
                         name: file.filename.clone(),
-                        size: file.data.len(),
+                        size: file.size as usize,
                         media_type: file.media_type.clone(),
+                        metadata: file.metadata.clone(),
                     })
                     .collect()
             };
@@ -268,35 +506,46 @@ async fn handle_start_server(
             web_server.add_log_message("INFO", &format!("Loaded {} media file(s)", files.len()));
             
             // Start server in background
-            tokio::spawn(async move {
-                if let Err(e) = server_for_task.start_server(port) {
-                    eprintln!("Server error: {}", e);
-                }
+            let task = tokio::spawn(async move {
+                server_for_task.serve(listener).await;
             });
+            *web_server.media_server_task.lock().unwrap() = Some(task);
 
             WebResponse {
                 success: true,
                 error: None,
                 data: Some(serde_json::json!({
                     "files": files,
-                    "clients": clients
+                    "clients": clients,
+                    "libraryRevision": *web_server.library_revision.lock().unwrap()
                 })),
             }
         }
         Err(e) => {
-            web_server.add_log_message("ERROR", &format!("Failed to load media files: {}", e));
+            web_server.add_log_message("ERROR", &e);
             WebResponse {
                 success: false,
-                error: Some(format!("Failed to load media files: {}", e)),
+                error: Some(e),
                 data: None,
             }
         }
     }
 }
 
+// Shuts the running media server down and waits until its port is free
+async fn stop_media_server(web_server: &Arc<WebServer>) {
+    if let Some(server) = web_server.media_server.lock().unwrap().take() {
+        server.shutdown();
+    }
+    let task = web_server.media_server_task.lock().unwrap().take();
+    if let Some(task) = task {
+        let _ = task.await;
+    }
+}
+
 async fn handle_stop_server(web_server: &Arc<WebServer>) -> WebResponse {
     web_server.add_log_message("INFO", "Stopping media server...");
-    *web_server.media_server.lock().unwrap() = None;
+    stop_media_server(web_server).await;
     web_server.loaded_files.lock().unwrap().clear();
     web_server.add_log_message("INFO", "Media server stopped successfully");
 
@@ -312,10 +561,16 @@ async fn handle_get_connected_clients(web_server: &Arc<WebServer>) -> WebRespons
         let connected_clients_data = server.get_connected_clients();
         let clients: Vec<ClientInfo> = connected_clients_data
             .iter()
-            .map(|(id, address)| ClientInfo {
-                id: id.clone(),
-                address: address.clone(),
+            .map(|client| ClientInfo {
+                id: client.id.clone(),
+                address: client.address.clone(),
                 connected_time: Utc::now().format("%H:%M:%S").to_string(),
+                clock_offset_ms: client.clock.map(|clock| clock.offset_ms),
+                round_trip_ms: client.clock.map(|clock| clock.round_trip_ms),
+                playing_file: client.playback.as_ref().map(|playback| playback.filename.clone()),
+                position_ms: client.playback.as_ref().map(|playback| playback.position_ms),
+                drift_ms: client.playback.as_ref().and_then(|playback| playback.drift_ms),
+                queued_messages: client.queued_messages,
             })
             .collect();
 
@@ -335,6 +590,36 @@ async fn handle_get_connected_clients(web_server: &Arc<WebServer>) -> WebRespons
     }
 }
 
+async fn handle_get_library_changes(
+    params: serde_json::Value,
+    web_server: &Arc<WebServer>,
+) -> WebResponse {
+    let since = params["since"].as_u64().unwrap_or(0);
+    let revision = *web_server.library_revision.lock().unwrap();
+    let deltas = web_server.library_deltas.lock().unwrap();
+    
+    // Too far behind to catch up from the kept deltas: send the whole list instead
+    let oldest_kept = deltas.first().map_or(revision + 1, |delta| delta.revision);
+    let data = if since < revision && since + 1 < oldest_kept {
+        serde_json::json!({
+            "revision": revision,
+            "files": *web_server.loaded_files.lock().unwrap()
+        })
+    } else {
+        let changes: Vec<&LibraryDelta> = deltas.iter().filter(|delta| delta.revision > since).collect();
+        serde_json::json!({
+            "revision": revision,
+            "changes": changes
+        })
+    };
+    
+    WebResponse {
+        success: true,
+        error: None,
+        data: Some(data),
+    }
+}
+
 async fn handle_get_logs(web_server: &Arc<WebServer>) -> WebResponse {
     let logs = web_server.log_messages.lock().unwrap().clone();
     WebResponse {
@@ -403,40 +688,70 @@ async fn handle_connect_client(
         };
     }
 
-    let client = MediaClient::new(server_address, client_id);
+    // Only one connection at a time; the previous one would otherwise keep running
+    if let Some(previous) = web_server.media_client.lock().unwrap().take() {
+        previous.disconnect();
+    }
     
-    // In a real implementation, you'd set up proper callbacks here
-    // For now, we'll simulate a successful connection
-    match client.connect() {
-        Ok(_) => {
-            // Simulate receiving file list
-            let files = vec![
-                "sample_video.mp4".to_string(),
-                "sample_audio.mp3".to_string(),
-                "sample_image.jpg".to_string(),
-            ];
-            
-            *web_server.available_files.lock().unwrap() = files.clone();
+    let client = MediaClient::new(server_address.clone(), client_id);
+    
+    // The connection counts as made once the server's media list arrives, or
+    // failed if the client stops before then
+    let (connected_tx, connected_rx) = tokio::sync::oneshot::channel::<Result<(), String>>();
+    let connected_tx = Arc::new(Mutex::new(Some(connected_tx)));
+    
+    let web_server_clone = Arc::clone(web_server);
+    let list_connected_tx = Arc::clone(&connected_tx);
+    client.set_media_list_callback(move |files| {
+        *web_server_clone.available_files.lock().unwrap() = files.to_vec();
+        if let Some(connected_tx) = list_connected_tx.lock().unwrap().take() {
+            let _ = connected_tx.send(Ok(()));
+        }
+    });
+    
+    // The client's message loop blocks until the server goes away
+    let client_clone = client.clone();
+    let web_server_clone = Arc::clone(web_server);
+    std::thread::spawn(move || {
+        let result = client_clone.connect().map_err(|e| e.to_string());
+        match &result {
+            Ok(()) => web_server_clone.add_log_message("INFO", &format!("Disconnected from {}", server_address)),
+            Err(e) => web_server_clone.add_log_message("ERROR", &format!("Connection to {} failed: {}", server_address, e)),
+        }
+        if let Some(connected_tx) = connected_tx.lock().unwrap().take() {
+            let _ = connected_tx.send(result.and(Err("server closed the connection".to_string())));
+        }
+    });
+    
+    let error = match tokio::time::timeout(CLIENT_CONNECT_TIMEOUT, connected_rx).await {
+        Ok(Ok(Ok(()))) => {
             *web_server.media_client.lock().unwrap() = Some(client);
-
-            WebResponse {
+            let files = web_server.available_files.lock().unwrap().clone();
+            return WebResponse {
                 success: true,
                 error: None,
                 data: Some(serde_json::json!({
                     "files": files
                 })),
-            }
+            };
         }
-        Err(e) => WebResponse {
-            success: false,
-            error: Some(format!("Failed to connect: {}", e)),
-            data: None,
-        },
+        Ok(Ok(Err(e))) => e,
+        Ok(Err(_)) => "client stopped unexpectedly".to_string(),
+        Err(_) => "timed out waiting for the media list".to_string(),
+    };
+    // Nothing will keep hold of the client, so it mustn't stay connected on its own
+    client.disconnect();
+    WebResponse {
+        success: false,
+        error: Some(format!("Failed to connect: {}", error)),
+        data: None,
     }
 }
 
 async fn handle_disconnect_client(web_server: &Arc<WebServer>) -> WebResponse {
-    *web_server.media_client.lock().unwrap() = None;
+    if let Some(client) = web_server.media_client.lock().unwrap().take() {
+        client.disconnect();
+    }
     web_server.available_files.lock().unwrap().clear();
 
     WebResponse {
@@ -448,7 +763,7 @@ async fn handle_disconnect_client(web_server: &Arc<WebServer>) -> WebResponse {
 
 async fn handle_request_media(
     params: serde_json::Value,
-    _web_server: &Arc<WebServer>,
+    web_server: &Arc<WebServer>,
 ) -> WebResponse {
     let filename = params["filename"].as_str().unwrap_or("").to_string();
 
@@ -460,10 +775,24 @@ async fn handle_request_media(
         };
     }
 
+    let entry = web_server
+        .available_files
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|entry| entry.path == filename)
+        .cloned();
+    let Some(entry) = entry else {
+        return WebResponse {
+            success: false,
+            error: Some(format!("{} is not in the server's media list", filename)),
+            data: None,
+        };
+    };
+
     // In a real implementation, this would request the media from the server
     // For demonstration, we'll create dummy media data
     let dummy_data = vec![0u8; 1000]; // Dummy binary data
-    let media_type = get_media_type_from_filename(&filename);
     
     let encoded_data = general_purpose::STANDARD.encode(&dummy_data);
 
@@ -474,7 +803,7 @@ async fn handle_request_media(
             "mediaData": {
                 "filename": filename,
                 "data": encoded_data,
-                "mediaType": media_type
+                "mediaType": entry.media_type
             }
         })),
     }
@@ -482,7 +811,7 @@ async fn handle_request_media(
 
 async fn handle_stream_media(
     params: serde_json::Value,
-    _web_server: &Arc<WebServer>,
+    web_server: &Arc<WebServer>,
 ) -> WebResponse {
     let filename = params["filename"].as_str().unwrap_or("").to_string();
 
@@ -494,25 +823,93 @@ async fn handle_stream_media(
         };
     }
 
-    // In a real implementation, this would stream the media to all connected clients
-    // For now, we'll just return success
-    WebResponse {
-        success: true,
-        error: None,
-        data: None,
+    if let Some(server) = web_server.media_server.lock().unwrap().as_ref() {
+        match server.play_media(&filename) {
+            Ok(()) => {
+                web_server.add_log_message("INFO", &format!("Scheduled synchronized playback of {}", filename));
+                WebResponse {
+                    success: true,
+                    error: None,
+                    data: None,
+                }
+            }
+            Err(e) => WebResponse {
+                success: false,
+                error: Some(e),
+                data: None,
+            },
+        }
+    } else {
+        WebResponse {
+            success: false,
+            error: Some("Server is not running".to_string()),
+            data: None,
+        }
     }
 }
 
-fn get_media_type_from_filename(filename: &str) -> String {
-    if let Some(extension) = Path::new(filename).extension() {
-        let ext = extension.to_string_lossy().to_lowercase();
-        match ext.as_str() {
-            "mp4" | "avi" | "mkv" | "mov" | "webm" => "video".to_string(),
-            "mp3" | "wav" | "flac" | "ogg" | "aac" => "audio".to_string(),
-            "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" => "image".to_string(),
-            _ => "unknown".to_string(),
+// Accepts either a JSON array of strings or one comma-separated string
+fn string_list(value: &serde_json::Value) -> Vec<String> {
+    let items: Vec<&str> = match value {
+        serde_json::Value::Array(items) => items.iter().filter_map(|item| item.as_str()).collect(),
+        serde_json::Value::String(list) => list.split(',').collect(),
+        _ => Vec::new(),
+    };
+    items
+        .into_iter()
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+async fn handle_seek_media(
+    params: serde_json::Value,
+    web_server: &Arc<WebServer>,
+) -> WebResponse {
+    let Some(position_ms) = params["positionMs"].as_u64() else {
+        return WebResponse {
+            success: false,
+            error: Some("Position is required".to_string()),
+            data: None,
+        };
+    };
+
+    let message = format!("Seeked to {} ms", position_ms);
+    handle_playback_command(web_server, &message, move |server| server.seek_media(position_ms)).await
+}
+
+// Runs a group playback control against the running media server
+async fn handle_playback_command<F>(
+    web_server: &Arc<WebServer>,
+    success_message: &str,
+    command: F,
+) -> WebResponse
+where
+    F: FnOnce(&MediaServer) -> Result<(), String> + Send + 'static,
+{
+    let server = web_server.media_server.lock().unwrap().clone();
+    let result = match server {
+        // The host player is driven over blocking IPC, which mustn't hold up the async runtime
+        Some(server) => tokio::task::spawn_blocking(move || command(&server))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string())),
+        None => Err("Server is not running".to_string()),
+    };
+
+    match result {
+        Ok(()) => {
+            web_server.add_log_message("INFO", success_message);
+            WebResponse {
+                success: true,
+                error: None,
+                data: None,
+            }
         }
-    } else {
-        "unknown".to_string()
+        Err(e) => WebResponse {
+            success: false,
+            error: Some(e),
+            data: None,
+        },
     }
 }